@@ -0,0 +1,153 @@
+use anyhow::{Context, Result};
+
+use std::collections::HashSet;
+
+use crate::index::{index_path, Ignore, Index, IndexEntry, Pathspec};
+use crate::object::{list_files, FileMode, GitObject, GitObjectType};
+use crate::repository::{repository, work_tree_path};
+
+pub(crate) struct AddOptions {
+    pub(crate) update: bool,
+    pub(crate) all: bool,
+    pub(crate) dry_run: bool,
+    pub(crate) intent_to_add: bool,
+}
+
+pub(crate) fn invoke(paths: Vec<String>, options: AddOptions) -> Result<()> {
+    if paths.is_empty() && !options.update && !options.all {
+        anyhow::bail!("nothing specified, nothing added");
+    }
+
+    repository().work_tree()?;
+    // Paths are relative to where we were run, but the index is relative to the top
+    let paths = paths
+        .iter()
+        .map(|path| Ok(work_tree_path(path)?.to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+
+    let pathspec = Pathspec::new(&paths);
+    let mut index = Index::load(index_path()).context("loading index")?;
+    let mut ignore = Ignore::load().context("loading ignore patterns")?;
+    let files = list_files(".", &mut ignore, &index).context("listing working tree")?;
+
+    check_pathspec(&pathspec, &files, &index, &mut ignore)?;
+
+    let present: HashSet<&str> = files.iter().map(|f| f.as_str()).collect();
+    for file in files.iter().filter(|f| pathspec.matches(f)) {
+        let tracked = index.entry(file).cloned();
+        if options.update && tracked.is_none() {
+            continue;
+        }
+        // Submodules are recorded by commit, which is left to the submodule itself
+        if tracked
+            .as_ref()
+            .is_some_and(|t| t.file_mode() == FileMode::Gitlink)
+        {
+            continue;
+        }
+
+        let metadata =
+            std::fs::symlink_metadata(file).with_context(|| format!("getting {file} metadata"))?;
+
+        if options.intent_to_add {
+            if tracked.is_none() {
+                report(&options, "add", file);
+                index.add(intent_to_add_entry(file, &metadata)?);
+            }
+            continue;
+        }
+
+        // Matching stat data means the content hasn't changed since it was staged
+        if let Some(tracked) = &tracked {
+            if !tracked.intent_to_add && tracked.stat_matches(&metadata) {
+                continue;
+            }
+        }
+
+        let hash = GitObject::hash_worktree_file(file, &metadata, false)
+            .with_context(|| format!("hashing {file}"))?;
+        let entry = IndexEntry::from_metadata(file, &metadata, hash);
+
+        let changed = tracked
+            .as_ref()
+            .is_none_or(|t| t.intent_to_add || t.hash != entry.hash || t.mode != entry.mode);
+        if changed {
+            report(&options, "add", file);
+            if !options.dry_run {
+                GitObject::hash_worktree_file(file, &metadata, true)
+                    .with_context(|| format!("writing blob for {file}"))?;
+            }
+        }
+
+        index.add(entry);
+    }
+
+    // Tracked files that have disappeared from the working tree are staged as removals
+    if !options.intent_to_add {
+        let removed: Vec<String> = index
+            .entries
+            .iter()
+            .filter(|entry| pathspec.matches(&entry.path) && !present.contains(entry.path.as_str()))
+            .map(|entry| entry.path.clone())
+            .collect();
+
+        for path in removed {
+            report(&options, "remove", &path);
+            index.remove(&path);
+        }
+    }
+
+    if !options.dry_run {
+        index.write(index_path()).context("writing index")?;
+    }
+
+    Ok(())
+}
+
+/// Every explicit path must match something on disk or in the index, and
+/// naming an ignored path is an error rather than silently adding nothing
+fn check_pathspec(
+    pathspec: &Pathspec,
+    files: &[String],
+    index: &Index,
+    ignore: &mut Ignore,
+) -> Result<()> {
+    for pattern in pathspec.patterns() {
+        let single = Pathspec::new(std::slice::from_ref(pattern));
+        let matched = files.iter().any(|f| single.matches(f))
+            || index.entries.iter().any(|e| single.matches(&e.path));
+        if matched {
+            continue;
+        }
+
+        if let Ok(metadata) = std::fs::symlink_metadata(pattern) {
+            anyhow::ensure!(
+                !ignore.is_ignored(pattern, metadata.is_dir())?,
+                "The following paths are ignored by one of your .gitignore files:\n{pattern}"
+            );
+        }
+        anyhow::bail!("pathspec '{pattern}' did not match any files");
+    }
+
+    Ok(())
+}
+
+/// Records the path with an empty blob so it shows up as a change to be added later
+fn intent_to_add_entry(file: &str, metadata: &std::fs::Metadata) -> Result<IndexEntry> {
+    let empty = GitObject::create_raw(&[], GitObjectType::Blob)?;
+    let stat = IndexEntry::from_metadata(file, metadata, empty.hash.clone());
+
+    Ok(IndexEntry {
+        mode: stat.mode,
+        hash: empty.hash,
+        path: file.to_string(),
+        intent_to_add: true,
+        ..Default::default()
+    })
+}
+
+fn report(options: &AddOptions, action: &str, path: &str) {
+    if options.dry_run {
+        println!("{action} '{path}'");
+    }
+}
@@ -1,13 +1,12 @@
-use crate::object::GitObject;
+use crate::object::ObjectReader;
 use anyhow::{Context, Result};
 
 use std::io::Write;
 
 pub(crate) fn invoke(hash: &str) -> Result<()> {
-    let object = GitObject::load(&hash).context("loading git object from hash")?;
-    let mut stdout = std::io::stdout();
-    stdout
-        .write_all(&object.content)
-        .context("writing object content to stdout")?;
+    let mut object = ObjectReader::open(hash).context("loading git object from hash")?;
+    let mut stdout = std::io::stdout().lock();
+    std::io::copy(&mut object, &mut stdout).context("writing object content to stdout")?;
+    stdout.flush().context("writing object content to stdout")?;
     Ok(())
 }
@@ -1,227 +1,267 @@
 use anyhow::{Context, Result};
-use reqwest::Client;
-use reqwest::StatusCode;
 
 use std::{
-    ffi::CStr,
-    fmt::Write,
-    io::{BufRead, BufReader, Read, Write as FileWrite},
-    path::{Path, PathBuf},
-    sync::Arc,
+    collections::HashSet,
+    io::Write as FileWrite,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::Path,
 };
-use tokio::sync::Mutex;
-use tokio::task::{spawn, JoinHandle};
 
-use crate::object::GitObject;
-use crate::object::GitObjectType;
+use crate::config::ConfigFile;
+use crate::index::{index_path, Index, IndexEntry};
+use crate::object::{Commit, FileMode, GitObject, Tree};
 use crate::pack::PackFile;
+use crate::protocol::{AdvertisedRef, UploadPack};
+use crate::refs::{self, PackedRef, PackedRefs};
+use crate::repository::git_path;
+
+/// The refs a clone copies from the remote
+const REF_PREFIXES: &[&str] = &["HEAD", "refs/heads/", "refs/tags/"];
+
+/// Clones into `dst`, or a directory named after the repository if not given
+pub(crate) async fn invoke(url: String, dst: Option<String>, quiet: bool) -> Result<()> {
+    let dst = match dst {
+        Some(dst) => dst,
+        None => default_destination(&url)?,
+    };
+    create_destination(dst)
+        .await
+        .context("creating clone destination")?;
 
-// TODO: Rebuild the repo from the ref objects
-// TODO: Focus only on `main` for now
-pub(crate) async fn invoke(url: String, dst: Option<String>) -> Result<()> {
-    if let Some(dst) = dst {
-        create_destination(dst)
-            .await
-            .context("creating clone destination")?;
+    if !quiet {
+        println!("Performing ref discovery for {url}");
     }
-
-    let client = Client::new();
-    let (head, advertised) = ref_discovery(&url, &client)
+    let remote = UploadPack::connect(&url).await.context("ref discovery")?;
+    let advertised = remote
+        .ls_refs(REF_PREFIXES)
         .await
-        .context("ref discovery")?;
-    let packs = fetch_refs(&url, advertised).await?;
-    for mut pack in packs {
-        pack.parse()
-            .with_context(|| format!("parsing pack {}", pack.id))?;
-    }
+        .context("listing remote refs")?;
 
-    build_repository(head).context("rebuilding repo from HEAD")?;
+    let mut wanted = Vec::new();
+    for reference in &advertised {
+        if !wanted.contains(&reference.hash) {
+            wanted.push(reference.hash.clone());
+        }
+    }
 
-    Ok(())
-}
+    if !wanted.is_empty() {
+        if !quiet {
+            println!("Downloading pack...");
+        }
+        let data = remote
+            .fetch(&wanted, &[], quiet)
+            .await
+            .context("fetching pack")?;
+        let mut pack = PackFile::new("clone", data).context("building packfile")?;
+        pack.store()
+            .with_context(|| format!("storing pack {}", pack.id))?;
+    }
 
-async fn create_destination(dst: impl AsRef<Path>) -> Result<()> {
-    if dst.as_ref().exists() {
-        tokio::fs::remove_dir_all(&dst).await?;
+    write_refs(remote.url(), &advertised).context("writing refs")?;
+    if let Some(head) = find(&advertised, "HEAD") {
+        build_repository(&head.hash).context("rebuilding repo from HEAD")?;
     }
-    tokio::fs::create_dir_all(&dst).await?;
-    std::env::set_current_dir(dst)?;
-    crate::commands::init::invoke()?;
 
     Ok(())
 }
 
-// This is clone so we have nothing so omitting the have part
-async fn fetch_refs(url: &str, advertised: Vec<String>) -> Result<Vec<PackFile>> {
-    let url = format!("{url}/git-upload-pack");
-    let packs = Arc::new(Mutex::new(Vec::new()));
-    let mut handles = vec![];
-
-    println!("Downloading packs...");
-    for reference in advertised.into_iter() {
-        let url = url.clone();
-        let packs = Arc::clone(&packs);
-
-        let hdl: JoinHandle<Result<()>> = spawn(async move {
-            let client = Client::new();
-            let mut data = String::new();
-            let line = format!("want {}\n", reference);
-            let size = (line.len() as u16 + 4).to_be_bytes();
-            let line = format!("{}{}", hex::encode(size), line);
-            write!(data, "{line}")?;
-            write!(data, "0000")?;
-            writeln!(data, "0009done")?;
-
-            let body = data.as_bytes().to_owned();
-            let mut packfile = client
-                .post(url)
-                .header("Content-Type", "x-git-upload-pack-request")
-                .body(body)
-                .send()
-                .await
-                .context("sending git upload pack request")?
-                .bytes()
-                .await?;
-
-            let mut packs = packs.lock().await;
-            let _ = packfile.split_to(8);
-            let packfile = PackFile::new(&reference, packfile).context("building packfile")?;
-            packs.push(packfile);
-
-            Ok(())
-        });
-
-        handles.push(hdl);
-    }
+fn find<'a>(refs: &'a [AdvertisedRef], name: &str) -> Option<&'a AdvertisedRef> {
+    refs.iter().find(|r| r.name == name)
+}
 
-    for hdl in handles {
-        let _ = hdl.await.context("awaiting packfile fetch task")?;
+/// The branch the remote's HEAD points at, or failing that a branch at the same commit
+fn default_branch(refs: &[AdvertisedRef]) -> Option<String> {
+    let head = find(refs, "HEAD")?;
+    if let Some(target) = &head.symref_target {
+        return Some(target.clone());
     }
 
-    let packs = Arc::try_unwrap(packs)
-        .expect("cannot extract out packs")
-        .into_inner();
-
-    Ok(packs)
+    let branches: Vec<_> = refs
+        .iter()
+        .filter(|r| r.name.starts_with("refs/heads/") && r.hash == head.hash)
+        .map(|r| r.name.as_str())
+        .collect();
+
+    ["refs/heads/main", "refs/heads/master"]
+        .into_iter()
+        .find(|preferred| branches.contains(preferred))
+        .or(branches.first().copied())
+        .map(|name| name.to_string())
 }
 
-async fn ref_discovery(url: &str, client: &Client) -> Result<(String, Vec<String>)> {
-    println!("Performing ref discovery for {url}");
-    let url = format!("{url}/info/refs");
-    let response = client
-        .get(&url)
-        .query(&[("service", "git-upload-pack")])
-        .send()
-        .await
-        .context("initiating ref discovery")?;
-
-    let status = response.status();
-    let response = response
-        .text()
-        .await
-        .context("converting ref discovery response to bytes")?;
-
-    let refs: Vec<_> = response.split('\n').map(|r| r.to_string()).collect();
-    validate_ref_header(&refs[0], status).context("validating ref discovery header")?;
-
-    // This is dirty
-    let mut discovered = Vec::new();
-    let refs = &refs[1..];
-    let head = &refs[0];
-    let Some((head, rest)) = head[8..].split_once(' ') else {
-        anyhow::bail!("this is not a reference");
-    };
-
-    anyhow::ensure!(rest.contains("HEAD"));
-    for reference in refs[1..].into_iter() {
-        // Encountered magic num, we're done
-        if reference == "0000" {
-            break;
-        }
-
-        let Some((reference, _)) = reference[4..].split_once(' ') else {
-            anyhow::bail!("this is not a reference");
-        };
+/// The directory git would clone into: the last component of the URL's path
+/// with any `.git` suffix or trailing `/.git` taken off
+fn default_destination(url: &str) -> Result<String> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let path = path.trim_end_matches('/');
+    let path = path
+        .strip_suffix("/.git")
+        .unwrap_or(path)
+        .trim_end_matches('/');
+    let name = path.rsplit(['/', ':']).next().unwrap_or_default();
+    let name = name.strip_suffix(".git").unwrap_or(name);
+
+    anyhow::ensure!(
+        !name.is_empty() && name != "." && name != "..",
+        "could not guess a directory name from {url}; please specify one"
+    );
+    Ok(name.to_string())
+}
 
-        discovered.push(reference.to_owned());
+async fn create_destination(dst: impl AsRef<Path>) -> Result<()> {
+    let dst = dst.as_ref();
+    if dst.exists() {
+        let mut entries = tokio::fs::read_dir(dst).await?;
+        anyhow::ensure!(
+            entries.next_entry().await?.is_none(),
+            "destination path '{}' already exists and is not an empty directory",
+            dst.display()
+        );
     }
+    tokio::fs::create_dir_all(&dst).await?;
+    std::env::set_current_dir(dst)?;
+    crate::commands::init::invoke(false)?;
 
-    Ok((head.to_string(), discovered))
+    Ok(())
 }
 
-fn validate_ref_header(header: &str, status: StatusCode) -> Result<()> {
-    match status {
-        StatusCode::OK | StatusCode::NOT_MODIFIED => {}
-        _ => anyhow::bail!(format!("failed status code validation: {}", status)),
+fn write_refs(url: &str, advertised: &[AdvertisedRef]) -> Result<()> {
+    let mut packed = PackedRefs::default();
+    for reference in advertised {
+        if reference.name != "HEAD" {
+            refs::validate_name(&reference.name).context("remote advertised an invalid ref")?;
+        }
+        anyhow::ensure!(
+            refs::is_hash(&reference.hash) && reference.peeled.as_deref().is_none_or(refs::is_hash),
+            "remote advertised {} at an invalid object name",
+            reference.name
+        );
+        if let Some(branch) = reference.name.strip_prefix("refs/heads/") {
+            packed.insert(PackedRef {
+                name: format!("refs/remotes/origin/{branch}"),
+                hash: reference.hash.clone(),
+                peeled: None,
+            });
+        } else if reference.name.starts_with("refs/tags/") {
+            packed.insert(PackedRef {
+                name: reference.name.clone(),
+                hash: reference.hash.clone(),
+                peeled: reference.peeled.clone(),
+            });
+        }
     }
-
-    let re = regex::Regex::new(r"^[0-9a-f]{4}# service=git-upload-pack")
-        .context("creating validation regex")?;
-
-    if !re.is_match(&header) {
-        anyhow::bail!("failed regex validation");
+    packed.write().context("writing packed-refs")?;
+
+    let (mut config, lock) = ConfigFile::lock(git_path("config"))?;
+    config.set("remote.origin.url", url)?;
+    config.set("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?;
+
+    let head = find(advertised, "HEAD").map(|head| head.hash.as_str());
+    match (default_branch(advertised), head) {
+        (Some(default), Some(head)) => {
+            refs::validate_name(&default).context("remote HEAD points at an invalid ref")?;
+            anyhow::ensure!(
+                default.starts_with("refs/heads/"),
+                "remote HEAD points at {default}, which is not a branch"
+            );
+            let branch = default.strip_prefix("refs/heads/").unwrap_or(&default);
+            refs::update_ref(&default, head, None).context("creating default branch")?;
+            refs::write_symref("HEAD", &default).context("pointing HEAD at default branch")?;
+            refs::write_symref(
+                "refs/remotes/origin/HEAD",
+                &format!("refs/remotes/origin/{branch}"),
+            )
+            .context("writing origin/HEAD")?;
+
+            config.set(&format!("branch.{branch}.remote"), "origin")?;
+            config.set(&format!("branch.{branch}.merge"), &default)?;
+        }
+        (None, Some(head)) => refs::detach_head(head).context("detaching HEAD")?,
+        // Nothing to check out from an empty repository
+        (_, None) => {}
     }
+    config.write(lock).context("writing config")?;
 
     Ok(())
 }
 
-fn build_repository(head: String) -> Result<()> {
-    let head = GitObject::load(&head).context("opening HEAD")?;
-    let mut reader = BufReader::new(&head.content[..]);
-    let mut content = String::new();
-    reader.read_to_string(&mut content)?;
-
-    let Some((tree, _)) = content.split_once('\n') else {
-        anyhow::bail!("this is not the correct format...");
+fn build_repository(head: &str) -> Result<()> {
+    let head = GitObject::load(head).context("opening HEAD")?;
+    let commit = Commit::try_from(&head).context("parsing HEAD commit")?;
+
+    let mut entries = Vec::new();
+    build_tree(&commit.tree, "", &mut entries)?;
+    // Trees sort a directory as if its name ended in `/`, so the order needs fixing up
+    entries.sort_by(|a, b| a.path.as_bytes().cmp(b.path.as_bytes()));
+    let index = Index {
+        entries,
+        ..Default::default()
     };
-
-    let Some((_, tree_hash)) = tree.split_once(' ') else {
-        anyhow::bail!("expected to find a hash");
-    };
-
-    let root = std::env::current_dir()?;
-    build_tree(&tree_hash, &root)?;
+    index.write(index_path()).context("writing index")?;
 
     Ok(())
 }
 
-fn build_tree(hash: &str, root: &PathBuf) -> Result<()> {
-    let tree = GitObject::load(&hash).context("loading tree")?;
-    let mut buf = BufReader::new(&tree.content[..]);
-
-    loop {
-        let mut name_buf = Vec::new();
-        let mut hash_buf: [u8; 20] = [0; 20];
-
-        buf.read_until(0, &mut name_buf)
-            .context("reading tree file content")?;
-        if name_buf.is_empty() {
-            break;
-        }
-        buf.read_exact(&mut hash_buf)
-            .context("reading tree file hash")?;
-
-        let hash = hex::encode(hash_buf);
-
-        let file_info =
-            CStr::from_bytes_until_nul(&name_buf).context("converting file info to c_str")?;
-        let file_info = file_info.to_str().context("converting c_str to str")?;
-
-        let Some((_, name)) = file_info.split_once(' ') else {
-            anyhow::bail!(format!("missing file mode and context: {file_info}"));
-        };
-
-        let path = root.join(name);
-        let obj = GitObject::load(&hash).context("loading object in tree")?;
-        if obj.obj_type == GitObjectType::Blob {
-            let mut f = std::fs::File::create(&path)?;
-            f.write_all(&obj.content)?;
-        } else if obj.obj_type == GitObjectType::Tree {
-            std::fs::create_dir_all(&path)?;
-            build_tree(&hash, &path)?;
+/// Checks out a tree into the current directory, collecting an index entry for each file.
+/// Nothing is ever written through an existing path, so a symlink checked out
+/// earlier can't redirect a later entry outside the work tree.
+fn build_tree(hash: &str, prefix: &str, entries: &mut Vec<IndexEntry>) -> Result<()> {
+    let tree = GitObject::load(hash).context("loading tree")?;
+    let tree = Tree::try_from(&tree).context("parsing tree")?;
+
+    let mut seen = HashSet::new();
+    for entry in tree.entries {
+        entry
+            .check_name()
+            .with_context(|| format!("refusing to check out tree {hash}"))?;
+        // Index paths are text, so there's nowhere to stage any other name
+        let name = std::str::from_utf8(&entry.name)
+            .with_context(|| format!("tree {hash} has a file name that is not valid utf-8"))?;
+        let path = format!("{prefix}{name}");
+        anyhow::ensure!(
+            seen.insert(path.clone()),
+            "tree {hash} has more than one entry named '{path}'"
+        );
+
+        match entry.mode.canonical() {
+            FileMode::Directory => {
+                std::fs::create_dir(&path).with_context(|| format!("creating {path}"))?;
+                build_tree(&entry.hash, &format!("{path}/"), entries)?;
+                continue;
+            }
+            FileMode::Gitlink => {
+                std::fs::create_dir(&path).with_context(|| format!("creating {path}"))?;
+                entries.push(IndexEntry {
+                    mode: entry.mode.as_u32(),
+                    hash: entry.hash,
+                    path,
+                    ..Default::default()
+                });
+                continue;
+            }
+            FileMode::Symlink => {
+                let obj = GitObject::load(&entry.hash).context("loading symlink target")?;
+                let target = std::ffi::OsStr::from_bytes(&obj.content);
+                std::os::unix::fs::symlink(target, &path)
+                    .with_context(|| format!("creating symlink {path}"))?;
+            }
+            _ => {
+                let obj = GitObject::load(&entry.hash).context("loading object in tree")?;
+                let mut f = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .with_context(|| format!("creating {path}"))?;
+                f.write_all(&obj.content)?;
+                if entry.mode.canonical() == FileMode::Executable {
+                    f.set_permissions(std::fs::Permissions::from_mode(0o755))?;
+                }
+            }
         }
 
-        name_buf.clear();
+        let metadata =
+            std::fs::symlink_metadata(&path).with_context(|| format!("getting {path} metadata"))?;
+        entries.push(IndexEntry::from_metadata(&path, &metadata, entry.hash));
     }
 
     Ok(())
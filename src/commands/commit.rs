@@ -0,0 +1,148 @@
+use anyhow::{Context, Result};
+
+use std::path::PathBuf;
+
+use super::add::{self, AddOptions};
+use super::committree::{cleanup_message, read_message, Cleanup};
+use crate::index::{index_path, Index};
+use crate::object::{hash_object, Commit, GitObject, GitObjectType, Role, Signature};
+use crate::refs::{self, short_name, NULL_HASH};
+use crate::repository::repository;
+
+pub(crate) struct CommitOptions {
+    pub(crate) messages: Vec<String>,
+    pub(crate) files: Vec<PathBuf>,
+    /// Replace the commit HEAD points at rather than adding to it
+    pub(crate) amend: bool,
+    pub(crate) allow_empty: bool,
+    /// Stage changes to tracked files first, as `add -u` would
+    pub(crate) all: bool,
+    /// `Name <email>` to credit instead of the current identity
+    pub(crate) author: Option<String>,
+    pub(crate) cleanup: Cleanup,
+}
+
+/// Commits the index on top of HEAD and moves the current branch, or HEAD
+/// itself when detached, to the new commit
+pub(crate) fn invoke(options: CommitOptions) -> Result<()> {
+    repository().work_tree()?;
+
+    if options.all {
+        let update = AddOptions {
+            update: true,
+            all: false,
+            dry_run: false,
+            intent_to_add: false,
+        };
+        add::invoke(Vec::new(), update).context("staging changes to tracked files")?;
+    }
+
+    let head = refs::resolve("HEAD").context("resolving HEAD")?;
+    let amended = match (&head, options.amend) {
+        (Some(head), true) => {
+            let obj = GitObject::load(head).context("loading HEAD")?;
+            Some(Commit::try_from(&obj).context("parsing HEAD commit")?)
+        }
+        (None, true) => anyhow::bail!("you have nothing to amend"),
+        (_, false) => None,
+    };
+    let parents = match &amended {
+        Some(amended) => amended.parents.clone(),
+        None => head.iter().cloned().collect(),
+    };
+
+    let index = Index::load(index_path()).context("loading index")?;
+    let tree = index.write_tree().context("writing tree from index")?;
+
+    // Merges may legitimately leave the tree as it was
+    if !options.allow_empty && parents.len() <= 1 {
+        let previous = match parents.first() {
+            Some(parent) => {
+                let obj = GitObject::load(parent).context("loading parent commit")?;
+                Commit::try_from(&obj)
+                    .context("parsing parent commit")?
+                    .tree
+            }
+            None => hash_object(GitObjectType::Tree, &[]),
+        };
+        anyhow::ensure!(
+            tree != previous,
+            "{}",
+            if options.amend {
+                "amending would make the most recent commit empty; use --allow-empty to do so anyway"
+            } else {
+                "nothing to commit"
+            }
+        );
+    }
+
+    let message = match read_message(&options.messages, &options.files)? {
+        Some(message) => message,
+        None => match &amended {
+            Some(amended) => String::from_utf8(amended.message.clone())
+                .context("the commit being amended has a message that is not valid utf-8")?,
+            None => anyhow::bail!("no commit message given; use -m or -F"),
+        },
+    };
+    let message = cleanup_message(&message, options.cleanup);
+    anyhow::ensure!(
+        !message.is_empty(),
+        "aborting commit due to empty commit message"
+    );
+
+    // An amended commit keeps its author unless told otherwise
+    let author = match (&options.author, amended) {
+        (Some(author), _) => Some(parse_author(author)?),
+        (None, Some(amended)) => Some(amended.author),
+        (None, None) => None,
+    };
+
+    let parent_count = parents.len();
+    let commit = GitObject::create_commit(tree, parents, message.clone(), author)
+        .context("creating commit object")?;
+    commit.write().context("writing commit")?;
+
+    let subject = message.lines().next().unwrap_or_default();
+    let kind = if options.amend {
+        "commit (amend)"
+    } else if parent_count == 0 {
+        "commit (initial)"
+    } else if parent_count > 1 {
+        "commit (merge)"
+    } else {
+        "commit"
+    };
+    let old = head.as_deref().unwrap_or(NULL_HASH);
+    refs::update_ref_logged(
+        "HEAD",
+        &commit.hash,
+        Some(old),
+        &format!("{kind}: {subject}"),
+    )
+    .context("updating HEAD")?;
+
+    let branch = match refs::current_branch()? {
+        Some(branch) => short_name(&branch).to_string(),
+        None => "detached HEAD".to_string(),
+    };
+    let root = if parent_count == 0 && !options.amend {
+        " (root-commit)"
+    } else {
+        ""
+    };
+    println!("[{branch}{root} {}] {subject}", &commit.hash[..7]);
+
+    Ok(())
+}
+
+/// `Name <email>`, dated as the author of a new commit would be
+fn parse_author(raw: &str) -> Result<Signature> {
+    let Some((name, rest)) = raw.split_once('<') else {
+        anyhow::bail!("--author '{raw}' is not of the form 'Name <email>'");
+    };
+    let Some((email, _)) = rest.split_once('>') else {
+        anyhow::bail!("--author '{raw}' is not of the form 'Name <email>'");
+    };
+
+    Signature::with_identity(Role::Author, name, email)
+}
@@ -1,12 +1,169 @@
 use anyhow::{Context, Result};
 
-use crate::object::GitObject;
+use std::io::Read;
+use std::path::PathBuf;
 
-pub(crate) fn invoke(tree_hash: String, parent: Option<String>, message: String) -> Result<()> {
-    let commit =
-        GitObject::create_commit(tree_hash, parent, message).context("creating commit object")?;
+use crate::object::{GitObject, GitObjectType};
+use crate::repository::cli_path;
+
+/// How a commit message is tidied up before it's stored, as for `git commit --cleanup`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Cleanup {
+    /// Drop `#` comment lines as well as surplus whitespace and blank lines
+    Strip,
+    /// Drop trailing whitespace and leading, trailing and repeated blank lines
+    Whitespace,
+    /// Keep the message exactly as given
+    Verbatim,
+}
+
+pub(crate) fn invoke(
+    tree_hash: String,
+    parents: Vec<String>,
+    messages: Vec<String>,
+    files: Vec<PathBuf>,
+    cleanup: Cleanup,
+) -> Result<()> {
+    let tree = GitObject::load(&tree_hash)
+        .with_context(|| format!("not a valid object name {tree_hash}"))?;
+    anyhow::ensure!(
+        tree.obj_type == GitObjectType::Tree,
+        "{tree_hash} is a {}, not a tree",
+        tree.obj_type
+    );
+
+    let mut unique: Vec<String> = Vec::new();
+    for parent in parents {
+        let obj = GitObject::load(&parent)
+            .with_context(|| format!("not a valid object name {parent}"))?;
+        anyhow::ensure!(
+            obj.obj_type == GitObjectType::Commit,
+            "{parent} is a {}, not a commit",
+            obj.obj_type
+        );
+
+        if unique.contains(&parent) {
+            eprintln!("error: duplicate parent {parent} ignored");
+            continue;
+        }
+        unique.push(parent);
+    }
+
+    let message = match read_message(&messages, &files)? {
+        Some(message) => message,
+        None => read_stdin()?,
+    };
+    let message = cleanup_message(&message, cleanup);
+
+    let commit = GitObject::create_commit(tree_hash, unique, message, None)
+        .context("creating commit object")?;
 
     commit.write().context("writing commit")?;
     println!("{}", commit.hash);
     Ok(())
 }
+
+/// Joins `-m` messages and the contents of `-F` files as separate paragraphs,
+/// with `-` reading standard input. `None` if neither was given.
+pub(crate) fn read_message(messages: &[String], files: &[PathBuf]) -> Result<Option<String>> {
+    if messages.is_empty() && files.is_empty() {
+        return Ok(None);
+    }
+
+    let mut message = String::new();
+    for paragraph in messages {
+        if !message.is_empty() {
+            message.push('\n');
+        }
+        message.push_str(paragraph);
+        if !paragraph.ends_with('\n') {
+            message.push('\n');
+        }
+    }
+
+    for file in files {
+        let content = if file.as_os_str() == "-" {
+            read_stdin()?
+        } else {
+            std::fs::read_to_string(cli_path(file))
+                .with_context(|| format!("could not read log file '{}'", file.display()))?
+        };
+
+        if !message.is_empty() {
+            message.push('\n');
+        }
+        message.push_str(&content);
+    }
+
+    Ok(Some(message))
+}
+
+fn read_stdin() -> Result<String> {
+    let mut message = String::new();
+    std::io::stdin()
+        .read_to_string(&mut message)
+        .context("reading commit message from standard input")?;
+    Ok(message)
+}
+
+pub(crate) fn cleanup_message(raw: &str, cleanup: Cleanup) -> String {
+    if cleanup == Cleanup::Verbatim {
+        return raw.to_string();
+    }
+
+    let mut message = String::new();
+    let mut pending_blank = false;
+    for line in raw.lines() {
+        if cleanup == Cleanup::Strip && line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            // Blank lines only survive between paragraphs, and only one at a time
+            pending_blank = !message.is_empty();
+            continue;
+        }
+
+        if pending_blank {
+            message.push('\n');
+            pending_blank = false;
+        }
+        message.push_str(line);
+        message.push('\n');
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW: &str = "\n\nsubject  \n# a comment\n\n\n\nbody\t\n#another\n\n";
+
+    #[test]
+    fn strip_drops_comments_and_collapses_blank_lines() {
+        assert_eq!(cleanup_message(RAW, Cleanup::Strip), "subject\n\nbody\n");
+    }
+
+    #[test]
+    fn whitespace_keeps_comments() {
+        assert_eq!(
+            cleanup_message(RAW, Cleanup::Whitespace),
+            "subject\n# a comment\n\nbody\n#another\n"
+        );
+    }
+
+    #[test]
+    fn verbatim_keeps_everything() {
+        assert_eq!(cleanup_message(RAW, Cleanup::Verbatim), RAW);
+    }
+
+    #[test]
+    fn empty_and_comment_only_messages_clean_up_to_nothing() {
+        assert_eq!(cleanup_message("", Cleanup::Strip), "");
+        assert_eq!(cleanup_message("\n \n\t\n", Cleanup::Whitespace), "");
+        assert_eq!(cleanup_message("# only\n#\n", Cleanup::Strip), "");
+    }
+}
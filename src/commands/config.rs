@@ -0,0 +1,105 @@
+use anyhow::{Context, Result};
+
+use std::path::PathBuf;
+
+use crate::config::{self, Config, ConfigFile, Entry, Key};
+use crate::repository::{cli_path, Repository};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Action {
+    Get,
+    GetAll,
+    Set,
+    Unset,
+    List,
+}
+
+/// Which config files are read or written
+#[derive(Debug, Clone)]
+pub(crate) enum Scope {
+    /// Every file when reading, the repository's when writing
+    All,
+    System,
+    Global,
+    Local,
+    File(PathBuf),
+}
+
+pub(crate) fn invoke(
+    action: Action,
+    key: Option<String>,
+    value: Option<String>,
+    scope: Scope,
+    show_origin: bool,
+) -> Result<()> {
+    if let Action::List = action {
+        for entry in &read(&scope)?.entries {
+            let value = match &entry.value {
+                Some(value) => format!("={value}"),
+                None => String::new(),
+            };
+            println!("{}{}{value}", origin(entry, show_origin), entry.key);
+        }
+        return Ok(());
+    }
+
+    let key = key.context("no key given")?;
+    Key::parse(&key)?;
+
+    match action {
+        Action::Get | Action::GetAll => {
+            let config = read(&scope)?;
+            let entries: Vec<&Entry> = config.get_entries(&key).collect();
+            let shown = match action {
+                Action::GetAll => &entries[..],
+                _ => &entries[entries.len().saturating_sub(1)..],
+            };
+            anyhow::ensure!(!shown.is_empty(), "{key} is not set");
+
+            for entry in shown {
+                let value = entry.value.as_deref().unwrap_or_default();
+                println!("{}{value}", origin(entry, show_origin));
+            }
+        }
+        Action::Set => {
+            let value = value.context("no value given")?;
+            let (mut file, lock) = ConfigFile::lock(target(&scope)?)?;
+            file.set(&key, &value)?;
+            file.write(lock)?;
+        }
+        Action::Unset => {
+            let (mut file, lock) = ConfigFile::lock(target(&scope)?)?;
+            file.unset(&key)?;
+            file.write(lock)?;
+        }
+        Action::List => unreachable!("listing is handled above"),
+    }
+
+    Ok(())
+}
+
+fn origin(entry: &Entry, show_origin: bool) -> String {
+    if show_origin {
+        format!("file:{}\t", entry.origin.display())
+    } else {
+        String::new()
+    }
+}
+
+fn read(scope: &Scope) -> Result<Config> {
+    match scope {
+        Scope::All => Config::load(),
+        _ => Config::load_file(target(scope)?),
+    }
+}
+
+fn target(scope: &Scope) -> Result<PathBuf> {
+    match scope {
+        Scope::All | Scope::Local => Repository::current()
+            .map(|repository| repository.git_dir().join("config"))
+            .context("not in a git directory"),
+        Scope::System => Ok(config::system_path()),
+        Scope::Global => config::global_path(),
+        Scope::File(path) => Ok(cli_path(path)),
+    }
+}
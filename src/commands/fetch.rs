@@ -0,0 +1,323 @@
+use anyhow::{Context, Result};
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::config::Config;
+use crate::object::{is_ancestor, Commit, CommitWalker, GitObject};
+use crate::pack::PackFile;
+use crate::protocol::{AdvertisedRef, UploadPack};
+use crate::refs::{self, short_name, Refspec, NULL_HASH};
+use crate::repository::git_path;
+
+/// Haves are sent to the server this many at a time
+const HAVE_BATCH: usize = 32;
+
+/// Negotiation gives up after this many haves without finding anything in common
+const MAX_HAVES: usize = 256;
+
+/// How fetching a ref changed (or didn't change) its local counterpart
+#[derive(Debug)]
+enum Update {
+    New,
+    UpToDate,
+    FastForward(String),
+    Forced(String),
+    Rejected,
+}
+
+/// A remote ref we are fetching and where it goes locally
+#[derive(Debug)]
+struct Fetched<'a> {
+    remote: &'a AdvertisedRef,
+    /// `None` when the ref only goes to FETCH_HEAD
+    local: Option<String>,
+    force: bool,
+    for_merge: bool,
+}
+
+pub(crate) async fn invoke(remote: String, refspecs: Vec<String>, quiet: bool) -> Result<()> {
+    // A remote can be given as a url rather than a configured name
+    let (url, configured) = if remote.contains("://") {
+        (remote.clone(), Vec::new())
+    } else {
+        remote_config(&remote).with_context(|| format!("reading remote '{remote}'"))?
+    };
+
+    // Refspecs on the command line replace the configured ones
+    let from_cli = !refspecs.is_empty();
+    let refspecs = if from_cli { refspecs } else { configured };
+    anyhow::ensure!(!refspecs.is_empty(), "no refspecs to fetch from {remote}");
+    let refspecs = refspecs
+        .iter()
+        .map(|spec| Refspec::parse(spec))
+        .collect::<Result<Vec<_>>>()?;
+
+    let connection = UploadPack::connect(&url).await.context("ref discovery")?;
+
+    let mut prefixes: Vec<String> = refspecs.iter().flat_map(|spec| spec.prefixes()).collect();
+    prefixes.push("refs/tags/".to_string());
+    let prefixes: Vec<&str> = prefixes.iter().map(|prefix| prefix.as_str()).collect();
+    let advertised = connection
+        .ls_refs(&prefixes)
+        .await
+        .context("listing remote refs")?;
+
+    let mut fetched = Vec::new();
+    for remote_ref in &advertised {
+        let Some(spec) = refspecs.iter().find(|spec| spec.matches(&remote_ref.name)) else {
+            continue;
+        };
+        fetched.push(Fetched {
+            remote: remote_ref,
+            local: spec.destination(&remote_ref.name),
+            force: spec.force,
+            for_merge: from_cli && !spec.is_glob(),
+        });
+    }
+    anyhow::ensure!(
+        from_cli || !fetched.is_empty() || advertised.is_empty(),
+        "no refs on {url} match the configured refspecs"
+    );
+    for spec in refspecs.iter().filter(|spec| !spec.is_glob()) {
+        anyhow::ensure!(
+            fetched.iter().any(|f| spec.matches(&f.remote.name)),
+            "couldn't find remote ref {}",
+            spec.src
+        );
+    }
+
+    let mut wants = Vec::new();
+    for f in &fetched {
+        if !wants.contains(&f.remote.hash) && !GitObject::exists(&f.remote.hash)? {
+            wants.push(f.remote.hash.clone());
+        }
+    }
+
+    if !wants.is_empty() {
+        let data = negotiate(&connection, &wants, quiet)
+            .await
+            .context("fetching pack")?;
+        let mut pack = PackFile::new("fetch", data).context("building packfile")?;
+        pack.store()
+            .with_context(|| format!("storing pack {}", pack.id))?;
+    }
+
+    write_fetch_head(&url, &fetched).context("writing FETCH_HEAD")?;
+
+    let mut report = String::new();
+    let mut rejected = false;
+    for f in &fetched {
+        let Some(local) = &f.local else {
+            report_line(&mut report, "*", "branch", &f.remote.name, "FETCH_HEAD", "")?;
+            continue;
+        };
+
+        let update = update_ref(local, &f.remote.hash, f.force)
+            .with_context(|| format!("updating {local}"))?;
+        rejected |= matches!(update, Update::Rejected);
+        report_update(&mut report, &update, &f.remote.name, local)?;
+    }
+
+    // Tags pointing into what we now have follow along, as with `git fetch`
+    for tag in advertised
+        .iter()
+        .filter(|r| r.name.starts_with("refs/tags/"))
+    {
+        if let Err(e) = refs::validate_name(&tag.name) {
+            eprintln!("warning: skipping tag: {e}");
+            continue;
+        }
+        if fetched.iter().any(|f| f.remote.name == tag.name)
+            || refs::read_ref(&tag.name)?.is_some()
+            || !GitObject::exists(&tag.hash)?
+        {
+            continue;
+        }
+
+        refs::update_ref(&tag.name, &tag.hash, Some(NULL_HASH))
+            .with_context(|| format!("creating {}", tag.name))?;
+        report_update(&mut report, &Update::New, &tag.name, &tag.name)?;
+    }
+
+    if !quiet && !report.is_empty() {
+        println!("From {url}");
+        print!("{report}");
+    }
+    anyhow::ensure!(!rejected, "some local refs could not be updated");
+
+    Ok(())
+}
+
+/// Finds the history we share with the remote by sending it our commits newest
+/// first, then asks for a pack of everything else
+async fn negotiate(connection: &UploadPack, wants: &[String], quiet: bool) -> Result<bytes::Bytes> {
+    let mut walker = CommitWalker::new();
+    for hash in local_tips()? {
+        walker.push(&hash)?;
+    }
+
+    // Commits known to the remote, whose ancestors we needn't mention
+    let mut common: HashSet<String> = HashSet::new();
+    let mut acked: Vec<String> = Vec::new();
+    let mut unacked = 0;
+
+    loop {
+        let mut batch = Vec::new();
+        while batch.len() < HAVE_BATCH {
+            let Some((hash, commit)) = walker.next_commit()? else {
+                break;
+            };
+            if common.contains(&hash) {
+                common.extend(commit.parents);
+                continue;
+            }
+            batch.push(hash);
+        }
+
+        if batch.is_empty() || unacked >= MAX_HAVES {
+            break;
+        }
+        unacked += batch.len();
+
+        // Requests are stateless, so everything found in common so far is repeated
+        let mut haves = acked.clone();
+        haves.extend(batch);
+        let acks = connection.negotiate(wants, &haves, quiet).await?;
+
+        for hash in acks.common {
+            if common.insert(hash.clone()) {
+                let obj = GitObject::load(&hash)?;
+                common.extend(Commit::try_from(&obj)?.parents);
+                acked.push(hash);
+                unacked = 0;
+            }
+        }
+
+        if let Some(pack) = acks.pack {
+            return Ok(pack);
+        }
+        if acks.ready {
+            break;
+        }
+    }
+
+    connection.fetch(wants, &acked, quiet).await
+}
+
+/// Every commit our refs point at
+fn local_tips() -> Result<Vec<String>> {
+    let mut tips: Vec<String> = refs::list_refs("refs/")?.into_values().collect();
+    tips.extend(refs::resolve("HEAD")?);
+    tips.sort();
+    tips.dedup();
+
+    Ok(tips)
+}
+
+fn update_ref(name: &str, hash: &str, force: bool) -> Result<Update> {
+    let Some(old) = refs::resolve(name)? else {
+        refs::update_ref(name, hash, Some(NULL_HASH))?;
+        return Ok(Update::New);
+    };
+
+    let update = if old == hash {
+        return Ok(Update::UpToDate);
+    } else if is_ancestor(&old, hash)? {
+        Update::FastForward(old.clone())
+    } else if force {
+        Update::Forced(old.clone())
+    } else {
+        return Ok(Update::Rejected);
+    };
+
+    refs::update_ref(name, hash, Some(&old))?;
+    Ok(update)
+}
+
+fn report_update(report: &mut String, update: &Update, remote: &str, local: &str) -> Result<()> {
+    let kind = if remote.starts_with("refs/tags/") {
+        "tag"
+    } else {
+        "branch"
+    };
+
+    match update {
+        Update::New => report_line(report, "*", &format!("[new {kind}]"), remote, local, ""),
+        Update::UpToDate => Ok(()),
+        Update::FastForward(old) => {
+            let new = refs::resolve(local)?.unwrap_or_default();
+            report_line(
+                report,
+                " ",
+                &format!("{}..{}", &old[..7], &new[..7]),
+                remote,
+                local,
+                "",
+            )
+        }
+        Update::Forced(old) => {
+            let new = refs::resolve(local)?.unwrap_or_default();
+            let range = format!("{}...{}", &old[..7], &new[..7]);
+            report_line(report, "+", &range, remote, local, "  (forced update)")
+        }
+        Update::Rejected => report_line(
+            report,
+            "!",
+            "[rejected]",
+            remote,
+            local,
+            "  (non-fast-forward)",
+        ),
+    }
+}
+
+fn report_line(
+    report: &mut String,
+    flag: &str,
+    summary: &str,
+    remote: &str,
+    local: &str,
+    note: &str,
+) -> Result<()> {
+    writeln!(
+        report,
+        " {flag} {summary:<17} {:<10} -> {}{note}",
+        short_name(remote),
+        short_name(local)
+    )?;
+    Ok(())
+}
+
+/// Records what was fetched for a later merge, like `git fetch` does
+fn write_fetch_head(url: &str, fetched: &[Fetched]) -> Result<()> {
+    let mut content = String::new();
+    for f in fetched {
+        let name = &f.remote.name;
+        let description = if let Some(branch) = name.strip_prefix("refs/heads/") {
+            format!("branch '{branch}' of {url}")
+        } else if let Some(tag) = name.strip_prefix("refs/tags/") {
+            format!("tag '{tag}' of {url}")
+        } else {
+            format!("'{name}' of {url}")
+        };
+        let merge = if f.for_merge { "" } else { "not-for-merge" };
+        writeln!(content, "{}\t{merge}\t{description}", f.remote.hash)?;
+    }
+
+    std::fs::write(git_path("FETCH_HEAD"), content).context("writing FETCH_HEAD")
+}
+
+/// The url and fetch refspecs of `remote.<name>` in the config
+pub(crate) fn remote_config(name: &str) -> Result<(String, Vec<String>)> {
+    let config = Config::load()?;
+    let url = config
+        .get(&format!("remote.{name}.url"))
+        .with_context(|| format!("no url configured for remote '{name}'"))?;
+    let fetch = config.get_all(&format!("remote.{name}.fetch"));
+
+    Ok((
+        url.to_string(),
+        fetch.into_iter().map(str::to_string).collect(),
+    ))
+}
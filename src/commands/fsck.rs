@@ -0,0 +1,322 @@
+use anyhow::{Context, Result};
+use flate2::read::ZlibDecoder;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::path::Path;
+
+use crate::index::{index_path, Index};
+use crate::object::{
+    hash_object, loose_objects, Commit, FileMode, GitObject, GitObjectType, Tag, Tree,
+};
+use crate::pack::{pack_dir, PackFile, PackIndex};
+use crate::refs;
+
+/// An object that passed its own checks, with the objects it points at and
+/// the type each of them should have
+#[derive(Debug)]
+struct Checked {
+    obj_type: GitObjectType,
+    links: Vec<(String, GitObjectType)>,
+}
+
+#[derive(Debug, Default)]
+struct Fsck {
+    objects: HashMap<String, Checked>,
+    errors: usize,
+}
+
+/// Verifies every loose and packed object, then that everything reachable from
+/// refs, HEAD and the index is present, failing if any problem was found
+pub(crate) fn invoke(no_dangling: bool) -> Result<()> {
+    let mut fsck = Fsck::default();
+
+    for (hash, path) in loose_objects()? {
+        fsck.check_loose(&hash, &path);
+    }
+    fsck.check_packs().context("checking packs")?;
+    fsck.check_connectivity(no_dangling)
+        .context("checking connectivity")?;
+
+    anyhow::ensure!(
+        fsck.errors == 0,
+        "found {} problem{}",
+        fsck.errors,
+        if fsck.errors == 1 { "" } else { "s" }
+    );
+
+    Ok(())
+}
+
+impl Fsck {
+    fn error(&mut self, message: String) {
+        eprintln!("error: {message}");
+        self.errors += 1;
+    }
+
+    /// A loose object must inflate to a `<type> <size>\0` header and exactly `size`
+    /// bytes, hashing to the name of its file
+    fn check_loose(&mut self, hash: &str, path: &Path) {
+        let mut raw = Vec::new();
+        let inflated =
+            std::fs::File::open(path).and_then(|file| ZlibDecoder::new(file).read_to_end(&mut raw));
+        if let Err(e) = inflated {
+            return self.error(format!("{hash}: object corrupt or missing: {e}"));
+        }
+
+        let Some(nul) = raw.iter().position(|&b| b == 0) else {
+            return self.error(format!("{hash}: object has no header"));
+        };
+        let header = String::from_utf8_lossy(&raw[..nul]).into_owned();
+        let body = &raw[nul + 1..];
+
+        let Some((obj_type, size)) = header.split_once(' ') else {
+            return self.error(format!("{hash}: malformed object header '{header}'"));
+        };
+        let obj_type = match GitObjectType::try_from(obj_type) {
+            Ok(obj_type) => obj_type,
+            Err(e) => return self.error(format!("{hash}: {e}")),
+        };
+        if size.parse::<usize>().ok() != Some(body.len()) {
+            return self.error(format!(
+                "{hash}: header claims {size} bytes but the object has {}",
+                body.len()
+            ));
+        }
+
+        self.check_object(hash, obj_type, body.to_vec());
+    }
+
+    fn check_packs(&mut self) -> Result<()> {
+        let dir = pack_dir();
+        if !dir.exists() {
+            return Ok(());
+        }
+
+        let mut idx_paths = Vec::new();
+        for entry in std::fs::read_dir(&dir).context("reading pack directory")? {
+            let path = entry.context("bad pack directory entry")?.path();
+            if path.extension().is_some_and(|ext| ext == "idx") {
+                idx_paths.push(path);
+            }
+        }
+        idx_paths.sort();
+
+        for idx_path in idx_paths {
+            let pack_path = idx_path.with_extension("pack");
+            let opened = PackIndex::load(&idx_path).and_then(|index| {
+                let pack = PackFile::open(&pack_path)?;
+                pack.verify().context("verify pack checksum")?;
+                anyhow::ensure!(
+                    index.pack_checksum == pack.checksum,
+                    "index checksum does not match the pack"
+                );
+                Ok((index, pack))
+            });
+
+            let (index, mut pack) = match opened {
+                Ok(opened) => opened,
+                Err(e) => {
+                    self.error(format!("{}: corrupt pack: {e:#}", pack_path.display()));
+                    continue;
+                }
+            };
+
+            for entry in &index.entries {
+                match pack.object_at(entry.offset) {
+                    Ok((obj_type, content)) => {
+                        if hash_object(obj_type, &content) != entry.hash {
+                            self.error(format!(
+                                "{}: packed object does not hash to its id in {}",
+                                entry.hash,
+                                pack_path.display()
+                            ));
+                            continue;
+                        }
+                        self.check_object(&entry.hash, obj_type, content);
+                    }
+                    Err(e) => self.error(format!(
+                        "{}: corrupt packed object in {}: {e:#}",
+                        entry.hash,
+                        pack_path.display()
+                    )),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hashes the object and checks its syntax, recording its links if it's sound
+    fn check_object(&mut self, hash: &str, obj_type: GitObjectType, content: Vec<u8>) {
+        if self.objects.contains_key(hash) {
+            return;
+        }
+
+        if hash_object(obj_type, &content) != hash {
+            return self.error(format!("{hash}: hash mismatch"));
+        }
+
+        let obj = GitObject {
+            hash: hash.to_string(),
+            size: content.len(),
+            content,
+            obj_type,
+        };
+        let links = match obj_type {
+            GitObjectType::Blob => Ok(Vec::new()),
+            GitObjectType::Tree => check_tree(&obj),
+            GitObjectType::Commit => check_commit(&obj),
+            GitObjectType::Tag => check_tag(&obj),
+        };
+
+        match links {
+            Ok(links) => {
+                self.objects
+                    .insert(hash.to_string(), Checked { obj_type, links });
+            }
+            Err(e) => self.error(format!("in {obj_type} {hash}: {e:#}")),
+        }
+    }
+
+    fn check_connectivity(&mut self, no_dangling: bool) -> Result<()> {
+        let mut roots: Vec<(String, String)> = refs::list_refs("refs/")
+            .context("listing refs")?
+            .into_iter()
+            .collect();
+        if let Some(head) = refs::resolve("HEAD").context("resolving HEAD")? {
+            roots.push(("HEAD".to_string(), head));
+        }
+        for entry in Index::load(index_path()).context("loading index")?.entries {
+            if !entry.intent_to_add && entry.file_mode() != FileMode::Gitlink {
+                roots.push((format!("index entry {}", entry.path), entry.hash));
+            }
+        }
+
+        let mut pending = Vec::new();
+        for (name, hash) in roots {
+            if self.objects.contains_key(&hash) {
+                pending.push(hash);
+            } else {
+                self.error(format!("{name}: invalid sha1 pointer {hash}"));
+            }
+        }
+
+        let mut reachable = HashSet::new();
+        let mut broken = Vec::new();
+        while let Some(hash) = pending.pop() {
+            if !reachable.insert(hash.clone()) {
+                continue;
+            }
+
+            let checked = &self.objects[&hash];
+            for (link, expected) in &checked.links {
+                match self.objects.get(link) {
+                    Some(target) if target.obj_type == *expected => pending.push(link.clone()),
+                    Some(target) => broken.push(format!(
+                        "broken link from {} {hash} to {expected} {link}, which is a {}",
+                        checked.obj_type, target.obj_type
+                    )),
+                    None => broken.push(format!("missing {expected} {link}")),
+                }
+            }
+        }
+
+        broken.sort();
+        broken.dedup();
+        for message in broken {
+            self.error(message);
+        }
+
+        if no_dangling {
+            return Ok(());
+        }
+
+        // Unreachable objects that nothing else points at are the dangling ones
+        let used: HashSet<&String> = self
+            .objects
+            .values()
+            .flat_map(|checked| checked.links.iter().map(|(link, _)| link))
+            .collect();
+        let dangling: BTreeMap<&String, GitObjectType> = self
+            .objects
+            .iter()
+            .filter(|(hash, _)| !reachable.contains(*hash) && !used.contains(hash))
+            .map(|(hash, checked)| (hash, checked.obj_type))
+            .collect();
+        for (hash, obj_type) in dangling {
+            println!("dangling {obj_type} {hash}");
+        }
+
+        Ok(())
+    }
+}
+
+/// Entries must have sensible names, appear once each and be in git's sort order
+fn check_tree(obj: &GitObject) -> Result<Vec<(String, GitObjectType)>> {
+    let tree = Tree::try_from(obj)?;
+
+    let mut names = HashSet::new();
+    let mut previous: Option<Vec<u8>> = None;
+    let mut links = Vec::new();
+    for entry in &tree.entries {
+        entry.check_name()?;
+        let name = entry.name_lossy();
+        anyhow::ensure!(names.insert(&entry.name), "duplicate entry '{name}'");
+
+        let key = entry.sort_key();
+        anyhow::ensure!(
+            previous.as_ref().is_none_or(|previous| *previous < key),
+            "entries are not sorted at '{name}'"
+        );
+        previous = Some(key);
+
+        if let FileMode::Other { mode, width } = entry.mode {
+            let problem = if width > format!("{mode:o}").len() {
+                "zero-padded"
+            } else {
+                "bad"
+            };
+            eprintln!(
+                "warning in tree {}: '{name}' has {problem} mode {}",
+                obj.hash, entry.mode
+            );
+        }
+        if entry.mode.canonical() != FileMode::Gitlink {
+            links.push((entry.hash.clone(), entry.mode.object_type()));
+        }
+    }
+
+    Ok(links)
+}
+
+fn check_commit(obj: &GitObject) -> Result<Vec<(String, GitObjectType)>> {
+    let commit = Commit::try_from(obj)?;
+
+    anyhow::ensure!(refs::is_hash(&commit.tree), "invalid tree {}", commit.tree);
+    let mut links = vec![(commit.tree, GitObjectType::Tree)];
+    for parent in commit.parents {
+        anyhow::ensure!(refs::is_hash(&parent), "invalid parent {parent}");
+        links.push((parent, GitObjectType::Commit));
+    }
+
+    Ok(links)
+}
+
+fn check_tag(obj: &GitObject) -> Result<Vec<(String, GitObjectType)>> {
+    let tag = Tag::try_from(obj)?;
+
+    anyhow::ensure!(refs::is_hash(&tag.object), "invalid object {}", tag.object);
+
+    let valid = std::str::from_utf8(&tag.name)
+        .is_ok_and(|name| refs::validate_name(&format!("refs/tags/{name}")).is_ok());
+    if !valid {
+        eprintln!(
+            "warning in tag {}: invalid tag name '{}'",
+            obj.hash,
+            String::from_utf8_lossy(&tag.name)
+        );
+    }
+
+    Ok(vec![(tag.object, tag.obj_type)])
+}
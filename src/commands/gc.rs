@@ -0,0 +1,90 @@
+use anyhow::{Context, Result};
+
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use crate::object::loose_objects;
+use crate::refs;
+
+/// Unreachable objects younger than this are kept in case something is about to reference them
+const DEFAULT_PRUNE_EXPIRY: &str = "2.weeks.ago";
+
+/// Packs refs and reachable objects, then prunes unreachable loose objects last
+/// modified before `prune` (`now`, `never` or e.g. `2.weeks.ago`)
+pub(crate) fn invoke(prune: Option<String>, quiet: bool) -> Result<()> {
+    let expiry = parse_expiry(prune.as_deref().unwrap_or(DEFAULT_PRUNE_EXPIRY))?;
+
+    let packed = refs::pack_refs().context("packing refs")?;
+    if !quiet && packed > 0 {
+        println!("Packed {packed} refs");
+    }
+
+    super::repack::invoke(true, quiet).context("repacking objects")?;
+
+    if let Some(expiry) = expiry {
+        let pruned = prune_unreachable(expiry).context("pruning unreachable objects")?;
+        if !quiet && pruned > 0 {
+            println!("Pruned {pruned} unreachable objects");
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes unreachable loose objects last modified before `expiry`
+fn prune_unreachable(expiry: SystemTime) -> Result<usize> {
+    let reachable: HashSet<String> = super::repack::reachable_objects()?
+        .into_iter()
+        .map(|obj| obj.hash)
+        .collect();
+
+    let mut pruned = 0;
+    for (hash, path) in loose_objects()? {
+        if reachable.contains(&hash) {
+            continue;
+        }
+
+        let modified = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("reading modification time of {}", path.display()))?;
+        if modified <= expiry {
+            std::fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+            pruned += 1;
+        }
+    }
+
+    super::repack::remove_empty_fanout_dirs()?;
+    Ok(pruned)
+}
+
+/// The cutoff for pruning, `None` meaning nothing should be pruned
+fn parse_expiry(value: &str) -> Result<Option<SystemTime>> {
+    match value {
+        "now" => return Ok(Some(SystemTime::now())),
+        "never" => return Ok(None),
+        _ => {}
+    }
+
+    let invalid = || format!("invalid prune expiry '{value}'");
+    let relative = value.strip_suffix(".ago").with_context(invalid)?;
+    let (count, unit) = relative.split_once('.').with_context(invalid)?;
+    let count: u64 = count.parse().with_context(invalid)?;
+
+    let seconds = match unit.strip_suffix('s').unwrap_or(unit) {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        "month" => 30 * 24 * 60 * 60,
+        "year" => 365 * 24 * 60 * 60,
+        _ => anyhow::bail!("{}", invalid()),
+    };
+
+    let age = Duration::from_secs(count.saturating_mul(seconds));
+    Ok(Some(
+        SystemTime::now()
+            .checked_sub(age)
+            .unwrap_or(SystemTime::UNIX_EPOCH),
+    ))
+}
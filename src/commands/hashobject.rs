@@ -1,13 +1,10 @@
 use crate::object::GitObject;
+use crate::repository::cli_path;
 use anyhow::{Context, Result};
 
 pub(crate) fn invoke(file: &str, write: bool) -> Result<()> {
-    //
-    let object = GitObject::create_blob(&file).context("creating git object")?;
-    if write {
-        object.write().context("writing git object")?;
-    }
+    let hash = GitObject::hash_file(cli_path(file), write).context("creating git object")?;
 
-    println!("{}", object.hash);
+    println!("{hash}");
     Ok(())
 }
@@ -2,11 +2,34 @@ use anyhow::{Context, Result};
 
 use std::fs;
 
-pub(crate) fn invoke() -> Result<()> {
-    fs::create_dir_all(".git/objects").context("creating the git objects directory")?;
-    fs::create_dir_all(".git/refs").context("creating the git refs directory")?;
-    fs::write(".git/HEAD", "ref: refs/heads/main\n").context("writing HEAD file")?;
-    println!("Initialized git directory");
+use crate::refs;
+use crate::repository::{git_path, Repository};
+
+pub(crate) fn invoke(bare: bool) -> Result<()> {
+    Repository::for_init(bare)?.install()?;
+
+    // Reinitialising keeps an existing HEAD and config, as git does
+    let existing = git_path("HEAD").exists();
+
+    fs::create_dir_all(git_path("objects")).context("creating the git objects directory")?;
+    fs::create_dir_all(git_path("refs/heads")).context("creating the git refs directory")?;
+    fs::create_dir_all(git_path("refs/tags")).context("creating the git tags directory")?;
+    if !existing {
+        refs::write_symref("HEAD", "refs/heads/main").context("writing HEAD file")?;
+    }
+    if !git_path("config").exists() {
+        fs::write(
+            git_path("config"),
+            format!("[core]\n\trepositoryformatversion = 0\n\tfilemode = true\n\tbare = {bare}\n\tlogallrefupdates = true\n"),
+        )
+        .context("writing config file")?;
+    }
+
+    if existing {
+        println!("Reinitialized existing git directory");
+    } else {
+        println!("Initialized git directory");
+    }
 
     Ok(())
 }
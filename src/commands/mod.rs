@@ -1,7 +1,16 @@
+pub(crate) mod add;
 pub(crate) mod catfile;
 pub(crate) mod clone;
+pub(crate) mod commit;
 pub(crate) mod committree;
+pub(crate) mod config;
+pub(crate) mod fetch;
+pub(crate) mod fsck;
+pub(crate) mod gc;
 pub(crate) mod hashobject;
 pub(crate) mod init;
 pub(crate) mod lstree;
+pub(crate) mod push;
+pub(crate) mod repack;
+pub(crate) mod status;
 pub(crate) mod writetree;
@@ -0,0 +1,420 @@
+use anyhow::{Context, Result};
+
+use std::collections::HashMap;
+
+use crate::object::{is_ancestor, objects_between, GitObject};
+use crate::pack::build_pack;
+use crate::protocol::{AdvertisedRef, ReceivePack, RefUpdate};
+use crate::refs::{self, short_name, shorthand_candidates, Refspec, NULL_HASH};
+
+use super::fetch::remote_config;
+
+pub(crate) struct PushOptions {
+    pub(crate) force: bool,
+    pub(crate) delete: bool,
+    /// `Some("")` for a bare `--force-with-lease`, otherwise `<ref>[:<expect>]`
+    pub(crate) force_with_lease: Option<String>,
+    pub(crate) quiet: bool,
+}
+
+/// Only allow overwriting a remote ref if it is where we last saw it
+#[derive(Debug)]
+struct Lease {
+    /// The remote ref this lease covers, or all of them
+    name: Option<String>,
+    /// The value to expect, defaulting to our remote-tracking ref
+    expect: Option<String>,
+}
+
+/// An update we mean to send along with the local name it came from
+#[derive(Debug)]
+struct Planned {
+    src: Option<String>,
+    update: RefUpdate,
+    force: bool,
+}
+
+pub(crate) async fn invoke(
+    remote: String,
+    refspecs: Vec<String>,
+    options: PushOptions,
+) -> Result<()> {
+    let (url, fetch_refspecs) = if remote.contains("://") {
+        (remote.clone(), Vec::new())
+    } else {
+        remote_config(&remote).with_context(|| format!("reading remote '{remote}'"))?
+    };
+    let fetch_refspecs = fetch_refspecs
+        .iter()
+        .map(|spec| Refspec::parse(spec))
+        .collect::<Result<Vec<_>>>()?;
+
+    let refspecs = push_refspecs(refspecs, &options)?;
+    let lease = options
+        .force_with_lease
+        .as_deref()
+        .map(parse_lease)
+        .transpose()?;
+
+    let connection = ReceivePack::connect(&url).await.context("ref discovery")?;
+    let remote_refs = connection.refs();
+
+    let mut planned = Vec::new();
+    for spec in &refspecs {
+        planned.extend(plan(spec, remote_refs)?);
+    }
+
+    let mut report = Vec::new();
+    let mut updates = Vec::new();
+    for mut plan in planned {
+        if plan.update.old == plan.update.new && !plan.update.is_delete() {
+            continue;
+        }
+
+        if let Some(lease) = lease.as_ref().filter(|lease| {
+            lease
+                .name
+                .as_ref()
+                .is_none_or(|name| name == &plan.update.name)
+        }) {
+            let expected = match &lease.expect {
+                Some(expect) => Some(expect.clone()),
+                None => tracking_ref(&fetch_refspecs, &plan.update.name)?,
+            };
+            if expected.as_deref().unwrap_or(NULL_HASH) != plan.update.old {
+                report.push(rejected(&plan, "[rejected]", "stale info"));
+                continue;
+            }
+            plan.force = true;
+        }
+
+        if let Some(reason) = check_update(&plan)? {
+            report.push(rejected(&plan, "[rejected]", reason));
+            continue;
+        }
+        updates.push(plan);
+    }
+
+    let mut failed = !report.is_empty();
+    if !updates.is_empty() {
+        let commands: Vec<RefUpdate> = updates.iter().map(|plan| plan.update.clone()).collect();
+        let pack = if commands.iter().all(|update| update.is_delete()) {
+            None
+        } else {
+            let ofs_delta = connection.supports("ofs-delta");
+            Some(pack_for(&commands, remote_refs, ofs_delta).context("building pack")?)
+        };
+
+        let statuses: HashMap<String, std::result::Result<(), String>> = connection
+            .push(&commands, pack, options.quiet)
+            .await
+            .context("sending pack")?
+            .into_iter()
+            .collect();
+
+        for plan in &updates {
+            match statuses.get(&plan.update.name) {
+                Some(Ok(())) => {
+                    update_tracking_ref(&fetch_refspecs, &plan.update)?;
+                    report.push(accepted(plan)?);
+                }
+                Some(Err(reason)) => {
+                    failed = true;
+                    report.push(rejected(plan, "[remote rejected]", reason));
+                }
+                None => {
+                    failed = true;
+                    report.push(rejected(
+                        plan,
+                        "[remote failure]",
+                        "remote did not report status",
+                    ));
+                }
+            }
+        }
+    }
+
+    if report.is_empty() {
+        if !options.quiet {
+            println!("Everything up-to-date");
+        }
+    } else if !options.quiet || failed {
+        println!("To {}", connection.url());
+        for line in report {
+            println!("{line}");
+        }
+    }
+    anyhow::ensure!(!failed, "failed to push some refs to {url}");
+
+    Ok(())
+}
+
+/// The refspecs given on the command line, or the current branch by default
+fn push_refspecs(refspecs: Vec<String>, options: &PushOptions) -> Result<Vec<Refspec>> {
+    let mut specs = if options.delete {
+        anyhow::ensure!(
+            !refspecs.is_empty(),
+            "--delete doesn't make sense without any refs"
+        );
+        refspecs
+            .iter()
+            .map(|name| Refspec::parse(&format!(":{name}")))
+            .collect::<Result<Vec<_>>>()?
+    } else if refspecs.is_empty() {
+        let branch = refs::current_branch()?
+            .context("HEAD is detached, so there is no current branch to push")?;
+        vec![Refspec::parse(&format!("{branch}:{branch}"))?]
+    } else {
+        refspecs
+            .iter()
+            .map(|spec| Refspec::parse(spec))
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    for spec in &mut specs {
+        spec.force |= options.force;
+    }
+
+    Ok(specs)
+}
+
+fn parse_lease(raw: &str) -> Result<Lease> {
+    if raw.is_empty() {
+        return Ok(Lease {
+            name: None,
+            expect: None,
+        });
+    }
+
+    let (name, expect) = match raw.split_once(':') {
+        Some((name, expect)) => (name, Some(expect)),
+        None => (raw, None),
+    };
+    let name = if name.starts_with("refs/") {
+        name.to_string()
+    } else {
+        format!("refs/heads/{name}")
+    };
+
+    let expect = match expect {
+        Some(expect) if refs::is_hash(expect) => Some(expect.to_string()),
+        Some(expect) => Some(
+            resolve_local(expect)
+                .with_context(|| format!("resolving --force-with-lease value {expect}"))?
+                .1,
+        ),
+        None => None,
+    };
+
+    Ok(Lease {
+        name: Some(name),
+        expect,
+    })
+}
+
+/// The updates a refspec asks for, against what the remote currently has
+fn plan(spec: &Refspec, remote_refs: &[AdvertisedRef]) -> Result<Vec<Planned>> {
+    let planned = |src: Option<String>, new: &str, dst: String| {
+        let old = remote_refs
+            .iter()
+            .find(|r| r.name == dst)
+            .map_or(NULL_HASH.to_string(), |r| r.hash.clone());
+        Planned {
+            src,
+            update: RefUpdate {
+                name: dst,
+                old,
+                new: new.to_string(),
+            },
+            force: spec.force,
+        }
+    };
+
+    if spec.src.is_empty() {
+        let dst = spec.dst.as_deref().unwrap_or_default();
+        let dst = qualify_remote(dst, remote_refs, None);
+        return Ok(vec![planned(None, NULL_HASH, dst)]);
+    }
+
+    if spec.is_glob() {
+        return Ok(refs::list_refs("refs/")?
+            .into_iter()
+            .filter(|(name, _)| spec.matches(name))
+            .map(|(name, hash)| {
+                let dst = spec.destination(&name).unwrap_or_else(|| name.clone());
+                planned(Some(name), &hash, dst)
+            })
+            .collect());
+    }
+
+    let (name, hash) = resolve_local(&spec.src)?;
+    let dst = match &spec.dst {
+        Some(dst) => qualify_remote(dst, remote_refs, name.as_deref()),
+        None => name
+            .clone()
+            .with_context(|| format!("pushing {} needs a destination ref", spec.src))?,
+    };
+
+    Ok(vec![planned(name, &hash, dst)])
+}
+
+/// Resolves the source side of a push refspec to its full ref name (if it is
+/// one) and the object it points at
+fn resolve_local(src: &str) -> Result<(Option<String>, String)> {
+    if refs::is_hash(src) {
+        return Ok((None, src.to_string()));
+    }
+
+    if src == "HEAD" {
+        let hash = refs::resolve("HEAD")?.context("HEAD does not point at a commit yet")?;
+        return Ok((refs::current_branch()?, hash));
+    }
+
+    let candidates = if src.starts_with("refs/") {
+        vec![src.to_string()]
+    } else {
+        shorthand_candidates(src)
+    };
+    for candidate in candidates {
+        if let Some(hash) = refs::resolve(&candidate)? {
+            return Ok((Some(candidate), hash));
+        }
+    }
+
+    anyhow::bail!("src refspec {src} does not match any")
+}
+
+/// Expands a short destination name, preferring a ref the remote already has
+fn qualify_remote(dst: &str, remote_refs: &[AdvertisedRef], src: Option<&str>) -> String {
+    if dst.starts_with("refs/") {
+        return dst.to_string();
+    }
+
+    let existing = shorthand_candidates(dst)
+        .into_iter()
+        .find(|candidate| remote_refs.iter().any(|r| &r.name == candidate));
+    if let Some(existing) = existing {
+        return existing;
+    }
+
+    match src {
+        Some(src) if src.starts_with("refs/tags/") => format!("refs/tags/{dst}"),
+        _ => format!("refs/heads/{dst}"),
+    }
+}
+
+/// Why an update can't be sent without forcing it, if it can't
+fn check_update(plan: &Planned) -> Result<Option<&'static str>> {
+    let update = &plan.update;
+    if update.is_delete() {
+        return Ok((update.old == NULL_HASH).then_some("remote ref does not exist"));
+    }
+    if plan.force || update.old == NULL_HASH {
+        return Ok(None);
+    }
+
+    if update.name.starts_with("refs/tags/") {
+        Ok(Some("already exists"))
+    } else if !GitObject::exists(&update.old)? {
+        Ok(Some("fetch first"))
+    } else if !is_ancestor(&update.old, &update.new)? {
+        Ok(Some("non-fast-forward"))
+    } else {
+        Ok(None)
+    }
+}
+
+/// A pack of everything the new values need that the remote's refs don't already reach
+fn pack_for(
+    updates: &[RefUpdate],
+    remote_refs: &[AdvertisedRef],
+    ofs_delta: bool,
+) -> Result<Vec<u8>> {
+    let tips: Vec<String> = updates
+        .iter()
+        .filter(|update| !update.is_delete())
+        .map(|update| update.new.clone())
+        .collect();
+    let exclude: Vec<String> = remote_refs.iter().map(|r| r.hash.clone()).collect();
+
+    let objects = objects_between(&tips, &exclude)?;
+    build_pack(&objects, ofs_delta)
+}
+
+/// Where the configured fetch refspecs keep a copy of a remote ref
+fn tracking_ref(fetch_refspecs: &[Refspec], name: &str) -> Result<Option<String>> {
+    match fetch_refspecs
+        .iter()
+        .find_map(|spec| spec.destination(name))
+    {
+        Some(tracking) => refs::resolve(&tracking),
+        None => Ok(None),
+    }
+}
+
+fn update_tracking_ref(fetch_refspecs: &[Refspec], update: &RefUpdate) -> Result<()> {
+    let Some(tracking) = fetch_refspecs
+        .iter()
+        .find_map(|spec| spec.destination(&update.name))
+    else {
+        return Ok(());
+    };
+
+    if !update.is_delete() {
+        refs::update_ref(&tracking, &update.new, None)
+    } else if refs::read_ref(&tracking)?.is_some() {
+        refs::delete_ref(&tracking, None)
+    } else {
+        Ok(())
+    }
+    .with_context(|| format!("updating {tracking}"))
+}
+
+fn accepted(plan: &Planned) -> Result<String> {
+    let update = &plan.update;
+    if update.is_delete() {
+        return Ok(format!(
+            " - {:<17} {}",
+            "[deleted]",
+            short_name(&update.name)
+        ));
+    }
+
+    let refs = format!("{} -> {}", source_name(plan), short_name(&update.name));
+    if update.old == NULL_HASH {
+        let kind = if update.name.starts_with("refs/tags/") {
+            "[new tag]"
+        } else if update.name.starts_with("refs/heads/") {
+            "[new branch]"
+        } else {
+            "[new reference]"
+        };
+        return Ok(format!(" * {kind:<17} {refs}"));
+    }
+
+    let (old, new) = (&update.old[..7], &update.new[..7]);
+    if GitObject::exists(&update.old)? && is_ancestor(&update.old, &update.new)? {
+        Ok(format!("   {:<17} {refs}", format!("{old}..{new}")))
+    } else {
+        Ok(format!(
+            " + {:<17} {refs} (forced update)",
+            format!("{old}...{new}")
+        ))
+    }
+}
+
+fn rejected(plan: &Planned, label: &str, reason: &str) -> String {
+    let name = short_name(&plan.update.name);
+    if plan.update.is_delete() {
+        format!(" ! {label:<17} {name} ({reason})")
+    } else {
+        format!(" ! {label:<17} {} -> {name} ({reason})", source_name(plan))
+    }
+}
+
+fn source_name(plan: &Planned) -> &str {
+    match &plan.src {
+        Some(src) => short_name(src),
+        None => &plan.update.new[..7],
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::index::{index_path, Index};
+use crate::object::{
+    loose_objects, loose_path, objects_between, objects_dir, FileMode, GitObject, GitObjectType,
+    ReachableObject,
+};
+use crate::pack::{self, build_pack, pack_dir, PackFile, PackIndex};
+use crate::refs;
+
+/// Packs every reachable loose object into a new pack, then deletes loose
+/// objects that a pack now holds. With `all`, everything reachable goes into
+/// the new pack and the packs it replaces are deleted.
+pub(crate) fn invoke(all: bool, quiet: bool) -> Result<()> {
+    let loose: HashSet<String> = loose_objects()?.into_iter().map(|(hash, _)| hash).collect();
+    let existing = if all {
+        existing_packs().context("listing packs")?
+    } else {
+        Vec::new()
+    };
+
+    let reachable = reachable_objects().context("finding reachable objects")?;
+    let mut objects = Vec::new();
+    for object in &reachable {
+        if all || (loose.contains(&object.hash) && !pack::contains(&object.hash)?) {
+            objects.push(object.clone());
+        }
+    }
+
+    let mut name = None;
+    if !objects.is_empty() {
+        let data = build_pack(&objects, true).context("building pack")?;
+        let stored = PackFile::new("repack", Bytes::from(data))
+            .and_then(|mut pack| pack.store())
+            .context("storing pack")?;
+        if !quiet {
+            println!("Packed {} objects into {stored}", objects.len());
+        }
+        name = Some(stored);
+    }
+
+    let removed = prune_packed().context("removing packed loose objects")?;
+    if !quiet && removed > 0 {
+        println!("Removed {removed} loose objects already in packs");
+    }
+
+    let reachable: HashSet<&str> = reachable.iter().map(|obj| obj.hash.as_str()).collect();
+    for old in existing {
+        if name
+            .as_ref()
+            .is_some_and(|name| old.ends_with(format!("{name}.idx")))
+        {
+            continue;
+        }
+        replace_pack(&old, &reachable)
+            .with_context(|| format!("removing replaced pack {}", old.display()))?;
+    }
+
+    Ok(())
+}
+
+/// The index of every pack currently in `.git/objects/pack`
+fn existing_packs() -> Result<Vec<PathBuf>> {
+    let dir = pack_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut packs = Vec::new();
+    for entry in std::fs::read_dir(&dir).context("reading pack directory")? {
+        let path = entry.context("bad pack directory entry")?.path();
+        if path.extension().is_some_and(|ext| ext == "idx") {
+            packs.push(path);
+        }
+    }
+
+    Ok(packs)
+}
+
+/// Deletes a pack whose reachable objects are now in another one. Its
+/// unreachable objects are written out loose with the pack's modification
+/// time, so they only disappear once `gc` would have pruned them anyway.
+fn replace_pack(idx_path: &Path, reachable: &HashSet<&str>) -> Result<()> {
+    let pack_path = idx_path.with_extension("pack");
+    let modified = std::fs::metadata(&pack_path)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("reading modification time of {}", pack_path.display()))?;
+
+    let index = PackIndex::load(idx_path)?;
+    for entry in &index.entries {
+        let path = loose_path(&entry.hash)?;
+        if reachable.contains(entry.hash.as_str()) || path.exists() {
+            continue;
+        }
+
+        GitObject::load(&entry.hash)
+            .and_then(|obj| obj.write())
+            .with_context(|| format!("loosening unreachable object {}", entry.hash))?;
+        File::open(&path)
+            .and_then(|file| file.set_modified(modified))
+            .with_context(|| format!("setting modification time of {}", path.display()))?;
+    }
+
+    std::fs::remove_file(&pack_path)
+        .with_context(|| format!("removing {}", pack_path.display()))?;
+    std::fs::remove_file(idx_path).with_context(|| format!("removing {}", idx_path.display()))
+}
+
+/// Everything reachable from refs, HEAD, reflogs and the index, as `gc` must keep it
+pub(crate) fn reachable_objects() -> Result<Vec<ReachableObject>> {
+    let mut tips: Vec<String> = refs::list_refs("refs/")
+        .context("listing refs")?
+        .into_values()
+        .collect();
+    tips.extend(refs::resolve("HEAD").context("resolving HEAD")?);
+    // Old reflog entries may point at objects that are already gone
+    for hash in refs::logged_objects().context("reading reflogs")? {
+        if GitObject::exists(&hash)? {
+            tips.push(hash);
+        }
+    }
+    tips.sort();
+    tips.dedup();
+
+    let mut objects = objects_between(&tips, &[])?;
+
+    // Staged content isn't reachable from any commit yet
+    let mut seen: HashSet<String> = objects.iter().map(|obj| obj.hash.clone()).collect();
+    for entry in Index::load(index_path()).context("loading index")?.entries {
+        // Submodule commits live in another repository
+        if entry.intent_to_add || entry.file_mode() == FileMode::Gitlink {
+            continue;
+        }
+        if !seen.insert(entry.hash.clone()) {
+            continue;
+        }
+        objects.push(ReachableObject {
+            hash: entry.hash,
+            obj_type: GitObjectType::Blob,
+            path: entry.path,
+        });
+    }
+
+    Ok(objects)
+}
+
+/// Deletes loose objects that are also in a pack, returning how many went
+fn prune_packed() -> Result<usize> {
+    let mut removed = 0;
+    for (hash, path) in loose_objects()? {
+        if pack::contains(&hash)? {
+            std::fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+            removed += 1;
+        }
+    }
+
+    remove_empty_fanout_dirs()?;
+    Ok(removed)
+}
+
+pub(crate) fn remove_empty_fanout_dirs() -> Result<()> {
+    for entry in std::fs::read_dir(objects_dir()).context("reading object directory")? {
+        let path = entry.context("bad object directory entry")?.path();
+        let is_fanout = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.len() == 2);
+        // Fails harmlessly if the directory still has objects in it
+        if is_fanout && path.is_dir() {
+            let _ = std::fs::remove_dir(&path);
+        }
+    }
+
+    Ok(())
+}
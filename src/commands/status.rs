@@ -0,0 +1,408 @@
+use anyhow::{Context, Result};
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::os::unix::fs::MetadataExt;
+
+use crate::index::{index_path, Ignore, Index, IndexEntry};
+use crate::object::{flatten_tree, list_files, quote_path, Commit, FileMode, GitObject};
+use crate::refs::{self, NULL_HASH};
+use crate::repository::repository;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StatusFormat {
+    Long,
+    PorcelainV1,
+    PorcelainV2,
+}
+
+/// A path that differs between HEAD and the index and/or the index and the working tree
+#[derive(Debug)]
+struct Change {
+    path: String,
+    staged: char,
+    unstaged: char,
+    head: Option<(u32, String)>,
+    index: Option<(u32, String)>,
+    worktree_mode: u32,
+}
+
+/// A path with merge conflict stages in the index
+#[derive(Debug)]
+struct Unmerged {
+    path: String,
+    code: &'static str,
+    stages: [Option<(u32, String)>; 3],
+    worktree_mode: u32,
+}
+
+#[derive(Debug, Default)]
+struct Status {
+    branch: Option<String>,
+    head: Option<String>,
+    changes: Vec<Change>,
+    unmerged: Vec<Unmerged>,
+    untracked: Vec<String>,
+}
+
+pub(crate) fn invoke(format: StatusFormat) -> Result<()> {
+    repository().work_tree()?;
+    let status = collect().context("collecting status")?;
+
+    match format {
+        StatusFormat::Long => print_long(&status),
+        StatusFormat::PorcelainV1 => print_porcelain_v1(&status),
+        StatusFormat::PorcelainV2 => print_porcelain_v2(&status),
+    }
+
+    Ok(())
+}
+
+fn collect() -> Result<Status> {
+    let (branch, head) = read_head().context("reading HEAD")?;
+    let head_entries = match &head {
+        Some(hash) => {
+            let obj = GitObject::load(hash).context("loading HEAD commit")?;
+            let commit = Commit::try_from(&obj).context("parsing HEAD commit")?;
+            flatten_tree(&commit.tree).context("walking HEAD tree")?
+        }
+        None => BTreeMap::new(),
+    };
+
+    let index = Index::load(index_path()).context("loading index")?;
+    let mut ignore = Ignore::load().context("loading ignore patterns")?;
+    let files = list_files(".", &mut ignore, &index).context("listing working tree")?;
+    let present: HashSet<&str> = files.iter().map(|f| f.as_str()).collect();
+
+    let mut status = Status {
+        branch,
+        head,
+        ..Default::default()
+    };
+
+    let mut conflicted: BTreeMap<&str, Vec<&IndexEntry>> = BTreeMap::new();
+    let mut staged: BTreeMap<&str, &IndexEntry> = BTreeMap::new();
+    for entry in &index.entries {
+        if entry.stage == 0 {
+            staged.insert(&entry.path, entry);
+        } else {
+            conflicted.entry(&entry.path).or_default().push(entry);
+        }
+    }
+
+    let paths: BTreeSet<&str> = head_entries
+        .keys()
+        .map(|p| p.as_str())
+        .chain(staged.keys().copied())
+        .filter(|p| !conflicted.contains_key(p))
+        .collect();
+
+    for path in paths {
+        let head = head_entries
+            .get(path)
+            .map(|e| (e.mode.as_u32(), e.hash.clone()));
+        let entry = staged.get(path);
+        let index_state = entry.map(|e| (e.mode, e.hash.clone()));
+
+        let staged_code = match (&head, entry) {
+            (_, Some(entry)) if entry.intent_to_add => ' ',
+            (None, Some(_)) => 'A',
+            (Some(_), None) => 'D',
+            (Some(head), Some(entry)) => diff_code(head, &(entry.mode, entry.hash.clone())),
+            (None, None) => unreachable!("path came from HEAD or the index"),
+        };
+
+        let (unstaged_code, worktree_mode) = match entry {
+            Some(entry) => worktree_state(entry, present.contains(path))?,
+            None => (' ', 0),
+        };
+
+        if staged_code != ' ' || unstaged_code != ' ' {
+            status.changes.push(Change {
+                path: path.to_string(),
+                staged: staged_code,
+                unstaged: unstaged_code,
+                head,
+                index: index_state.filter(|_| entry.is_some_and(|e| !e.intent_to_add)),
+                worktree_mode,
+            });
+        }
+    }
+
+    for (path, entries) in conflicted {
+        let mut stages: [Option<(u32, String)>; 3] = Default::default();
+        for entry in entries {
+            stages[entry.stage as usize - 1] = Some((entry.mode, entry.hash.clone()));
+        }
+
+        let code = match (
+            stages[0].is_some(),
+            stages[1].is_some(),
+            stages[2].is_some(),
+        ) {
+            (true, false, false) => "DD",
+            (false, true, false) => "AU",
+            (true, true, false) => "UD",
+            (false, false, true) => "UA",
+            (true, false, true) => "DU",
+            (false, true, true) => "AA",
+            _ => "UU",
+        };
+
+        let worktree_mode = worktree_mode(path)?;
+        status.unmerged.push(Unmerged {
+            path: path.to_string(),
+            code,
+            stages,
+            worktree_mode,
+        });
+    }
+
+    status.untracked = untracked(&files, &index);
+
+    Ok(status)
+}
+
+/// Compares an index entry against the file on disk, hashing it only if the
+/// stat data suggests it may have changed
+fn worktree_state(entry: &IndexEntry, present: bool) -> Result<(char, u32)> {
+    if !present {
+        return Ok(('D', 0));
+    }
+
+    // The submodule's own checkout isn't compared against the recorded commit
+    if entry.file_mode() == FileMode::Gitlink {
+        return Ok((' ', entry.mode));
+    }
+
+    let metadata = std::fs::symlink_metadata(&entry.path)
+        .with_context(|| format!("getting {} metadata", entry.path))?;
+    let mode = FileMode::from_mode(metadata.mode()).as_u32();
+
+    if entry.intent_to_add {
+        return Ok(('A', mode));
+    }
+
+    if entry.stat_matches(&metadata) {
+        return Ok((' ', mode));
+    }
+
+    let hash = GitObject::hash_worktree_file(&entry.path, &metadata, false)
+        .with_context(|| format!("hashing {}", entry.path))?;
+    let code = diff_code(&(entry.mode, entry.hash.clone()), &(mode, hash));
+
+    Ok((code, mode))
+}
+
+fn worktree_mode(path: &str) -> Result<u32> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(FileMode::from_mode(metadata.mode()).as_u32()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).with_context(|| format!("getting {path} metadata")),
+    }
+}
+
+fn diff_code(old: &(u32, String), new: &(u32, String)) -> char {
+    let kind = |mode: u32| match FileMode::from_mode(mode) {
+        FileMode::Executable => FileMode::Regular,
+        other => other,
+    };
+
+    if kind(old.0) != kind(new.0) {
+        'T'
+    } else if old != new {
+        'M'
+    } else {
+        ' '
+    }
+}
+
+/// Untracked files, collapsing directories that contain nothing tracked into `dir/`
+fn untracked(files: &[String], index: &Index) -> Vec<String> {
+    let tracked: HashSet<&str> = index.entries.iter().map(|e| e.path.as_str()).collect();
+    let tracked_dirs: HashSet<&str> = index
+        .entries
+        .iter()
+        .flat_map(|e| e.path.match_indices('/').map(|(idx, _)| &e.path[..idx]))
+        .collect();
+
+    let mut untracked = BTreeSet::new();
+    for file in files.iter().filter(|f| !tracked.contains(f.as_str())) {
+        let collapsed = file
+            .match_indices('/')
+            .map(|(idx, _)| &file[..idx])
+            .find(|dir| !tracked_dirs.contains(dir));
+
+        match collapsed {
+            Some(dir) => untracked.insert(format!("{dir}/")),
+            None => untracked.insert(file.clone()),
+        };
+    }
+
+    untracked.into_iter().collect()
+}
+
+fn read_head() -> Result<(Option<String>, Option<String>)> {
+    let branch = refs::current_branch()?.map(|target| {
+        target
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&target)
+            .to_string()
+    });
+    let commit = refs::resolve("HEAD")?;
+
+    Ok((branch, commit))
+}
+
+fn print_long(status: &Status) {
+    match (&status.branch, &status.head) {
+        (Some(branch), _) => println!("On branch {branch}"),
+        (None, Some(head)) => println!("HEAD detached at {}", &head[..7]),
+        (None, None) => {}
+    }
+    if status.head.is_none() {
+        println!("\nNo commits yet");
+    }
+
+    let staged: Vec<_> = status.changes.iter().filter(|c| c.staged != ' ').collect();
+    let unstaged: Vec<_> = status
+        .changes
+        .iter()
+        .filter(|c| c.unstaged != ' ')
+        .collect();
+
+    if !staged.is_empty() {
+        println!("\nChanges to be committed:");
+        for change in &staged {
+            println!("\t{:<12}{}", label(change.staged), quoted(&change.path));
+        }
+    }
+
+    if !status.unmerged.is_empty() {
+        println!("\nUnmerged paths:");
+        for unmerged in &status.unmerged {
+            let label = match unmerged.code {
+                "DD" => "both deleted:",
+                "AU" => "added by us:",
+                "UD" => "deleted by them:",
+                "UA" => "added by them:",
+                "DU" => "deleted by us:",
+                "AA" => "both added:",
+                _ => "both modified:",
+            };
+            println!("\t{label:<17}{}", quoted(&unmerged.path));
+        }
+    }
+
+    if !unstaged.is_empty() {
+        println!("\nChanges not staged for commit:");
+        for change in &unstaged {
+            println!("\t{:<12}{}", label(change.unstaged), quoted(&change.path));
+        }
+    }
+
+    if !status.untracked.is_empty() {
+        println!("\nUntracked files:");
+        for path in &status.untracked {
+            println!("\t{}", quoted(path));
+        }
+    }
+
+    println!();
+    if !staged.is_empty() || !status.unmerged.is_empty() {
+        return;
+    }
+
+    if !unstaged.is_empty() {
+        println!("no changes added to commit (use \"git add\" and/or \"git commit -a\")");
+    } else if !status.untracked.is_empty() {
+        println!("nothing added to commit but untracked files present (use \"git add\" to track)");
+    } else if status.head.is_none() {
+        println!("nothing to commit (create/copy files and use \"git add\" to track)");
+    } else {
+        println!("nothing to commit, working tree clean");
+    }
+}
+
+/// Only the short format quotes paths containing spaces
+fn quoted(path: &str) -> String {
+    quote_path(path.as_bytes(), false)
+}
+
+fn label(code: char) -> &'static str {
+    match code {
+        'A' => "new file:",
+        'D' => "deleted:",
+        'T' => "typechange:",
+        _ => "modified:",
+    }
+}
+
+fn print_porcelain_v1(status: &Status) {
+    // Unmerged paths are listed in path order along with the other changes
+    let mut entries: Vec<(&str, String)> = status
+        .changes
+        .iter()
+        .map(|change| {
+            let code = format!("{}{}", change.staged, change.unstaged);
+            (change.path.as_str(), code)
+        })
+        .chain(
+            status
+                .unmerged
+                .iter()
+                .map(|unmerged| (unmerged.path.as_str(), unmerged.code.to_string())),
+        )
+        .collect();
+    entries.sort_by_key(|(path, _)| *path);
+
+    for (path, code) in entries {
+        println!("{code} {}", quote_path(path.as_bytes(), true));
+    }
+    for path in &status.untracked {
+        println!("?? {}", quote_path(path.as_bytes(), true));
+    }
+}
+
+fn print_porcelain_v2(status: &Status) {
+    let code = |c: char| if c == ' ' { '.' } else { c };
+    let mode = |state: &Option<(u32, String)>| state.as_ref().map_or(0, |(mode, _)| *mode);
+    let hash = |state: &Option<(u32, String)>| {
+        state
+            .as_ref()
+            .map_or(NULL_HASH.to_string(), |(_, hash)| hash.clone())
+    };
+
+    for change in &status.changes {
+        println!(
+            "1 {}{} N... {:06o} {:06o} {:06o} {} {} {}",
+            code(change.staged),
+            code(change.unstaged),
+            mode(&change.head),
+            mode(&change.index),
+            change.worktree_mode,
+            hash(&change.head),
+            hash(&change.index),
+            quoted(&change.path)
+        );
+    }
+
+    for unmerged in &status.unmerged {
+        let [base, ours, theirs] = &unmerged.stages;
+        println!(
+            "u {} N... {:06o} {:06o} {:06o} {:06o} {} {} {} {}",
+            unmerged.code,
+            mode(base),
+            mode(ours),
+            mode(theirs),
+            unmerged.worktree_mode,
+            hash(base),
+            hash(ours),
+            hash(theirs),
+            quoted(&unmerged.path)
+        );
+    }
+
+    for path in &status.untracked {
+        println!("? {}", quoted(path));
+    }
+}
@@ -2,13 +2,24 @@ use anyhow::{Context, Result};
 
 use std::path::Path;
 
+use crate::index::{index_path, Index};
 use crate::object::GitObject;
+use crate::repository::repository;
 
 pub(crate) fn invoke(path: impl AsRef<Path>) -> Result<()> {
-    let tree = GitObject::create_tree(path).context("creating tree object")?;
-    tree.write().context("writing tree object")?;
+    // Without a staging area fall back to hashing the working tree directly
+    if !index_path().exists() {
+        repository().work_tree()?;
+        let tree = GitObject::create_tree(path).context("creating tree object")?;
+        tree.write().context("writing tree object")?;
+        println!("{}", tree.hash);
 
-    println!("{}", tree.hash);
+        return Ok(());
+    }
+
+    let index = Index::load(index_path()).context("loading index")?;
+    let hash = index.write_tree().context("writing tree from index")?;
+    println!("{hash}");
 
     Ok(())
 }
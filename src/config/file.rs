@@ -0,0 +1,543 @@
+use anyhow::{Context, Result};
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use super::{Entry, Key};
+use crate::lockfile::LockFile;
+
+/// A single config file, parsed for reading and kept as written so edits only
+/// touch the lines they change
+#[derive(Debug, Clone)]
+pub(crate) struct ConfigFile {
+    path: PathBuf,
+    raw: String,
+    /// Each entry with the bytes of `raw` it was parsed from
+    entries: Vec<(Entry, Range<usize>)>,
+    sections: Vec<Section>,
+}
+
+/// A section header and where new entries for it would go
+#[derive(Debug, Clone)]
+struct Section {
+    section: String,
+    subsection: Option<String>,
+    /// Just past the line of the section's last entry, or of its header
+    end: usize,
+}
+
+impl ConfigFile {
+    /// Loads a config file, treating a missing file as empty
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let raw = match std::fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+
+        Self::parse(path, raw)
+    }
+
+    /// Locks a config file before loading it, so nothing else can change it
+    /// between reading it and writing the edited version back under the lock
+    pub(crate) fn lock(path: impl AsRef<Path>) -> Result<(Self, LockFile)> {
+        let lock = LockFile::acquire(&path)?;
+        Ok((Self::load(path)?, lock))
+    }
+
+    pub(crate) fn parse(path: PathBuf, raw: String) -> Result<Self> {
+        let mut parser = Parser {
+            raw: raw.as_bytes(),
+            pos: 0,
+            line: 1,
+        };
+        let parsed = parser.parse(&path);
+        let (entries, sections) = parsed.with_context(|| {
+            format!("bad config line {} in file {}", parser.line, path.display())
+        })?;
+
+        Ok(Self {
+            path,
+            raw,
+            entries,
+            sections,
+        })
+    }
+
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().map(|(entry, _)| entry)
+    }
+
+    /// Sets a key, replacing its value if it's already set once and otherwise
+    /// adding it at the end of the last section it belongs in
+    pub(crate) fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let parsed = Key::parse(key)?;
+        let (section, name) = spelled(key);
+        let mut raw = self.raw.clone();
+
+        match self.matching(&parsed).as_slice() {
+            [] => {
+                let existing = self.sections.iter().rev().find(|section| {
+                    section.section == parsed.section && section.subsection == parsed.subsection
+                });
+                let (at, mut text) = match existing {
+                    Some(section) => (section.end, String::new()),
+                    None => (
+                        raw.len(),
+                        section_header(section, parsed.subsection.as_deref()),
+                    ),
+                };
+                text.push_str(&format!("\t{name} = {}\n", quote(value)));
+                if at > 0 && !raw[..at].ends_with('\n') {
+                    text.insert(0, '\n');
+                }
+                raw.insert_str(at, &text);
+            }
+            [span] => {
+                let line = if span.start == 0 || raw[..span.start].ends_with('\n') {
+                    format!("\t{name} = {}\n", quote(value))
+                } else {
+                    format!("{name} = {}", quote(value))
+                };
+                raw.replace_range(span.clone(), &line);
+            }
+            _ => anyhow::bail!("cannot overwrite multiple values of {key} with a single value"),
+        }
+
+        *self = Self::parse(self.path.clone(), raw)?;
+        Ok(())
+    }
+
+    /// Removes a key that's set exactly once in this file
+    pub(crate) fn unset(&mut self, key: &str) -> Result<()> {
+        let parsed = Key::parse(key)?;
+        let span = match self.matching(&parsed).as_slice() {
+            [] => anyhow::bail!("{key} is not set in {}", self.path.display()),
+            [span] => span.clone(),
+            _ => anyhow::bail!("{key} has multiple values"),
+        };
+
+        let mut raw = self.raw.clone();
+        raw.replace_range(span, "");
+        *self = Self::parse(self.path.clone(), raw)?;
+        Ok(())
+    }
+
+    /// Replaces the file with the edited contents, releasing the lock taken by `lock`
+    pub(crate) fn write(&self, lock: LockFile) -> Result<()> {
+        lock.commit(self.raw.as_bytes())
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    fn matching(&self, key: &Key) -> Vec<Range<usize>> {
+        self.entries
+            .iter()
+            .filter(|(entry, _)| entry.key == *key)
+            .map(|(_, span)| span.clone())
+            .collect()
+    }
+}
+
+/// The section and variable name of a key as the user wrote them, as git keeps
+/// their case when writing even though it ignores it when reading
+fn spelled(key: &str) -> (&str, &str) {
+    let section = key.split_once('.').map_or(key, |(section, _)| section);
+    let name = key.rsplit_once('.').map_or(key, |(_, name)| name);
+    (section, name)
+}
+
+fn section_header(section: &str, subsection: Option<&str>) -> String {
+    match subsection {
+        Some(subsection) => {
+            let escaped = subsection.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("[{section} \"{escaped}\"]\n")
+        }
+        None => format!("[{section}]\n"),
+    }
+}
+
+/// Formats a value so it parses back unchanged, quoting it if it has spaces at
+/// either end or would otherwise start a comment
+fn quote(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\u{8}' => escaped.push_str("\\b"),
+            c => escaped.push(c),
+        }
+    }
+
+    if value.starts_with(' ') || value.ends_with(' ') || value.contains(['#', ';']) {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+struct Parser<'a> {
+    raw: &'a [u8],
+    pos: usize,
+    /// The current line, for error messages
+    line: usize,
+}
+
+type Parsed = (Vec<(Entry, Range<usize>)>, Vec<Section>);
+
+impl Parser<'_> {
+    fn parse(&mut self, origin: &Path) -> Result<Parsed> {
+        let mut entries = Vec::new();
+        let mut sections: Vec<Section> = Vec::new();
+
+        if self.raw.starts_with(b"\xef\xbb\xbf") {
+            self.pos = 3;
+        }
+        let mut line_start = self.pos;
+
+        while let Some(c) = self.peek() {
+            match c {
+                b'\n' => {
+                    self.pos += 1;
+                    self.line += 1;
+                    line_start = self.pos;
+                }
+                b' ' | b'\t' | b'\r' => self.pos += 1,
+                b'#' | b';' => self.skip_comment(),
+                b'[' => {
+                    let (section, subsection) = self.section_header()?;
+                    sections.push(Section {
+                        section,
+                        subsection,
+                        end: self.line_end(),
+                    });
+                }
+                c if c.is_ascii_alphabetic() => {
+                    let Some(current) = sections.last_mut() else {
+                        anyhow::bail!("variable outside of any section");
+                    };
+
+                    // Entries on a line of their own take the whole line with them
+                    // when they're removed
+                    let whole_line = self.raw[line_start..self.pos]
+                        .iter()
+                        .all(u8::is_ascii_whitespace);
+                    let start = if whole_line { line_start } else { self.pos };
+                    let (name, value) = self.variable()?;
+                    let end = if whole_line {
+                        self.line_end()
+                    } else {
+                        self.pos
+                    };
+                    current.end = self.line_end();
+
+                    let key = Key {
+                        section: current.section.clone(),
+                        subsection: current.subsection.clone(),
+                        name,
+                    };
+                    let entry = Entry {
+                        key,
+                        value,
+                        origin: origin.to_path_buf(),
+                    };
+                    entries.push((entry, start..end));
+                }
+                c => anyhow::bail!("unexpected character '{}'", c as char),
+            }
+        }
+
+        Ok((entries, sections))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.raw.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn take_while(&mut self, pred: impl Fn(u8) -> bool) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(&pred) {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.raw[start..self.pos]).into_owned()
+    }
+
+    fn skip_blanks(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skips to the end of the line, leaving the newline itself
+    fn skip_comment(&mut self) {
+        while self.peek().is_some_and(|c| c != b'\n') {
+            self.pos += 1;
+        }
+    }
+
+    /// Just past the newline ending the current line
+    fn line_end(&self) -> usize {
+        self.raw[self.pos..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map_or(self.raw.len(), |newline| self.pos + newline + 1)
+    }
+
+    /// `[section]`, `[section "subsection"]` or the deprecated `[section.subsection]`
+    fn section_header(&mut self) -> Result<(String, Option<String>)> {
+        self.pos += 1;
+        let name = self
+            .take_while(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'.')
+            .to_ascii_lowercase();
+        anyhow::ensure!(!name.is_empty(), "empty section name");
+
+        let mut subsection = None;
+        if matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.skip_blanks();
+            anyhow::ensure!(
+                self.next() == Some(b'"') && !name.contains('.'),
+                "invalid section header"
+            );
+
+            let mut raw = Vec::new();
+            loop {
+                match self.next() {
+                    Some(b'"') => break,
+                    Some(b'\\') => match self.next() {
+                        Some(b'\n') | None => anyhow::bail!("unterminated subsection name"),
+                        Some(c) => raw.push(c),
+                    },
+                    Some(b'\n') | None => anyhow::bail!("unterminated subsection name"),
+                    Some(c) => raw.push(c),
+                }
+            }
+            subsection = Some(String::from_utf8(raw).context("subsection is not valid UTF-8")?);
+        }
+        anyhow::ensure!(self.next() == Some(b']'), "expected ']' after section name");
+
+        if subsection.is_none() {
+            if let Some((section, subsection)) = name.split_once('.') {
+                return Ok((section.to_string(), Some(subsection.to_string())));
+            }
+        }
+        Ok((name, subsection))
+    }
+
+    /// `name = value`, or a lone `name` which has no value at all
+    fn variable(&mut self) -> Result<(String, Option<String>)> {
+        let name = self
+            .take_while(|c| c.is_ascii_alphanumeric() || c == b'-')
+            .to_ascii_lowercase();
+        self.skip_blanks();
+
+        match self.peek() {
+            None | Some(b'\n') => Ok((name, None)),
+            Some(b'#' | b';') => {
+                self.skip_comment();
+                Ok((name, None))
+            }
+            Some(b'=') => {
+                self.pos += 1;
+                Ok((name, Some(self.value()?)))
+            }
+            Some(c) => anyhow::bail!("unexpected '{}' after variable {name}", c as char),
+        }
+    }
+
+    /// A value up to the end of its line, following git's rules: quotes can be
+    /// opened and closed anywhere, unquoted whitespace characters become
+    /// spaces and are dropped at either end, and a trailing backslash continues
+    /// the value on the next line
+    fn value(&mut self) -> Result<String> {
+        let mut value = Vec::new();
+        let mut quoted = false;
+        let mut spaces = 0;
+
+        while let Some(c) = self.peek() {
+            if c == b'\n' {
+                break;
+            }
+            self.pos += 1;
+
+            if !quoted && (c == b'#' || c == b';') {
+                self.skip_comment();
+                break;
+            }
+            if !quoted && c.is_ascii_whitespace() {
+                spaces += 1;
+                continue;
+            }
+            if !value.is_empty() {
+                value.resize(value.len() + spaces, b' ');
+            }
+            spaces = 0;
+
+            match c {
+                b'\\' => match self.next() {
+                    Some(b'\n') => self.line += 1,
+                    Some(b'\r') if self.peek() == Some(b'\n') => {
+                        self.pos += 1;
+                        self.line += 1;
+                    }
+                    Some(b'n') => value.push(b'\n'),
+                    Some(b't') => value.push(b'\t'),
+                    Some(b'b') => value.push(0x08),
+                    Some(c @ (b'\\' | b'"')) => value.push(c),
+                    _ => anyhow::bail!("invalid escape sequence in value"),
+                },
+                b'"' => quoted = !quoted,
+                c => value.push(c),
+            }
+        }
+        anyhow::ensure!(!quoted, "unterminated quoted value");
+
+        String::from_utf8(value).context("value is not valid UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> ConfigFile {
+        ConfigFile::parse(PathBuf::from("config"), raw.to_string()).unwrap()
+    }
+
+    fn values(file: &ConfigFile) -> Vec<(String, Option<String>)> {
+        file.entries()
+            .map(|entry| (entry.key.to_string(), entry.value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_sections_values_and_comments() {
+        let file = parse(concat!(
+            "\u{feff}# leading comment\n",
+            "[Core]\n",
+            "\tBare = false ; trailing comment\n",
+            "\tsparse\n",
+            "[remote \"Origin.Dotted\"]\n",
+            "\turl = \"  spaced  \"  and   more \n",
+            "\tpath = a\\\\b\\t\"#not a comment\"\n",
+            "\tlong = first \\\n",
+            "  second\n",
+            "[branch.main] remote = origin\n",
+        ));
+
+        assert_eq!(
+            values(&file),
+            [
+                ("core.bare".into(), Some("false".into())),
+                ("core.sparse".into(), None),
+                (
+                    "remote.Origin.Dotted.url".into(),
+                    Some("  spaced    and   more".into())
+                ),
+                (
+                    "remote.Origin.Dotted.path".into(),
+                    Some("a\\b\t#not a comment".into())
+                ),
+                (
+                    "remote.Origin.Dotted.long".into(),
+                    Some("first   second".into())
+                ),
+                ("branch.main.remote".into(), Some("origin".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_bad_input() {
+        for (raw, line) in [
+            ("[core]\n\tbare = true\n\tbad\"name = 1\n", 3),
+            ("name = outside\n", 1),
+            ("[core\n", 1),
+            ("[remote \"unterminated]\n", 1),
+            ("[core]\n\tvalue = \"open\n", 2),
+            ("[core]\n\tvalue = bad\\escape\n", 2),
+            ("[]\n", 1),
+        ] {
+            let error = ConfigFile::parse(PathBuf::from("config"), raw.to_string()).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("bad config line {line} in file config"),
+                "{raw:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn set_only_touches_the_line_it_changes() {
+        let mut file = parse("# keep me\n[core]\n\tbare = false # and me\n\n[user]\n\tname = A\n");
+
+        file.set("core.bare", "true").unwrap();
+        assert_eq!(
+            file.raw,
+            "# keep me\n[core]\n\tbare = true\n\n[user]\n\tname = A\n"
+        );
+
+        file.set("core.editor", "vim -f").unwrap();
+        file.set("user.email", " padded ").unwrap();
+        assert_eq!(
+            file.raw,
+            concat!(
+                "# keep me\n[core]\n\tbare = true\n\teditor = vim -f\n\n",
+                "[user]\n\tname = A\n\temail = \" padded \"\n",
+            )
+        );
+        assert_eq!(
+            values(&file).last().unwrap(),
+            &("user.email".into(), Some(" padded ".into()))
+        );
+    }
+
+    #[test]
+    fn set_adds_missing_sections_keeping_the_key_as_written() {
+        let mut file = parse("[core]\n\tbare = false");
+
+        file.set("Remote.origin.URL", "https://example.com/a b#c")
+            .unwrap();
+        file.set("branch.we\"ird.remote", "origin").unwrap();
+        assert_eq!(
+            file.raw,
+            concat!(
+                "[core]\n\tbare = false\n",
+                "[Remote \"origin\"]\n\tURL = \"https://example.com/a b#c\"\n",
+                "[branch \"we\\\"ird\"]\n\tremote = origin\n",
+            )
+        );
+        assert_eq!(
+            file.entries().nth(2).unwrap().key.subsection.as_deref(),
+            Some("we\"ird")
+        );
+    }
+
+    #[test]
+    fn set_refuses_to_overwrite_multiple_values() {
+        let mut file = parse("[remote \"origin\"]\n\tfetch = a\n\tfetch = b\n");
+        assert!(file.set("remote.origin.fetch", "c").is_err());
+        assert!(file.unset("remote.origin.fetch").is_err());
+    }
+
+    #[test]
+    fn unset_removes_the_whole_line() {
+        let mut file = parse("[core]\n\tbare = false\n\teditor = vim\n[user] name = A\n");
+
+        file.unset("core.bare").unwrap();
+        assert_eq!(file.raw, "[core]\n\teditor = vim\n[user] name = A\n");
+
+        file.unset("user.name").unwrap();
+        assert_eq!(file.raw, "[core]\n\teditor = vim\n[user] \n");
+
+        assert!(file.unset("core.bare").is_err());
+    }
+}
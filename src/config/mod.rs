@@ -0,0 +1,480 @@
+mod file;
+
+use anyhow::{Context, Result};
+
+use std::path::{Path, PathBuf};
+
+pub(crate) use file::ConfigFile;
+
+use crate::repository::Repository;
+use crate::wildmatch::wildmatch;
+
+/// Includes are followed at most this deep, which also stops include cycles
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// A variable name split into its parts, with the section and name lowercased
+/// as only the subsection is case-sensitive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Key {
+    pub(crate) section: String,
+    pub(crate) subsection: Option<String>,
+    pub(crate) name: String,
+}
+
+impl Key {
+    /// Parses `section.name` or `section.subsection.name`, where the subsection
+    /// may itself contain dots
+    pub(crate) fn parse(key: &str) -> Result<Self> {
+        let Some((section, rest)) = key.split_once('.') else {
+            anyhow::bail!("key does not contain a section: {key}");
+        };
+        let (subsection, name) = match rest.rsplit_once('.') {
+            Some((subsection, name)) => (Some(subsection.to_string()), name),
+            None => (None, rest),
+        };
+
+        anyhow::ensure!(
+            !section.is_empty()
+                && section
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-'),
+            "invalid section name in key: {key}"
+        );
+        anyhow::ensure!(
+            name.starts_with(|c: char| c.is_ascii_alphabetic())
+                && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'),
+            "invalid key: {key}"
+        );
+
+        Ok(Self {
+            section: section.to_ascii_lowercase(),
+            subsection,
+            name: name.to_ascii_lowercase(),
+        })
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.subsection {
+            Some(subsection) => write!(f, "{}.{subsection}.{}", self.section, self.name),
+            None => write!(f, "{}.{}", self.section, self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub(crate) key: Key,
+    /// `None` for a variable with no `=`, which counts as true
+    pub(crate) value: Option<String>,
+    /// The file the entry was read from
+    pub(crate) origin: PathBuf,
+}
+
+/// Every config entry that applies, in the order they were read so later
+/// entries override earlier ones
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    pub(crate) entries: Vec<Entry>,
+}
+
+impl Config {
+    /// The system, global and repository config files layered in that order,
+    /// leaving out the repository's if there isn't one
+    pub(crate) fn load() -> Result<Self> {
+        Self::load_for(Repository::current().map(Repository::git_dir))
+    }
+
+    pub(crate) fn load_for(git_dir: Option<&Path>) -> Result<Self> {
+        let mut paths = Vec::new();
+        if !std::env::var("GIT_CONFIG_NOSYSTEM")
+            .is_ok_and(|value| parse_bool(Some(&value)) == Some(true))
+        {
+            paths.push(system_path());
+        }
+        paths.extend(global_paths());
+        paths.extend(git_dir.map(|git_dir| git_dir.join("config")));
+
+        let mut config = Self::default();
+        for path in paths {
+            config.read(&path, git_dir, 0)?;
+        }
+
+        Ok(config)
+    }
+
+    /// A single file on its own, without following its includes
+    pub(crate) fn load_file(path: impl AsRef<Path>) -> Result<Self> {
+        let file = ConfigFile::load(path)?;
+        Ok(Self {
+            entries: file.entries().cloned().collect(),
+        })
+    }
+
+    fn read(&mut self, path: &Path, git_dir: Option<&Path>, depth: usize) -> Result<()> {
+        anyhow::ensure!(
+            depth <= MAX_INCLUDE_DEPTH,
+            "exceeded maximum include depth ({MAX_INCLUDE_DEPTH}) while including {}",
+            path.display()
+        );
+        if !path.is_file() {
+            return Ok(());
+        }
+
+        let file = ConfigFile::load(path)?;
+        for entry in file.entries() {
+            self.entries.push(entry.clone());
+            if let Some(include) = included_path(entry, git_dir)? {
+                self.read(&include, git_dir, depth + 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every entry for a key, in the order they apply
+    pub(crate) fn get_entries<'a>(&'a self, key: &str) -> impl Iterator<Item = &'a Entry> {
+        let key = Key::parse(key).ok();
+        self.entries
+            .iter()
+            .filter(move |entry| key.as_ref() == Some(&entry.key))
+    }
+
+    /// The value that applies for a key, with a variable that has no value
+    /// giving an empty string
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.get_entries(key)
+            .last()
+            .map(|entry| entry.value.as_deref().unwrap_or_default())
+    }
+
+    pub(crate) fn get_all(&self, key: &str) -> Vec<&str> {
+        self.get_entries(key)
+            .map(|entry| entry.value.as_deref().unwrap_or_default())
+            .collect()
+    }
+
+    /// A path value, with a leading `~/` expanded to the home directory
+    pub(crate) fn get_path(&self, key: &str) -> Option<PathBuf> {
+        self.get(key)
+            .filter(|value| !value.is_empty())
+            .map(expand_home)
+    }
+
+    pub(crate) fn get_bool(&self, key: &str) -> Result<Option<bool>> {
+        let Some(entry) = self.get_entries(key).last() else {
+            return Ok(None);
+        };
+
+        parse_bool(entry.value.as_deref())
+            .map(Some)
+            .with_context(|| {
+                format!(
+                    "bad boolean config value '{}' for '{key}'",
+                    entry.value.as_deref().unwrap_or_default()
+                )
+            })
+    }
+}
+
+/// git's boolean values, where a variable with no value is true and any
+/// integer other than zero is too
+fn parse_bool(value: Option<&str>) -> Option<bool> {
+    let Some(value) = value else {
+        return Some(true);
+    };
+
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" => Some(true),
+        "false" | "no" | "off" | "" => Some(false),
+        number => number.parse::<i64>().ok().map(|number| number != 0),
+    }
+}
+
+/// `/etc/gitconfig`, unless `GIT_CONFIG_SYSTEM` points elsewhere
+pub(crate) fn system_path() -> PathBuf {
+    env_path("GIT_CONFIG_SYSTEM").unwrap_or_else(|| PathBuf::from("/etc/gitconfig"))
+}
+
+/// The per-user files read, `$XDG_CONFIG_HOME/git/config` then `~/.gitconfig`,
+/// unless `GIT_CONFIG_GLOBAL` names a single file instead
+fn global_paths() -> Vec<PathBuf> {
+    if let Some(path) = env_path("GIT_CONFIG_GLOBAL") {
+        return vec![path];
+    }
+
+    let mut paths = Vec::new();
+    paths.extend(xdg_path("config"));
+    paths.extend(home_dir().map(|home| home.join(".gitconfig")));
+    paths
+}
+
+/// The per-user file written to: `~/.gitconfig`, unless only the XDG file exists
+pub(crate) fn global_path() -> Result<PathBuf> {
+    let paths = global_paths();
+    let preferred = paths
+        .last()
+        .context("no home directory to find the global config in")?;
+    if preferred.exists() {
+        return Ok(preferred.clone());
+    }
+
+    let existing = paths.iter().find(|path| path.exists());
+    Ok(existing.unwrap_or(preferred).clone())
+}
+
+/// A file in git's per-user XDG directory, `$XDG_CONFIG_HOME/git` or
+/// `~/.config/git`
+pub(crate) fn xdg_path(name: &str) -> Option<PathBuf> {
+    let xdg = env_path("XDG_CONFIG_HOME").or_else(|| home_dir().map(|home| home.join(".config")));
+    xdg.map(|xdg| xdg.join("git").join(name))
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env_path("HOME")
+}
+
+fn env_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Expands a leading `~/` to the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// The file an `include.path` or a matching `includeIf.<condition>.path` entry
+/// pulls in, relative paths being relative to the file holding the entry
+fn included_path(entry: &Entry, git_dir: Option<&Path>) -> Result<Option<PathBuf>> {
+    let (Some(value), "path") = (entry.value.as_deref(), entry.key.name.as_str()) else {
+        return Ok(None);
+    };
+
+    let applies = match (entry.key.section.as_str(), &entry.key.subsection) {
+        ("include", None) => true,
+        ("includeif", Some(condition)) => condition_holds(condition, &entry.origin, git_dir)?,
+        _ => false,
+    };
+    if !applies {
+        return Ok(None);
+    }
+
+    let base = entry.origin.parent().unwrap_or(Path::new(""));
+    Ok(Some(base.join(expand_home(value))))
+}
+
+/// Evaluates the `gitdir:`, `gitdir/i:` and `onbranch:` conditions of
+/// `includeIf`, ignoring any others as git does
+fn condition_holds(condition: &str, origin: &Path, git_dir: Option<&Path>) -> Result<bool> {
+    let Some(git_dir) = git_dir else {
+        return Ok(false);
+    };
+
+    if let Some(pattern) = condition.strip_prefix("gitdir:") {
+        return Ok(gitdir_matches(pattern, origin, git_dir, false));
+    }
+    if let Some(pattern) = condition.strip_prefix("gitdir/i:") {
+        return Ok(gitdir_matches(pattern, origin, git_dir, true));
+    }
+    if let Some(pattern) = condition.strip_prefix("onbranch:") {
+        let head = std::fs::read_to_string(git_dir.join("HEAD")).context("reading HEAD")?;
+        let Some(branch) = head.trim_end().strip_prefix("ref: refs/heads/") else {
+            return Ok(false);
+        };
+
+        let mut pattern = pattern.to_string();
+        if pattern.ends_with('/') {
+            pattern.push_str("**");
+        }
+        return Ok(wildmatch(pattern.as_bytes(), branch.as_bytes()));
+    }
+
+    Ok(false)
+}
+
+/// Matches the git directory against a `gitdir:` pattern, which is relative to
+/// the including file if it starts with `./`, matches at any depth unless it's
+/// absolute, and matches everything below a directory if it ends with `/`
+fn gitdir_matches(pattern: &str, origin: &Path, git_dir: &Path, ignore_case: bool) -> bool {
+    let pattern = match pattern.strip_prefix("./") {
+        Some(rest) => origin.parent().unwrap_or(Path::new("")).join(rest),
+        None => expand_home(pattern),
+    };
+
+    let mut pattern = pattern.to_string_lossy().into_owned();
+    if !pattern.starts_with('/') {
+        pattern.insert_str(0, "**/");
+    }
+    if pattern.ends_with('/') {
+        pattern.push_str("**");
+    }
+
+    let mut git_dir = git_dir.to_string_lossy().into_owned();
+    if ignore_case {
+        pattern.make_ascii_lowercase();
+        git_dir.make_ascii_lowercase();
+    }
+
+    wildmatch(pattern.as_bytes(), git_dir.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of config files for the include tests, removed when dropped
+    struct Files(PathBuf);
+
+    impl Files {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "git-starter-rust-config-{name}-{}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            for (path, contents) in files {
+                let path = dir.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, contents).unwrap();
+            }
+            Self(dir)
+        }
+
+        fn read(&self, path: &str, git_dir: Option<&Path>) -> Result<Config> {
+            let mut config = Config::default();
+            config.read(&self.0.join(path), git_dir, 0)?;
+            Ok(config)
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn keys_lowercase_all_but_the_subsection() {
+        let key = Key::parse("Remote.My.Origin.URL").unwrap();
+        assert_eq!(key.section, "remote");
+        assert_eq!(key.subsection.as_deref(), Some("My.Origin"));
+        assert_eq!(key.name, "url");
+        assert_eq!(key.to_string(), "remote.My.Origin.url");
+
+        assert_eq!(Key::parse("core.bare").unwrap().subsection, None);
+        for key in [
+            "core",
+            ".bare",
+            "core.",
+            "core.1bare",
+            "co re.bare",
+            "core.ba_re",
+        ] {
+            assert!(Key::parse(key).is_err(), "{key}");
+        }
+    }
+
+    #[test]
+    fn parses_booleans_like_git() {
+        for (value, expected) in [
+            (None, Some(true)),
+            (Some("Yes"), Some(true)),
+            (Some("on"), Some(true)),
+            (Some("2"), Some(true)),
+            (Some(""), Some(false)),
+            (Some("OFF"), Some(false)),
+            (Some("0"), Some(false)),
+            (Some("maybe"), None),
+        ] {
+            assert_eq!(parse_bool(value), expected, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn later_entries_win() {
+        let files = Files::new(
+            "layering",
+            &[("config", "[core]\n\tbare = true\n\tbare = false\n\tflag\n")],
+        );
+        let config = files.read("config", None).unwrap();
+
+        assert_eq!(config.get("CORE.Bare"), Some("false"));
+        assert_eq!(config.get_all("core.bare"), ["true", "false"]);
+        assert_eq!(config.get_bool("core.flag").unwrap(), Some(true));
+        assert_eq!(config.get("core.missing"), None);
+    }
+
+    #[test]
+    fn follows_includes_relative_to_the_including_file() {
+        let files = Files::new(
+            "include",
+            &[
+                (
+                    "config",
+                    "[user]\n\tname = before\n[include]\n\tpath = sub/extra\n[core]\n\tbare = true\n",
+                ),
+                ("sub/extra", "[user]\n\tname = included\n[core]\n\tbare = false\n"),
+            ],
+        );
+        let config = files.read("config", None).unwrap();
+
+        assert_eq!(config.get("user.name"), Some("included"));
+        // Entries after the include still override it
+        assert_eq!(config.get("core.bare"), Some("true"));
+        assert_eq!(
+            config.get_entries("user.name").last().unwrap().origin,
+            files.0.join("sub/extra")
+        );
+    }
+
+    #[test]
+    fn include_cycles_hit_the_depth_limit() {
+        let files = Files::new("cycle", &[("config", "[include]\n\tpath = config\n")]);
+        let error = files.read("config", None).unwrap_err();
+        assert!(
+            error.to_string().contains("maximum include depth"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn conditional_includes_check_the_git_dir_and_branch() {
+        let files = Files::new(
+            "include-if",
+            &[
+                (
+                    "config",
+                    concat!(
+                        "[includeIf \"gitdir:projects/\"]\n\tpath = work.inc\n",
+                        "[includeIf \"gitdir/i:**/OTHER/.git\"]\n\tpath = other.inc\n",
+                        "[includeIf \"onbranch:topic/\"]\n\tpath = topic.inc\n",
+                    ),
+                ),
+                ("work.inc", "[user]\n\temail = work@example.com\n"),
+                ("other.inc", "[user]\n\temail = other@example.com\n"),
+                ("topic.inc", "[user]\n\tname = topic\n"),
+                ("projects/repo/.git/HEAD", "ref: refs/heads/topic/parser\n"),
+                ("other/.git/HEAD", "ref: refs/heads/main\n"),
+            ],
+        );
+
+        let config = files.read("config", None).unwrap();
+        assert_eq!(config.get("user.email"), None);
+
+        let work = files.0.join("projects/repo/.git");
+        let config = files.read("config", Some(&work)).unwrap();
+        assert_eq!(config.get("user.email"), Some("work@example.com"));
+        assert_eq!(config.get("user.name"), Some("topic"));
+
+        let other = files.0.join("other/.git");
+        let config = files.read("config", Some(&other)).unwrap();
+        assert_eq!(config.get("user.email"), Some("other@example.com"));
+        assert_eq!(config.get("user.name"), None);
+    }
+}
@@ -0,0 +1,246 @@
+use anyhow::{Context, Result};
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::config::{xdg_path, Config};
+use crate::repository::git_path;
+use crate::wildmatch::wildmatch;
+
+/// A single line of an ignore file
+#[derive(Debug, Clone)]
+struct Pattern {
+    pattern: String,
+    /// `!pattern`, which re-includes what an earlier pattern excluded
+    negated: bool,
+    /// `pattern/`, which only matches directories
+    dir_only: bool,
+    /// A pattern with a `/` before its end is matched against the path relative
+    /// to `base`, and any other against just the last component
+    anchored: bool,
+    /// The directory of the `.gitignore` the pattern came from, empty or ending in `/`
+    base: String,
+}
+
+impl Pattern {
+    fn parse(line: &str, base: &str) -> Option<Self> {
+        let mut line = trim_trailing_spaces(line);
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let negated = line.starts_with('!');
+        if negated {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            pattern: line.to_string(),
+            negated,
+            dir_only,
+            anchored,
+            base: base.to_string(),
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let Some(relative) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+        let text = if self.anchored {
+            relative
+        } else {
+            relative.rsplit('/').next().unwrap_or(relative)
+        };
+
+        wildmatch(self.pattern.as_bytes(), text.as_bytes())
+    }
+}
+
+/// Trailing spaces are dropped unless escaped with a backslash
+fn trim_trailing_spaces(line: &str) -> &str {
+    let trimmed = line.trim_end_matches(' ');
+    if trimmed.ends_with('\\') && trimmed.len() < line.len() {
+        &line[..trimmed.len() + 1]
+    } else {
+        trimmed
+    }
+}
+
+/// The patterns deciding which untracked files are left out of `status` and
+/// `add`: each directory's `.gitignore`, then `info/exclude`, then the
+/// `core.excludesFile`, the first of those with a matching pattern deciding
+#[derive(Debug, Default)]
+pub(crate) struct Ignore {
+    /// `core.excludesFile` followed by `info/exclude`, so later patterns win
+    global: Vec<Pattern>,
+    /// `.gitignore` patterns keyed by the directory holding them, read as needed
+    per_directory: HashMap<String, Vec<Pattern>>,
+}
+
+impl Ignore {
+    pub(crate) fn load() -> Result<Self> {
+        let config = Config::load().context("loading config")?;
+        let excludes_file = config
+            .get_path("core.excludesFile")
+            .or_else(|| xdg_path("ignore"));
+
+        let mut global = Vec::new();
+        for path in excludes_file.into_iter().chain([git_path("info/exclude")]) {
+            global.extend(read_patterns(&path, "")?);
+        }
+
+        Ok(Self {
+            global,
+            per_directory: HashMap::new(),
+        })
+    }
+
+    /// Whether `path` is ignored, assuming the directories above it are not
+    pub(crate) fn matches(&mut self, path: &str, is_dir: bool) -> Result<bool> {
+        let mut directories = vec![""];
+        directories.extend(path.match_indices('/').map(|(idx, _)| &path[..=idx]));
+
+        // The closest `.gitignore` takes precedence, and within a file the last match
+        for directory in directories.into_iter().rev() {
+            if !self.per_directory.contains_key(directory) {
+                let patterns = read_patterns(&Path::new(directory).join(".gitignore"), directory)?;
+                self.per_directory.insert(directory.to_string(), patterns);
+            }
+
+            let patterns = &self.per_directory[directory];
+            if let Some(pattern) = patterns.iter().rev().find(|p| p.matches(path, is_dir)) {
+                return Ok(!pattern.negated);
+            }
+        }
+
+        let pattern = self.global.iter().rev().find(|p| p.matches(path, is_dir));
+        Ok(pattern.is_some_and(|pattern| !pattern.negated))
+    }
+
+    /// Whether `path` or any directory above it is ignored
+    pub(crate) fn is_ignored(&mut self, path: &str, is_dir: bool) -> Result<bool> {
+        for (idx, _) in path.match_indices('/') {
+            if self.matches(&path[..idx], true)? {
+                return Ok(true);
+            }
+        }
+
+        self.matches(path, is_dir)
+    }
+}
+
+fn read_patterns(path: &Path, base: &str) -> Result<Vec<Pattern>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotADirectory => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+
+    Ok(String::from_utf8_lossy(&contents)
+        .lines()
+        .filter_map(|line| Pattern::parse(line, base))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(lines: &[&str], base: &str) -> Vec<Pattern> {
+        lines
+            .iter()
+            .filter_map(|line| Pattern::parse(line, base))
+            .collect()
+    }
+
+    /// An `Ignore` with the given `.gitignore` contents, so nothing is read from disk
+    fn ignore(global: &[&str], per_directory: &[(&str, &[&str])]) -> Ignore {
+        let mut ignore = Ignore {
+            global: patterns(global, ""),
+            per_directory: HashMap::new(),
+        };
+        for (directory, lines) in per_directory {
+            ignore
+                .per_directory
+                .insert(directory.to_string(), patterns(lines, directory));
+        }
+        ignore
+    }
+
+    #[test]
+    fn parses_negation_directories_and_anchoring() {
+        let pattern = Pattern::parse("!/build/ ", "sub/").unwrap();
+        assert!(pattern.negated && pattern.dir_only && pattern.anchored);
+        assert_eq!(pattern.pattern, "build");
+
+        let pattern = Pattern::parse("*.o", "").unwrap();
+        assert!(!pattern.negated && !pattern.dir_only && !pattern.anchored);
+
+        assert_eq!(
+            Pattern::parse("trailing\\ ", "").unwrap().pattern,
+            "trailing\\ "
+        );
+        for line in ["", "   ", "# comment", "/", "!"] {
+            assert!(Pattern::parse(line, "").is_none(), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn unanchored_patterns_match_the_last_component() {
+        let mut ignore = ignore(&[], &[("", &["*.o", "/root.txt", "docs/*.html"])]);
+
+        assert!(ignore.matches("a/b/main.o", false).unwrap());
+        assert!(ignore.matches("root.txt", false).unwrap());
+        assert!(!ignore.matches("sub/root.txt", false).unwrap());
+        assert!(ignore.matches("docs/index.html", false).unwrap());
+        assert!(!ignore.matches("sub/docs/index.html", false).unwrap());
+    }
+
+    #[test]
+    fn directory_patterns_only_match_directories() {
+        let mut ignore = ignore(&[], &[("", &["build/"])]);
+
+        assert!(ignore.matches("build", true).unwrap());
+        assert!(!ignore.matches("build", false).unwrap());
+        assert!(ignore.is_ignored("build/out/app", false).unwrap());
+    }
+
+    #[test]
+    fn closest_file_and_last_line_win() {
+        let mut ignore = ignore(
+            &["*.log"],
+            &[
+                ("", &["*.txt", "!keep.txt"]),
+                ("sub/", &["!*.txt", "/only-here"]),
+            ],
+        );
+
+        assert!(ignore.matches("notes.txt", false).unwrap());
+        assert!(!ignore.matches("keep.txt", false).unwrap());
+        assert!(!ignore.matches("sub/notes.txt", false).unwrap());
+        assert!(ignore.matches("sub/only-here", false).unwrap());
+        assert!(!ignore.matches("sub/deeper/only-here", false).unwrap());
+        assert!(ignore.matches("sub/debug.log", false).unwrap());
+    }
+
+    #[test]
+    fn negation_cannot_reinclude_inside_an_ignored_directory() {
+        let mut ignore = ignore(&[], &[("", &["vendor/", "!vendor/keep.rs"])]);
+
+        assert!(!ignore.matches("vendor/keep.rs", false).unwrap());
+        assert!(ignore.is_ignored("vendor/keep.rs", false).unwrap());
+    }
+}
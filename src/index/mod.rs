@@ -0,0 +1,628 @@
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, Bytes};
+use sha1::{Digest, Sha1};
+
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::lockfile::LockFile;
+use crate::object::{FileMode, Tree, TreeEntry};
+use crate::repository::git_path;
+
+mod ignore;
+mod pathspec;
+
+pub(crate) use ignore::Ignore;
+pub(crate) use pathspec::Pathspec;
+
+pub(crate) fn index_path() -> PathBuf {
+    git_path("index")
+}
+
+const INDEX_SIGNATURE: &[u8; 4] = b"DIRC";
+
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+
+const EXT_FLAG_SKIP_WORKTREE: u16 = 0x4000;
+const EXT_FLAG_INTENT_TO_ADD: u16 = 0x2000;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub(crate) ctime: u32,
+    pub(crate) ctime_nsec: u32,
+    pub(crate) mtime: u32,
+    pub(crate) mtime_nsec: u32,
+    pub(crate) dev: u32,
+    pub(crate) ino: u32,
+    pub(crate) mode: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) size: u32,
+    pub(crate) hash: String,
+    pub(crate) stage: u8,
+    pub(crate) assume_valid: bool,
+    pub(crate) skip_worktree: bool,
+    pub(crate) intent_to_add: bool,
+    pub(crate) path: String,
+}
+
+impl IndexEntry {
+    /// Builds a stage 0 entry from a file's stat data, normalising its mode
+    pub(crate) fn from_metadata(path: &str, metadata: &Metadata, hash: String) -> Self {
+        // Index stat fields are 32 bits wide, so larger values are truncated like git does
+        Self {
+            ctime: metadata.ctime() as u32,
+            ctime_nsec: metadata.ctime_nsec() as u32,
+            mtime: metadata.mtime() as u32,
+            mtime_nsec: metadata.mtime_nsec() as u32,
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            mode: FileMode::from_mode(metadata.mode()).as_u32(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+            hash,
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the file's stat data still matches what was recorded when it was staged
+    pub(crate) fn stat_matches(&self, metadata: &Metadata) -> bool {
+        let current = Self::from_metadata(&self.path, metadata, self.hash.clone());
+        current.mtime == self.mtime
+            && current.mtime_nsec == self.mtime_nsec
+            && current.ctime == self.ctime
+            && current.ctime_nsec == self.ctime_nsec
+            && current.size == self.size
+            && current.ino == self.ino
+            && current.mode == self.mode
+    }
+
+    pub(crate) fn file_mode(&self) -> FileMode {
+        FileMode::from_mode(self.mode)
+    }
+
+    fn needs_extended_flags(&self) -> bool {
+        self.skip_worktree || self.intent_to_add
+    }
+}
+
+/// The staging area stored in `.git/index`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Index {
+    pub(crate) version: u32,
+    /// Sorted by path, then stage
+    pub(crate) entries: Vec<IndexEntry>,
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self {
+            version: 2,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl Index {
+    /// Loads the index, treating a missing file as an empty staging area
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self> {
+        if !path.as_ref().exists() {
+            return Ok(Self::default());
+        }
+
+        let raw = std::fs::read(&path)
+            .with_context(|| format!("reading index {}", path.as_ref().display()))?;
+        Self::parse(Bytes::from(raw))
+    }
+
+    pub(crate) fn parse(mut raw: Bytes) -> Result<Self> {
+        anyhow::ensure!(raw.len() >= 12 + 20, "index file is truncated");
+        let checksum = raw.split_off(raw.len() - 20);
+        let mut hasher = Sha1::new();
+        hasher.update(&raw);
+        anyhow::ensure!(
+            hasher.finalize()[..] == checksum[..],
+            "index checksum mismatch"
+        );
+
+        let total = raw.len();
+        anyhow::ensure!(
+            &raw.split_to(4)[..] == INDEX_SIGNATURE,
+            "not a valid index signature"
+        );
+        let version = raw.get_u32();
+        anyhow::ensure!(
+            (2..=4).contains(&version),
+            "unsupported index version {version}"
+        );
+
+        let count = raw.get_u32();
+        // Each entry is at least 62 bytes, which bounds a bogus entry count
+        let mut entries = Vec::with_capacity((count as usize).min(raw.remaining() / 62));
+        let mut previous_path: Vec<u8> = Vec::new();
+
+        for _ in 0..count {
+            let start = total - raw.remaining();
+            anyhow::ensure!(raw.remaining() >= 62, "index entry is truncated");
+
+            let mut entry = IndexEntry {
+                ctime: raw.get_u32(),
+                ctime_nsec: raw.get_u32(),
+                mtime: raw.get_u32(),
+                mtime_nsec: raw.get_u32(),
+                dev: raw.get_u32(),
+                ino: raw.get_u32(),
+                mode: raw.get_u32(),
+                uid: raw.get_u32(),
+                gid: raw.get_u32(),
+                size: raw.get_u32(),
+                hash: hex::encode(raw.split_to(20)),
+                ..Default::default()
+            };
+
+            let flags = raw.get_u16();
+            entry.assume_valid = flags & FLAG_ASSUME_VALID != 0;
+            entry.stage = ((flags & FLAG_STAGE_MASK) >> 12) as u8;
+
+            if flags & FLAG_EXTENDED != 0 {
+                anyhow::ensure!(version >= 3, "extended index entry in version {version}");
+                anyhow::ensure!(raw.remaining() >= 2, "index entry is truncated");
+                let extended = raw.get_u16();
+                entry.skip_worktree = extended & EXT_FLAG_SKIP_WORKTREE != 0;
+                entry.intent_to_add = extended & EXT_FLAG_INTENT_TO_ADD != 0;
+            }
+
+            let path = if version == 4 {
+                let strip = read_offset(&mut raw)? as usize;
+                anyhow::ensure!(
+                    strip <= previous_path.len(),
+                    "index path prefix is out of range"
+                );
+                let mut path = previous_path[..previous_path.len() - strip].to_vec();
+                path.extend(read_until_nul(&mut raw)?);
+                path
+            } else {
+                let path = read_until_nul(&mut raw)?;
+                // Entries are NUL padded to a multiple of eight bytes, with at least one NUL
+                let length = total - raw.remaining() - start;
+                let padding = (8 - length % 8) % 8;
+                anyhow::ensure!(raw.remaining() >= padding, "index entry is truncated");
+                raw.advance(padding);
+                path
+            };
+
+            let name_length = (flags & FLAG_NAME_MASK) as usize;
+            anyhow::ensure!(
+                name_length == path.len().min(FLAG_NAME_MASK as usize),
+                "index entry name length mismatch"
+            );
+
+            entry.path = String::from_utf8(path.clone()).context("index path is not utf-8")?;
+            previous_path = path;
+            entries.push(entry);
+        }
+
+        // Extensions are optional caches (or ones we can't honour) so only check
+        // that none of them are required for correctness
+        while raw.remaining() >= 8 {
+            let signature = raw.split_to(4);
+            let size = raw.get_u32() as usize;
+            anyhow::ensure!(
+                !signature[0].is_ascii_lowercase(),
+                "unsupported required index extension {}",
+                String::from_utf8_lossy(&signature)
+            );
+            anyhow::ensure!(raw.remaining() >= size, "index extension is truncated");
+            raw.advance(size);
+        }
+        anyhow::ensure!(!raw.has_remaining(), "trailing data in index");
+
+        Ok(Self { version, entries })
+    }
+
+    /// Serialises the index. Optional extensions such as the cached tree are not
+    /// written, as they would go stale once entries change.
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
+        let version = if self.version == 2 && self.entries.iter().any(|e| e.needs_extended_flags())
+        {
+            3
+        } else {
+            self.version
+        };
+
+        let mut out = Vec::new();
+        out.put_slice(INDEX_SIGNATURE);
+        out.put_u32(version);
+        out.put_u32(self.entries.len() as u32);
+
+        let mut previous_path: &[u8] = &[];
+        for entry in &self.entries {
+            let start = out.len();
+            out.put_u32(entry.ctime);
+            out.put_u32(entry.ctime_nsec);
+            out.put_u32(entry.mtime);
+            out.put_u32(entry.mtime_nsec);
+            out.put_u32(entry.dev);
+            out.put_u32(entry.ino);
+            out.put_u32(entry.mode);
+            out.put_u32(entry.uid);
+            out.put_u32(entry.gid);
+            out.put_u32(entry.size);
+            out.put_slice(
+                &hex::decode(&entry.hash)
+                    .with_context(|| format!("decoding index entry hash {}", entry.hash))?,
+            );
+
+            let path = entry.path.as_bytes();
+            let mut flags = path.len().min(FLAG_NAME_MASK as usize) as u16;
+            flags |= ((entry.stage as u16) << 12) & FLAG_STAGE_MASK;
+            if entry.assume_valid {
+                flags |= FLAG_ASSUME_VALID;
+            }
+            if entry.needs_extended_flags() {
+                flags |= FLAG_EXTENDED;
+            }
+            out.put_u16(flags);
+
+            if entry.needs_extended_flags() {
+                let mut extended = 0;
+                if entry.skip_worktree {
+                    extended |= EXT_FLAG_SKIP_WORKTREE;
+                }
+                if entry.intent_to_add {
+                    extended |= EXT_FLAG_INTENT_TO_ADD;
+                }
+                out.put_u16(extended);
+            }
+
+            if version == 4 {
+                let common = previous_path
+                    .iter()
+                    .zip(path)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                write_offset(&mut out, (previous_path.len() - common) as u64);
+                out.put_slice(&path[common..]);
+                out.put_u8(0);
+            } else {
+                out.put_slice(path);
+                let length = out.len() - start;
+                let padding = 8 - length % 8;
+                out.put_bytes(0, padding);
+            }
+
+            previous_path = path;
+        }
+
+        let mut hasher = Sha1::new();
+        hasher.update(&out);
+        out.put_slice(&hasher.finalize());
+
+        Ok(out)
+    }
+
+    /// Writes the index through a `.lock` file so readers never see a partial index
+    pub(crate) fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let lock = LockFile::acquire(path)?;
+        lock.commit(&self.serialize()?).context("writing index")
+    }
+
+    pub(crate) fn entry(&self, path: &str) -> Option<&IndexEntry> {
+        self.position(path, 0).ok().map(|idx| &self.entries[idx])
+    }
+
+    /// Stages an entry, replacing any existing entry (or merge conflict) for its
+    /// path, and, as git's `add_index_entry` does, any file where it needs a
+    /// directory or anything staged under it when it used to be a directory
+    pub(crate) fn add(&mut self, entry: IndexEntry) {
+        let mut parent = entry.path.as_str();
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            self.remove(dir);
+            parent = dir;
+        }
+
+        let prefix = format!("{}/", entry.path);
+        let start = self
+            .entries
+            .partition_point(|e| e.path.as_bytes() < prefix.as_bytes());
+        let len = self.entries[start..]
+            .iter()
+            .take_while(|e| e.path.starts_with(&prefix))
+            .count();
+        self.entries.drain(start..start + len);
+
+        let range = self.path_range(&entry.path);
+        self.entries.splice(range, [entry]);
+    }
+
+    /// Removes every stage of the given path, returning whether anything was removed
+    pub(crate) fn remove(&mut self, path: &str) -> bool {
+        let range = self.path_range(path);
+        let removed = !range.is_empty();
+        self.entries.drain(range);
+        removed
+    }
+
+    /// Where the entries for every stage of a path are, or would be inserted
+    fn path_range(&self, path: &str) -> std::ops::Range<usize> {
+        let start = self
+            .entries
+            .partition_point(|entry| entry.path.as_bytes() < path.as_bytes());
+        let len = self.entries[start..]
+            .iter()
+            .take_while(|entry| entry.path == path)
+            .count();
+        start..start + len
+    }
+
+    fn position(&self, path: &str, stage: u8) -> std::result::Result<usize, usize> {
+        self.entries.binary_search_by(|entry| {
+            entry
+                .path
+                .as_bytes()
+                .cmp(path.as_bytes())
+                .then(entry.stage.cmp(&stage))
+        })
+    }
+
+    /// Writes the tree objects described by the staged entries, returning the root tree hash
+    pub(crate) fn write_tree(&self) -> Result<String> {
+        if let Some(conflict) = self.entries.iter().find(|entry| entry.stage != 0) {
+            anyhow::bail!("{} has unmerged changes", conflict.path);
+        }
+
+        let entries: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|entry| !entry.intent_to_add)
+            .collect();
+        write_subtree(&entries, "")
+    }
+}
+
+fn write_subtree(entries: &[&IndexEntry], prefix: &str) -> Result<String> {
+    let mut tree = Tree::default();
+    let mut idx = 0;
+
+    while idx < entries.len() {
+        let relative = &entries[idx].path[prefix.len()..];
+        match relative.split_once('/') {
+            Some((dir, _)) => {
+                let dir_prefix = format!("{prefix}{dir}/");
+                let end = entries[idx..]
+                    .iter()
+                    .position(|entry| !entry.path.starts_with(&dir_prefix))
+                    .map_or(entries.len(), |len| idx + len);
+
+                let hash = write_subtree(&entries[idx..end], &dir_prefix)?;
+                tree.entries.push(TreeEntry {
+                    mode: FileMode::Directory,
+                    name: dir.as_bytes().to_vec(),
+                    hash,
+                });
+                idx = end;
+            }
+            None => {
+                tree.entries.push(TreeEntry {
+                    mode: entries[idx].file_mode(),
+                    name: relative.as_bytes().to_vec(),
+                    hash: entries[idx].hash.clone(),
+                });
+                idx += 1;
+            }
+        }
+    }
+
+    tree.sort();
+    let obj = tree.to_object().context("creating tree from index")?;
+    obj.write()
+        .with_context(|| format!("writing tree {}", obj.hash))?;
+
+    Ok(obj.hash)
+}
+
+fn read_until_nul(raw: &mut Bytes) -> Result<Vec<u8>> {
+    let Some(end) = raw.iter().position(|&b| b == 0) else {
+        anyhow::bail!("unterminated index path");
+    };
+    let path = raw.split_to(end).to_vec();
+    raw.advance(1);
+
+    Ok(path)
+}
+
+/// Variable length integer as used by index v4 (and offset deltas)
+fn read_offset(raw: &mut Bytes) -> Result<u64> {
+    anyhow::ensure!(raw.has_remaining(), "index path prefix is truncated");
+    let mut byte = raw.get_u8();
+    let mut value = (byte & 0b0111_1111) as u64;
+    while (byte >> 7) & 1 == 1 {
+        anyhow::ensure!(raw.has_remaining(), "index path prefix is truncated");
+        byte = raw.get_u8();
+        value = ((value + 1) << 7) | (byte & 0b0111_1111) as u64;
+    }
+
+    Ok(value)
+}
+
+fn write_offset(out: &mut Vec<u8>, mut value: u64) {
+    let mut buf = vec![(value & 0b0111_1111) as u8];
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        buf.push(0b1000_0000 | (value & 0b0111_1111) as u8);
+        value >>= 7;
+    }
+
+    buf.reverse();
+    out.extend(buf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V2: &[u8] = include_bytes!("../../tests/fixtures/index-v2");
+    const V3: &[u8] = include_bytes!("../../tests/fixtures/index-v3");
+    const V4: &[u8] = include_bytes!("../../tests/fixtures/index-v4");
+
+    fn summary(index: &Index) -> Vec<(&str, &str, bool, bool)> {
+        index
+            .entries
+            .iter()
+            .map(|e| {
+                (
+                    e.path.as_str(),
+                    e.hash.as_str(),
+                    e.skip_worktree,
+                    e.intent_to_add,
+                )
+            })
+            .collect()
+    }
+
+    fn entry(path: &str) -> IndexEntry {
+        IndexEntry {
+            path: path.to_string(),
+            hash: "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn paths(index: &Index) -> Vec<&str> {
+        index.entries.iter().map(|e| e.path.as_str()).collect()
+    }
+
+    #[test]
+    fn reads_indexes_written_by_git() {
+        let v2 = Index::parse(Bytes::from_static(V2)).unwrap();
+        assert_eq!(v2.version, 2);
+        assert_eq!(
+            summary(&v2),
+            [
+                (
+                    "dir/nested.txt",
+                    "79c53955ef856f16f2107446bc721c8879a1bd2e",
+                    false,
+                    false
+                ),
+                (
+                    "text.txt",
+                    "e3fc58efb03c18f3a8bf1212b0a64e1565dfceb5",
+                    false,
+                    false
+                ),
+            ]
+        );
+        assert_eq!(v2.entries[1].size, 2319);
+        assert_eq!(v2.entries[1].mode, 0o100644);
+
+        let expected = [
+            (
+                "added.txt",
+                "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391",
+                false,
+                true,
+            ),
+            (
+                "dir/nested.txt",
+                "79c53955ef856f16f2107446bc721c8879a1bd2e",
+                true,
+                false,
+            ),
+            (
+                "text.txt",
+                "e3fc58efb03c18f3a8bf1212b0a64e1565dfceb5",
+                false,
+                false,
+            ),
+        ];
+        for (raw, version) in [(V3, 3), (V4, 4)] {
+            let index = Index::parse(Bytes::from_static(raw)).unwrap();
+            assert_eq!(index.version, version);
+            assert_eq!(summary(&index), expected);
+        }
+    }
+
+    #[test]
+    fn round_trips_every_version() {
+        for raw in [V2, V3, V4] {
+            let index = Index::parse(Bytes::from_static(raw)).unwrap();
+            let written = index.serialize().unwrap();
+            assert_eq!(Index::parse(Bytes::from(written)).unwrap(), index);
+        }
+    }
+
+    #[test]
+    fn upgrades_to_version_3_for_extended_flags() {
+        let mut index = Index::parse(Bytes::from_static(V2)).unwrap();
+        index.entries[0].skip_worktree = true;
+
+        let written = Index::parse(Bytes::from(index.serialize().unwrap())).unwrap();
+        assert_eq!(written.version, 3);
+        assert!(written.entries[0].skip_worktree);
+    }
+
+    #[test]
+    fn stages_entries_in_path_order() {
+        let mut index = Index::default();
+        for path in ["b", "a/c", "a.txt", "a/d", "b"] {
+            index.add(entry(path));
+        }
+        assert_eq!(paths(&index), ["a.txt", "a/c", "a/d", "b"]);
+
+        assert!(index.remove("a/c"));
+        assert!(!index.remove("a/c"));
+        assert!(index.entry("a/d").is_some());
+        assert!(index.entry("a/c").is_none());
+    }
+
+    #[test]
+    fn files_and_directories_replace_each_other() {
+        let mut index = Index::default();
+        for path in ["a/b/c", "a/b/d", "a/bc", "a.txt"] {
+            index.add(entry(path));
+        }
+
+        index.add(entry("a/b"));
+        assert_eq!(paths(&index), ["a.txt", "a/b", "a/bc"]);
+
+        index.add(entry("a/b/e/f"));
+        assert_eq!(paths(&index), ["a.txt", "a/b/e/f", "a/bc"]);
+
+        index.add(entry("a"));
+        assert_eq!(paths(&index), ["a", "a.txt"]);
+    }
+
+    #[test]
+    fn rejects_damaged_indexes_without_panicking() {
+        let reseal = |mut raw: Vec<u8>| {
+            raw.truncate(raw.len() - 20);
+            let checksum = Sha1::digest(&raw);
+            raw.extend_from_slice(&checksum);
+            Bytes::from(raw)
+        };
+
+        // An entry claiming extended flags that the file ends before
+        let mut raw = V3[..12 + 62].to_vec();
+        raw.extend_from_slice(&[0; 20]);
+        assert!(Index::parse(reseal(raw)).is_err());
+
+        // A version 4 prefix longer than the previous path
+        let mut raw = V4.to_vec();
+        raw[12 + 64] = 0x7f;
+        assert!(Index::parse(reseal(raw)).is_err());
+
+        let mut raw = V2.to_vec();
+        raw[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Index::parse(reseal(raw)).is_err());
+
+        assert!(Index::parse(Bytes::from_static(&V2[..V2.len() - 1])).is_err());
+    }
+}
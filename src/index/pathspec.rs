@@ -0,0 +1,114 @@
+use crate::wildmatch::match_class;
+
+/// A set of paths, directories or globs used to limit which files a command touches.
+/// An empty pathspec matches everything.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pathspec {
+    patterns: Vec<String>,
+}
+
+impl Pathspec {
+    pub(crate) fn new(patterns: &[String]) -> Self {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                let mut pattern = pattern.as_str();
+                while let Some(stripped) = pattern.strip_prefix("./") {
+                    pattern = stripped;
+                }
+                pattern.trim_end_matches('/').to_string()
+            })
+            .map(|pattern| {
+                if pattern == "." {
+                    String::new()
+                } else {
+                    pattern
+                }
+            })
+            .collect();
+
+        Self { patterns }
+    }
+
+    pub(crate) fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        self.patterns.is_empty() || self.patterns.iter().any(|p| pattern_matches(p, path))
+    }
+}
+
+/// Matches a single pattern either as a path prefix (`src` matches `src/main.rs`)
+/// or as a glob, where `*` may cross directory boundaries as it does in git
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    if pattern.is_empty() || pattern == path {
+        return true;
+    }
+
+    if path.starts_with(pattern) && path.as_bytes()[pattern.len()] == b'/' {
+        return true;
+    }
+
+    pattern.contains(['*', '?', '['])
+        && (glob_match(pattern.as_bytes(), path.as_bytes())
+            || path
+                .match_indices('/')
+                .any(|(idx, _)| glob_match(pattern.as_bytes(), &path.as_bytes()[..idx])))
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position to resume from when a `*` needs to swallow another character
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some(b'[') => {
+                if let Some((matched, len)) = match_class(&pattern[p..], text[t]) {
+                    if matched {
+                        p += len;
+                        t += 1;
+                        continue;
+                    }
+                } else if text[t] == b'[' {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&text[t]) => {
+                p += 2;
+                t += 1;
+                continue;
+            }
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        match backtrack {
+            Some((star, consumed)) => {
+                p = star + 1;
+                t = consumed + 1;
+                backtrack = Some((star, consumed + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Exclusive `<path>.lock` file that atomically replaces `path` when committed.
+/// The lock is released without touching `path` if dropped before then.
+#[derive(Debug)]
+pub(crate) struct LockFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: Option<File>,
+}
+
+impl LockFile {
+    pub(crate) fn acquire(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("creating {}", parent.display()))?;
+        }
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .with_context(|| {
+                format!(
+                    "unable to create {}: another process may be running",
+                    lock_path.display()
+                )
+            })?;
+
+        Ok(Self {
+            path,
+            lock_path,
+            file: Some(file),
+        })
+    }
+
+    /// Writes the new content and moves it into place. The lock is only given
+    /// up once the rename has succeeded, so a failure leaves no `.lock` behind.
+    pub(crate) fn commit(mut self, content: &[u8]) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.write_all(content)
+                .with_context(|| format!("writing {}", self.lock_path.display()))?;
+        }
+
+        std::fs::rename(&self.lock_path, &self.path)
+            .with_context(|| format!("replacing {}", self.path.display()))?;
+        self.file = None;
+
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = std::fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for each test, removed when dropped
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "git-starter-rust-lock-{name}-{}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn commit_replaces_the_file() {
+        let dir = Dir::new("commit");
+        let path = dir.0.join("nested/config");
+
+        let lock = LockFile::acquire(&path).unwrap();
+        assert!(dir.0.join("nested/config.lock").is_file());
+        lock.commit(b"new\n").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"new\n");
+        assert!(!dir.0.join("nested/config.lock").exists());
+    }
+
+    #[test]
+    fn only_one_holder_at_a_time() {
+        let dir = Dir::new("exclusive");
+        let path = dir.0.join("HEAD");
+
+        let lock = LockFile::acquire(&path).unwrap();
+        let error = LockFile::acquire(&path).unwrap_err();
+        assert!(error.to_string().contains("another process"), "{error}");
+
+        drop(lock);
+        assert!(!dir.0.join("HEAD.lock").exists());
+        assert!(!path.exists());
+        LockFile::acquire(&path).unwrap();
+    }
+
+    #[test]
+    fn a_failed_rename_releases_the_lock() {
+        let dir = Dir::new("failed-rename");
+        // A non-empty directory can't be replaced by a file
+        let path = dir.0.join("occupied");
+        std::fs::create_dir_all(path.join("inside")).unwrap();
+
+        let lock = LockFile::acquire(&path).unwrap();
+        assert!(lock.commit(b"new\n").is_err());
+
+        assert!(!dir.0.join("occupied.lock").exists());
+        assert!(path.join("inside").is_dir());
+    }
+}
@@ -1,13 +1,29 @@
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 
+use std::path::PathBuf;
+
 mod commands;
+mod config;
+mod index;
+mod lockfile;
 mod object;
 mod pack;
+mod protocol;
+mod refs;
+mod repository;
+mod wildmatch;
+
+use commands::committree::Cleanup;
+use repository::Repository;
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    /// Run as if started in this directory, relative to the previous one if repeated
+    #[arg(short = 'C', value_name = "PATH")]
+    directories: Vec<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -15,7 +31,11 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Initialises a Git repository
-    Init,
+    Init {
+        /// Create a repository without a work tree
+        #[arg(long)]
+        bare: bool,
+    },
 
     /// Parse a blob
     CatFile {
@@ -48,23 +68,230 @@ enum Commands {
     CommitTree {
         tree_hash: String,
 
-        #[arg(short)]
-        parent: Option<String>,
+        /// A parent commit, repeated for merges
+        #[arg(short = 'p', value_name = "PARENT")]
+        parents: Vec<String>,
 
-        #[arg(short)]
-        message: String,
+        /// A paragraph of the message, read from standard input if there's no -m or -F
+        #[arg(short = 'm', value_name = "MESSAGE")]
+        messages: Vec<String>,
+
+        /// Read a paragraph of the message from a file, or standard input for `-`
+        #[arg(short = 'F', value_name = "FILE")]
+        files: Vec<PathBuf>,
+
+        /// How to tidy up the message
+        #[arg(long, value_enum, default_value_t = Cleanup::Strip)]
+        cleanup: Cleanup,
+    },
+
+    /// Record the staged changes as a new commit on the current branch
+    Commit {
+        /// A paragraph of the message
+        #[arg(short = 'm', long = "message", value_name = "MESSAGE")]
+        messages: Vec<String>,
+
+        /// Read a paragraph of the message from a file, or standard input for `-`
+        #[arg(short = 'F', long = "file", value_name = "FILE")]
+        files: Vec<PathBuf>,
+
+        /// Replace the last commit instead of adding a new one
+        #[arg(long)]
+        amend: bool,
+
+        /// Allow a commit that doesn't change the tree
+        #[arg(long)]
+        allow_empty: bool,
+
+        /// Stage changes to tracked files before committing
+        #[arg(short, long)]
+        all: bool,
+
+        /// Credit this author instead of the current identity
+        #[arg(long, value_name = "NAME <EMAIL>")]
+        author: Option<String>,
+
+        /// How to tidy up the message
+        #[arg(long, value_enum, default_value_t = Cleanup::Strip)]
+        cleanup: Cleanup,
+    },
+
+    /// Add file contents to the index
+    Add {
+        /// Only stage changes to files that are already tracked
+        #[arg(short, long, conflicts_with = "all")]
+        update: bool,
+
+        /// Stage all changes, including new and removed files
+        #[arg(short = 'A', long)]
+        all: bool,
+
+        /// Show what would be staged without touching the index
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Record only that the paths will be added later
+        #[arg(short = 'N', long)]
+        intent_to_add: bool,
+
+        paths: Vec<String>,
+    },
+
+    /// Show the working tree status
+    Status {
+        /// Machine readable output in the given format version
+        #[arg(
+            long,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "v1",
+            value_parser = ["v1", "v2"]
+        )]
+        porcelain: Option<String>,
     },
 
     /// Clones a repository to the target destination
-    Clone { url: String, dst: Option<String> },
+    Clone {
+        /// Don't report progress
+        #[arg(short, long)]
+        quiet: bool,
+        url: String,
+        dst: Option<String>,
+    },
+
+    /// Download objects and refs from another repository
+    Fetch {
+        /// Don't report progress or updated refs
+        #[arg(short, long)]
+        quiet: bool,
+        /// A configured remote name or a url
+        #[arg(default_value = "origin")]
+        remote: String,
+        /// Which refs to fetch and where to store them, replacing the configured refspecs
+        refspecs: Vec<String>,
+    },
+
+    /// Update remote refs along with the objects they need
+    Push {
+        /// Allow updates that aren't fast-forwards
+        #[arg(short, long)]
+        force: bool,
+        /// Delete the named remote refs
+        #[arg(short, long)]
+        delete: bool,
+        /// Only force an update if the remote ref is still at the expected value,
+        /// by default our remote-tracking ref
+        #[arg(
+            long,
+            value_name = "REF[:EXPECT]",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = ""
+        )]
+        force_with_lease: Option<String>,
+        /// Don't report progress or updated refs
+        #[arg(short, long)]
+        quiet: bool,
+        /// A configured remote name or a url
+        #[arg(default_value = "origin")]
+        remote: String,
+        /// Which local refs to push and where to, by default the current branch
+        refspecs: Vec<String>,
+    },
+
+    /// Verify the integrity of objects and that everything reachable is present
+    Fsck {
+        /// Don't list unreachable objects that nothing points at
+        #[arg(long)]
+        no_dangling: bool,
+    },
+
+    /// Pack loose objects and refs and prune unreachable objects
+    Gc {
+        /// Prune unreachable objects older than this, e.g. `now`, `never` or `2.weeks.ago`
+        #[arg(long, value_name = "DATE", require_equals = true)]
+        prune: Option<String>,
+        /// Don't report what was packed or pruned
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
+    /// Pack reachable loose objects and remove loose copies of packed objects
+    Repack {
+        /// Pack everything reachable into one pack, replacing the existing packs
+        #[arg(short)]
+        all: bool,
+        /// Don't report what was packed
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
+    /// Get and set repository or global options
+    Config {
+        /// Use the system-wide config file
+        #[arg(long, group = "scope")]
+        system: bool,
+        /// Use the per-user config file
+        #[arg(long, group = "scope")]
+        global: bool,
+        /// Use the repository's config file
+        #[arg(long, group = "scope")]
+        local: bool,
+        /// Use the given config file
+        #[arg(short, long, group = "scope", value_name = "FILE")]
+        file: Option<PathBuf>,
+
+        /// Print the value that applies for the key
+        #[arg(long, group = "action")]
+        get: bool,
+        /// Print every value of a multivar
+        #[arg(long, group = "action")]
+        get_all: bool,
+        /// Set the key, the default when a value is given
+        #[arg(long, group = "action", requires = "value")]
+        set: bool,
+        /// Remove the key
+        #[arg(long, group = "action")]
+        unset: bool,
+        /// List every variable with its value
+        #[arg(short, long, group = "action", conflicts_with = "key")]
+        list: bool,
+        /// Show the file each value was read from
+        #[arg(long)]
+        show_origin: bool,
+
+        #[arg(required_unless_present = "list")]
+        key: Option<String>,
+        #[arg(conflicts_with_all = ["get", "get_all", "unset"])]
+        value: Option<String>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    for directory in &cli.directories {
+        std::env::set_current_dir(directory)
+            .with_context(|| format!("cannot change to '{}'", directory.display()))?;
+    }
+
+    match &cli.command {
+        Commands::Init { .. } | Commands::Clone { .. } => {}
+        // Hashing without storing the object, and config outside the
+        // repository, work without one
+        Commands::HashObject { write: false, .. } | Commands::Config { .. } => {
+            if let Ok(repository) = Repository::discover() {
+                repository.install()?;
+            }
+        }
+        _ => {
+            Repository::discover()?.install()?;
+        }
+    }
+
     match cli.command {
-        Commands::Init => commands::init::invoke().context("initialisation")?,
+        Commands::Init { bare } => commands::init::invoke(bare).context("initialisation")?,
         Commands::CatFile {
             pretty_print: _,
             hash,
@@ -82,16 +309,145 @@ async fn main() -> Result<()> {
 
         Commands::CommitTree {
             tree_hash,
-            parent,
-            message,
-        } => commands::committree::invoke(tree_hash, parent, message)
+            parents,
+            messages,
+            files,
+            cleanup,
+        } => commands::committree::invoke(tree_hash, parents, messages, files, cleanup)
             .context("commit tree invocation")?,
 
-        Commands::Clone { url, dst } => {
-            commands::clone::invoke(url, dst)
+        Commands::Commit {
+            messages,
+            files,
+            amend,
+            allow_empty,
+            all,
+            author,
+            cleanup,
+        } => {
+            let options = commands::commit::CommitOptions {
+                messages,
+                files,
+                amend,
+                allow_empty,
+                all,
+                author,
+                cleanup,
+            };
+            commands::commit::invoke(options).context("commit invocation")?
+        }
+
+        Commands::Add {
+            update,
+            all,
+            dry_run,
+            intent_to_add,
+            paths,
+        } => {
+            let options = commands::add::AddOptions {
+                update,
+                all,
+                dry_run,
+                intent_to_add,
+            };
+            commands::add::invoke(paths, options).context("add invocation")?
+        }
+
+        Commands::Status { porcelain } => {
+            let format = match porcelain.as_deref() {
+                Some("v2") => commands::status::StatusFormat::PorcelainV2,
+                Some(_) => commands::status::StatusFormat::PorcelainV1,
+                None => commands::status::StatusFormat::Long,
+            };
+            commands::status::invoke(format).context("status invocation")?
+        }
+
+        Commands::Clone { quiet, url, dst } => {
+            commands::clone::invoke(url, dst, quiet)
                 .await
                 .context("clone invocation")?;
         }
+
+        Commands::Fetch {
+            quiet,
+            remote,
+            refspecs,
+        } => {
+            commands::fetch::invoke(remote, refspecs, quiet)
+                .await
+                .context("fetch invocation")?;
+        }
+
+        Commands::Push {
+            force,
+            delete,
+            force_with_lease,
+            quiet,
+            remote,
+            refspecs,
+        } => {
+            let options = commands::push::PushOptions {
+                force,
+                delete,
+                force_with_lease,
+                quiet,
+            };
+            commands::push::invoke(remote, refspecs, options)
+                .await
+                .context("push invocation")?;
+        }
+
+        Commands::Fsck { no_dangling } => {
+            commands::fsck::invoke(no_dangling).context("fsck invocation")?
+        }
+
+        Commands::Gc { prune, quiet } => {
+            commands::gc::invoke(prune, quiet).context("gc invocation")?
+        }
+
+        Commands::Repack { all, quiet } => {
+            commands::repack::invoke(all, quiet).context("repack invocation")?
+        }
+
+        Commands::Config {
+            system,
+            global,
+            local,
+            file,
+            get,
+            get_all,
+            set,
+            unset,
+            list,
+            show_origin,
+            key,
+            value,
+        } => {
+            use commands::config::{Action, Scope};
+
+            let scope = match file {
+                Some(file) => Scope::File(file),
+                None if system => Scope::System,
+                None if global => Scope::Global,
+                None if local => Scope::Local,
+                None => Scope::All,
+            };
+            let action = if get {
+                Action::Get
+            } else if get_all {
+                Action::GetAll
+            } else if unset {
+                Action::Unset
+            } else if list {
+                Action::List
+            } else if set || value.is_some() {
+                Action::Set
+            } else {
+                Action::Get
+            };
+            commands::config::invoke(action, key, value, scope, show_origin)
+                .context("config invocation")?
+        }
     }
 
     Ok(())
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+
+use super::utils::{header_text, parse_headers, write_header, Headers};
+use super::{GitObject, GitObjectType, Signature};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Commit {
+    pub(crate) tree: String,
+    pub(crate) parents: Vec<String>,
+    pub(crate) author: Signature,
+    pub(crate) committer: Signature,
+    /// Any headers after the committer (`encoding`, `gpgsig`, `mergetag`...) in the
+    /// order they appeared
+    pub(crate) extra_headers: Headers,
+    /// In the encoding named by any `encoding` header, UTF-8 otherwise
+    pub(crate) message: Vec<u8>,
+}
+
+impl Commit {
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        let mut content = Vec::new();
+        write_header(&mut content, "tree", self.tree.as_bytes());
+        for parent in &self.parents {
+            write_header(&mut content, "parent", parent.as_bytes());
+        }
+        write_header(&mut content, "author", &self.author.to_bytes());
+        write_header(&mut content, "committer", &self.committer.to_bytes());
+        for (key, value) in &self.extra_headers {
+            write_header(&mut content, key, value);
+        }
+        content.push(b'\n');
+        content.extend_from_slice(&self.message);
+
+        content
+    }
+
+    pub(crate) fn to_object(&self) -> Result<GitObject> {
+        GitObject::create_raw(&self.serialize(), GitObjectType::Commit)
+    }
+}
+
+impl TryFrom<&GitObject> for Commit {
+    type Error = anyhow::Error;
+
+    fn try_from(obj: &GitObject) -> Result<Self> {
+        anyhow::ensure!(
+            obj.obj_type == GitObjectType::Commit,
+            "expected commit, found {} {}",
+            obj.obj_type,
+            obj.hash
+        );
+
+        let (headers, message) = parse_headers(&obj.content).context("parsing commit headers")?;
+        let mut headers = headers.into_iter().peekable();
+
+        let tree = match headers.next() {
+            Some((key, value)) if key == "tree" => header_text(&key, value)?,
+            _ => anyhow::bail!("commit {} is missing a tree", obj.hash),
+        };
+
+        let mut parents = Vec::new();
+        while let Some((_, parent)) = headers.next_if(|(key, _)| key == "parent") {
+            parents.push(header_text("parent", parent)?);
+        }
+
+        let author = match headers.next() {
+            Some((key, value)) if key == "author" => {
+                Signature::parse(&value).context("parsing commit author")?
+            }
+            _ => anyhow::bail!("commit {} is missing an author", obj.hash),
+        };
+
+        let committer = match headers.next() {
+            Some((key, value)) if key == "committer" => {
+                Signature::parse(&value).context("parsing commit committer")?
+            }
+            _ => anyhow::bail!("commit {} is missing a committer", obj.hash),
+        };
+
+        Ok(Self {
+            tree,
+            parents,
+            author,
+            committer,
+            extra_headers: headers.collect(),
+            message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMIT: &[u8] = include_bytes!("../../tests/fixtures/commit");
+
+    #[test]
+    fn round_trips_a_signed_latin1_commit() {
+        let obj = GitObject::create_raw(COMMIT, GitObjectType::Commit).unwrap();
+        assert_eq!(obj.hash, "cdbbd8e509fdf14b0b56cb5c0d3ca7d538296320");
+
+        let commit = Commit::try_from(&obj).unwrap();
+        assert_eq!(commit.tree, "6a853821d6d9d94df0c6a2b185193ebb4d5a7492");
+        assert_eq!(commit.parents, ["27a9c3fc83d3c25b5ebc4bc115409460aed56d4f"]);
+        assert_eq!(commit.author.name, b"Jos\xe9 Garc\xeda");
+        assert_eq!(commit.author.timezone.to_string(), "-0000");
+        assert_eq!(commit.committer.name, b" Spaced Name ");
+        assert_eq!(commit.committer.time, 1700000100);
+        assert_eq!(
+            commit
+                .extra_headers
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>(),
+            ["encoding", "gpgsig"]
+        );
+        assert_eq!(
+            commit.extra_headers[1].1,
+            b"-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n-----END PGP SIGNATURE-----"
+        );
+        assert_eq!(commit.message, b"Caf\xe9 message\n\nwith body\n");
+
+        assert_eq!(commit.serialize(), COMMIT);
+        assert_eq!(commit.to_object().unwrap().hash, obj.hash);
+    }
+
+    #[test]
+    fn rejects_commits_missing_required_headers() {
+        let tree = "tree 6a853821d6d9d94df0c6a2b185193ebb4d5a7492\n";
+        let author = "author A <a@example.com> 1700000000 +0000\n";
+        for content in [
+            String::new(),
+            format!("{author}{tree}\nmessage\n"),
+            format!("{tree}{author}\nmessage\n"),
+            format!("{tree}{author}committer C 1700000000 +0000\n\nmessage\n"),
+        ] {
+            let obj = GitObject::create_raw(content.as_bytes(), GitObjectType::Commit).unwrap();
+            assert!(Commit::try_from(&obj).is_err(), "{content:?}");
+        }
+
+        let blob = GitObject::create_raw(COMMIT, GitObjectType::Blob).unwrap();
+        assert!(Commit::try_from(&blob).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, Offset, TimeZone};
+
+use super::Timezone;
+
+/// Formats tried for ISO 8601 dates once any timezone has been taken off the end
+const ISO_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+];
+
+/// Dates with no time at all, which take the current time of day as `git commit
+/// --date` does
+const ISO_DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%Y.%m.%d"];
+
+/// The current time and local timezone
+pub(crate) fn now() -> (i64, Timezone) {
+    let time = chrono::Utc::now().timestamp();
+    (time, local_timezone(time))
+}
+
+/// The local timezone's offset at the given time, which varies with daylight saving
+pub(crate) fn local_timezone(time: i64) -> Timezone {
+    let offset = Local
+        .timestamp_opt(time, 0)
+        .single()
+        .map_or(0, |local| local.offset().fix().local_minus_utc());
+    Timezone::from_seconds(offset)
+}
+
+/// Parses a date as git accepts them in `GIT_AUTHOR_DATE` and friends: its own
+/// `<unix time> <tz>` format, optionally prefixed with `@`, a bare unix time,
+/// RFC 2822 or ISO 8601.
+/// Dates without a timezone are taken to be in local time.
+pub(crate) fn parse_date(raw: &str) -> Result<(i64, Timezone)> {
+    let raw = raw.trim();
+    if let Some(date) = parse_raw(raw) {
+        return Ok(date);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc2822(raw) {
+        let timezone = Timezone::from_seconds(date.offset().local_minus_utc());
+        return Ok((date.timestamp(), timezone));
+    }
+    if let Some(date) = parse_iso8601(raw) {
+        return Ok(date);
+    }
+
+    anyhow::bail!("invalid date format: {raw}")
+}
+
+/// `<unix time> <tz>`, or `@<unix time>` or a bare `<unix time>` where the
+/// timezone defaults to local
+fn parse_raw(raw: &str) -> Option<(i64, Timezone)> {
+    let (prefixed, rest) = match raw.strip_prefix('@') {
+        Some(rest) => (true, rest),
+        None => (false, raw),
+    };
+
+    match rest.split_once(' ') {
+        Some((time, timezone)) => {
+            Some((time.parse().ok()?, Timezone::parse(timezone.trim()).ok()?))
+        }
+        // Like git, only numbers of more than eight digits are taken for a bare
+        // timestamp, so that they can't be mistaken for a year or a date
+        None if prefixed || (rest.len() > 8 && rest.bytes().all(|b| b.is_ascii_digit())) => {
+            let time = rest.parse().ok()?;
+            Some((time, local_timezone(time)))
+        }
+        None => None,
+    }
+}
+
+fn parse_iso8601(raw: &str) -> Option<(i64, Timezone)> {
+    let (datetime, timezone) = split_timezone(raw);
+    let datetime = datetime.trim_end();
+
+    let naive = ISO_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(datetime, format).ok())
+        .or_else(|| {
+            let date = ISO_DATE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(datetime, format).ok())?;
+            Some(date.and_time(Local::now().time()))
+        })?;
+
+    match timezone {
+        Some(timezone) => {
+            let time = naive.and_utc().timestamp() - i64::from(timezone.seconds());
+            Some((time, timezone))
+        }
+        None => {
+            let local = Local.from_local_datetime(&naive).earliest()?;
+            let offset = local.offset().fix().local_minus_utc();
+            Some((local.timestamp(), Timezone::from_seconds(offset)))
+        }
+    }
+}
+
+/// Takes a trailing `Z`, `+hh:mm` or `+hhmm` off an ISO 8601 date
+fn split_timezone(raw: &str) -> (&str, Option<Timezone>) {
+    if let Some(rest) = raw.strip_suffix('Z') {
+        return (rest, Some(Timezone::default()));
+    }
+
+    let bytes = raw.as_bytes();
+    let is_sign = |i: usize| bytes.get(i).is_some_and(|b| matches!(b, b'+' | b'-'));
+
+    if raw.len() >= 6 && is_sign(raw.len() - 6) && bytes[raw.len() - 3] == b':' {
+        let (rest, offset) = raw.split_at(raw.len() - 6);
+        if let Ok(timezone) = Timezone::parse(&offset.replace(':', "")) {
+            return (rest, Some(timezone));
+        }
+    }
+    if raw.len() >= 5 && is_sign(raw.len() - 5) {
+        let (rest, offset) = raw.split_at(raw.len() - 5);
+        if rest.ends_with(|c: char| c.is_ascii_digit() || c == ' ') {
+            if let Ok(timezone) = Timezone::parse(offset) {
+                return (rest, Some(timezone));
+            }
+        }
+    }
+
+    (raw, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(raw: &str) -> (i64, String) {
+        let (time, timezone) = parse_date(raw).unwrap();
+        (time, timezone.to_string())
+    }
+
+    #[test]
+    fn parses_gits_own_format() {
+        assert_eq!(parsed("1700000000 +0100"), (1700000000, "+0100".into()));
+        assert_eq!(parsed("@1700000000 -0000"), (1700000000, "-0000".into()));
+        assert_eq!(parse_date("@1700000000").unwrap().0, 1700000000);
+
+        let (time, timezone) = parse_date("1700000000").unwrap();
+        assert_eq!(time, 1700000000);
+        assert_eq!(timezone, local_timezone(time));
+    }
+
+    #[test]
+    fn parses_rfc_2822() {
+        assert_eq!(
+            parsed("Tue, 14 Nov 2023 22:13:20 +0000"),
+            (1700000000, "+0000".into())
+        );
+        assert_eq!(
+            parsed("Wed, 15 Nov 2023 03:43:20 +0530"),
+            (1700000000, "+0530".into())
+        );
+    }
+
+    #[test]
+    fn parses_iso_8601_with_a_timezone() {
+        for raw in [
+            "2023-11-14T22:13:20Z",
+            "2023-11-14 22:13:20Z",
+            "2023-11-14T22:13:20.250Z",
+            "2023-11-14T23:13:20+01:00",
+            "2023-11-14 23:13:20 +0100",
+            "2023-11-14T17:13:20-0500",
+        ] {
+            assert_eq!(parse_date(raw).unwrap().0, 1700000000, "{raw}");
+        }
+        assert_eq!(
+            parsed("2023-11-14T23:13+01:00"),
+            (1699999980, "+0100".into())
+        );
+    }
+
+    #[test]
+    fn dates_without_a_timezone_are_local() {
+        let (time, timezone) = parse_date("2023-11-14 22:13:20").unwrap();
+        assert_eq!(timezone, local_timezone(time));
+        let local = DateTime::from_timestamp(time + i64::from(timezone.seconds()), 0).unwrap();
+        assert_eq!(local.naive_utc().to_string(), "2023-11-14 22:13:20");
+    }
+
+    #[test]
+    fn date_only_takes_the_current_time_of_day() {
+        for raw in ["2023-11-14", "2023.11.14", "2023-11-14 +0000"] {
+            let (time, timezone) = parse_date(raw).unwrap();
+            let local = DateTime::from_timestamp(time + i64::from(timezone.seconds()), 0).unwrap();
+            assert_eq!(local.date_naive().to_string(), "2023-11-14", "{raw}");
+        }
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        for raw in [
+            "",
+            "yesterday",
+            "20231114",
+            "1700000000x",
+            "1700000000 +01",
+            "@17x",
+            "2023-13-01",
+            "2023-11-14T25:00:00Z",
+            "2023-11-14 22:13:20 +01:00:00",
+            "2023-11-14 ü1234",
+        ] {
+            assert!(parse_date(raw).is_err(), "{raw}");
+        }
+    }
+}
@@ -1,15 +1,35 @@
+mod commit;
+mod date;
+mod signature;
+mod stream;
+mod tag;
+mod tree;
 mod utils;
+mod walk;
 
 use anyhow::{Context, Result};
-use flate2::read::ZlibDecoder;
-use sha1::{Digest, Sha1};
 
-use std::ffi::CStr;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::os::unix::fs::MetadataExt;
+use std::io::Read;
 use std::path::Path;
 
-use utils::{build_tree, compress, create_filepath, hash_content};
+pub(crate) use commit::Commit;
+pub(crate) use signature::{Role, Signature, Timezone};
+use stream::hash_stream;
+pub(crate) use stream::ObjectReader;
+pub(crate) use tag::Tag;
+pub(crate) use tree::{FileMode, Tree, TreeEntry};
+use utils::build_tree;
+pub(crate) use utils::{
+    flatten_tree, hash_object, list_files, loose_objects, loose_path, objects_dir, quote_path,
+};
+pub(crate) use walk::{is_ancestor, objects_between, CommitWalker, ReachableObject};
+
+use crate::config::Config;
+use crate::pack;
+
+/// Sizes read from headers are only trusted this far when allocating up front,
+/// so a corrupt or hostile object can't make us reserve gigabytes it doesn't have
+pub(crate) const MAX_PREALLOCATION: usize = 1024 * 1024;
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -22,152 +42,146 @@ pub(crate) struct GitObject {
 
 impl GitObject {
     pub(crate) fn load(hash: &str) -> Result<Self> {
-        anyhow::ensure!(hash.len() == 40);
-        let path = create_filepath(hash)?;
-        let f =
-            std::fs::File::open(&path).with_context(|| format!("opening git object {}", path))?;
-        let decoder = ZlibDecoder::new(f);
-        let mut decoder = BufReader::new(decoder);
-        let mut buf = Vec::new();
-
-        decoder
-            .read_until(0, &mut buf)
-            .context("reading object header")?;
-
-        let header = CStr::from_bytes_with_nul(&buf).context("converting header to string")?;
-        let header = header.to_str().context("converting header to string")?;
-        let Some((obj_type, size)) = header.split_once(' ') else {
-            anyhow::bail!("no object type or size");
-        };
-
-        let obj = GitObjectType::from(obj_type);
-        let size = size.parse::<usize>().context("converting size")?;
+        if !loose_path(hash)?.exists() {
+            return Self::load_packed(hash);
+        }
 
-        let mut content = Vec::with_capacity(size);
-        decoder
+        let mut reader = ObjectReader::open(hash)?;
+        let mut content = Vec::with_capacity(reader.size.min(MAX_PREALLOCATION));
+        reader
             .read_to_end(&mut content)
-            .context("reading content slice")?;
+            .context("reading object content")?;
 
         Ok(Self {
             hash: hash.to_string(),
+            size: content.len(),
             content,
-            obj_type: obj,
-            size,
+            obj_type: reader.obj_type,
         })
     }
 
-    pub(crate) fn create_blob(file: impl AsRef<Path>) -> Result<Self> {
-        let metadata = std::fs::metadata(&file)
-            .with_context(|| format!("getting {} metadata", file.as_ref().display()))?;
-        let size = metadata.size() as usize;
-        let mut buffer = vec![];
-        write!(buffer, "blob {size}\0").context("writing blob header")?;
-
-        let mut f = std::fs::File::open(&file).context("opening file")?;
-        std::io::copy(&mut f, &mut buffer).context("copying file contents over")?;
+    /// Rehashes the object, catching truncated or bit-flipped data before it's used
+    fn verify(&self) -> Result<()> {
+        let actual = hash_object(self.obj_type, &self.content);
+        anyhow::ensure!(
+            actual == self.hash,
+            "object {} is corrupt: its content hashes to {actual}",
+            self.hash
+        );
 
-        let mut hasher = Sha1::new();
-        hasher.update(&buffer);
+        Ok(())
+    }
 
-        let raw_hash = hasher.finalize();
-        let hash = hex::encode(raw_hash);
+    /// Whether an object is present, loose or packed, without loading it
+    pub(crate) fn exists(hash: &str) -> Result<bool> {
+        if loose_path(hash)?.exists() {
+            return Ok(true);
+        }
 
-        Ok(Self {
-            hash,
-            content: buffer,
-            obj_type: GitObjectType::Blob,
-            size,
-        })
+        pack::contains(hash).context("searching packs for object")
     }
 
-    pub(crate) fn create_tree(path: impl AsRef<Path>) -> Result<Self> {
-        let tree_content = build_tree(path).context("constructing tree object")?;
-        let size = tree_content.len();
-        let raw = hash_content(&tree_content);
-        let hash = hex::encode(raw);
+    fn load_packed(hash: &str) -> Result<Self> {
+        let Some((obj_type, content)) =
+            pack::find_object(hash).context("searching packs for object")?
+        else {
+            anyhow::bail!("object {hash} not found");
+        };
 
-        Ok(Self {
-            hash,
-            content: tree_content,
-            obj_type: GitObjectType::Tree,
-            size,
-        })
-    }
+        let obj = Self {
+            hash: hash.to_string(),
+            size: content.len(),
+            content,
+            obj_type,
+        };
+        obj.verify()?;
 
-    pub(crate) fn create_commit(
-        tree_hash: String,
-        parent: Option<String>,
-        message: String,
-    ) -> Result<Self> {
-        use std::fmt::Write; // Prevents clash with io::Write i guess
+        Ok(obj)
+    }
 
-        let mut content = String::new();
-        writeln!(content, "tree {tree_hash}")?;
+    /// Hashes a file as a blob without reading it all into memory, also storing
+    /// it as a loose object when `write` is set
+    pub(crate) fn hash_file(file: impl AsRef<Path>, write: bool) -> Result<String> {
+        let f = std::fs::File::open(&file)
+            .with_context(|| format!("opening {}", file.as_ref().display()))?;
+        let size = f
+            .metadata()
+            .with_context(|| format!("getting {} metadata", file.as_ref().display()))?
+            .len();
+
+        hash_stream(GitObjectType::Blob, f, size, write)
+            .with_context(|| format!("hashing {}", file.as_ref().display()))
+    }
 
-        if let Some(parent) = parent {
-            writeln!(content, "parent {parent}")?;
+    /// Hashes a working tree entry, storing a symlink's target rather than following it
+    pub(crate) fn hash_worktree_file(
+        path: impl AsRef<Path>,
+        metadata: &std::fs::Metadata,
+        write: bool,
+    ) -> Result<String> {
+        if !metadata.is_symlink() {
+            return Self::hash_file(path, write);
         }
 
-        // TODO: Deal with getting Author name and Email
-        let author = "Big Cheese";
-        let email = "cheddar@dairyfarm.com";
-        let time =
-            std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?;
-        writeln!(
-            content,
-            "author {author} <{email}> {} +0000",
-            time.as_secs()
-        )?;
-        writeln!(
-            content,
-            "committer {author} <{email}> {} +0000",
-            time.as_secs()
-        )?;
-        writeln!(content, "")?;
-        writeln!(content, "{message}")?;
-
-        let size = content.len();
+        let target = std::fs::read_link(&path).context("reading symlink target")?;
+        let target = target.as_os_str().as_encoded_bytes();
+        hash_stream(GitObjectType::Blob, target, target.len() as u64, write)
+    }
 
-        let mut commit = String::new();
-        write!(commit, "commit {size}\0{content}")?;
+    pub(crate) fn create_tree(path: impl AsRef<Path>) -> Result<Self> {
+        let tree = build_tree(path).context("constructing tree object")?;
+        tree.to_object()
+    }
 
-        let commit = commit.as_bytes().to_vec();
-        let raw = hash_content(&commit);
-        let hash = hex::encode(raw);
+    /// Creates a commit committed by the current identity and authored by it too
+    /// unless `author` is given, storing the message exactly as given
+    pub(crate) fn create_commit(
+        tree_hash: String,
+        parents: Vec<String>,
+        message: String,
+        author: Option<Signature>,
+    ) -> Result<Self> {
+        let config = Config::load()?;
+        let author = match author {
+            Some(author) => author,
+            None => Signature::current(Role::Author, &config).context("getting author")?,
+        };
+        let commit = Commit {
+            tree: tree_hash,
+            parents,
+            author,
+            committer: Signature::current(Role::Committer, &config).context("getting committer")?,
+            extra_headers: Vec::new(),
+            message: message.into_bytes(),
+        };
 
-        Ok(Self {
-            content: commit,
-            hash,
-            size,
-            obj_type: GitObjectType::Commit,
-        })
+        commit.to_object()
     }
 
     pub(crate) fn create_raw(data: &[u8], obj: GitObjectType) -> Result<Self> {
-        let mut content = vec![];
-        write!(content, "{} {}\0", obj, data.len())?;
-        content.extend(data);
-        let hash = hex::encode(hash_content(&content));
-
         Ok(Self {
-            content,
+            content: data.to_vec(),
             size: data.len(),
-            hash,
+            hash: hash_object(obj, data),
             obj_type: obj,
         })
     }
 
+    /// Stores the object as a loose file, written to a temporary file and
+    /// renamed into place so readers never see a partial object
     pub(crate) fn write(&self) -> Result<()> {
-        let compressed = compress(&self.content[..]).context("attempting to compress data")?;
-        let path = create_filepath(&self.hash)?;
-        let mut f = if std::path::PathBuf::from(&path).exists() {
-            // This file is the same, no reason to write again
-            return Ok(());
-        } else {
-            std::fs::File::create(&path).context("creating file to write object")?
-        };
-
-        f.write_all(&compressed).context("writing git object")?;
+        let hash = hash_stream(
+            self.obj_type,
+            &self.content[..],
+            self.content.len() as u64,
+            true,
+        )
+        .context("writing git object")?;
+        anyhow::ensure!(
+            hash == self.hash,
+            "object {} hashed to {hash} when written",
+            self.hash
+        );
 
         Ok(())
     }
@@ -182,25 +196,27 @@ pub(crate) enum GitObjectType {
     Tag,
 }
 
-impl From<&str> for GitObjectType {
-    fn from(raw: &str) -> Self {
+impl TryFrom<&str> for GitObjectType {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: &str) -> Result<Self> {
         match raw {
-            "blob" => Self::Blob,
-            "tree" => Self::Tree,
-            "commit" => Self::Commit,
-            "tag" => Self::Tag,
-            _ => Self::default(),
+            "blob" => Ok(Self::Blob),
+            "tree" => Ok(Self::Tree),
+            "commit" => Ok(Self::Commit),
+            "tag" => Ok(Self::Tag),
+            _ => anyhow::bail!("unknown object type '{raw}'"),
         }
     }
 }
 
-impl Into<String> for GitObjectType {
-    fn into(self) -> String {
-        match self {
-            Self::Blob => "blob".to_string(),
-            Self::Tree => "tree".to_string(),
-            Self::Commit => "commit".to_string(),
-            Self::Tag => "tag".to_string(),
+impl From<GitObjectType> for String {
+    fn from(obj_type: GitObjectType) -> Self {
+        match obj_type {
+            GitObjectType::Blob => "blob".to_string(),
+            GitObjectType::Tree => "tree".to_string(),
+            GitObjectType::Commit => "commit".to_string(),
+            GitObjectType::Tag => "tag".to_string(),
         }
     }
 }
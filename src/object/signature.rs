@@ -0,0 +1,280 @@
+use anyhow::{Context, Result};
+
+use super::date::{now, parse_date};
+use crate::config::Config;
+
+/// A timezone offset as it appears in a signature, e.g. `+0100` or `-0530`.
+///
+/// The sign is tracked separately from the offset so that `-0000` survives a
+/// round-trip unchanged.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Timezone {
+    pub(crate) negative: bool,
+    pub(crate) minutes: u32,
+}
+
+impl Timezone {
+    pub(crate) fn parse(raw: &str) -> Result<Self> {
+        let [sign, digits @ ..] = raw.as_bytes() else {
+            anyhow::bail!("invalid timezone {raw}");
+        };
+        anyhow::ensure!(
+            digits.len() == 4 && digits.iter().all(u8::is_ascii_digit),
+            "invalid timezone {raw}"
+        );
+        let negative = match sign {
+            b'+' => false,
+            b'-' => true,
+            _ => anyhow::bail!("invalid timezone sign in {raw}"),
+        };
+
+        let number = |digits: &[u8]| digits.iter().fold(0, |n, d| n * 10 + u32::from(d - b'0'));
+        Ok(Self {
+            negative,
+            minutes: number(&digits[..2]) * 60 + number(&digits[2..]),
+        })
+    }
+}
+
+impl Timezone {
+    pub(crate) fn from_seconds(offset: i32) -> Self {
+        Self {
+            negative: offset < 0,
+            minutes: offset.unsigned_abs() / 60,
+        }
+    }
+
+    /// The offset from UTC in seconds
+    pub(crate) fn seconds(&self) -> i32 {
+        let seconds = self.minutes as i32 * 60;
+        if self.negative {
+            -seconds
+        } else {
+            seconds
+        }
+    }
+}
+
+impl std::fmt::Display for Timezone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.negative { '-' } else { '+' };
+        write!(f, "{sign}{:02}{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+/// The identity line used by commits and tags: `Name <email> <unix time> <tz>`.
+/// The name and email are bytes, in whatever encoding the object uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Signature {
+    pub(crate) name: Vec<u8>,
+    pub(crate) email: Vec<u8>,
+    pub(crate) time: i64,
+    pub(crate) timezone: Timezone,
+    /// The bytes between the name and `<`, and between `>` and the time, which
+    /// older tools didn't always write as a single space
+    pub(crate) separators: [Vec<u8>; 2],
+}
+
+/// Which identity a new signature is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Author,
+    Committer,
+}
+
+impl Signature {
+    /// The identity and time to record for a new object, taken from
+    /// `GIT_<ROLE>_NAME`, `GIT_<ROLE>_EMAIL` and `GIT_<ROLE>_DATE` where set, then
+    /// from `author.*` or `committer.*` and finally `user.name` and `user.email`
+    /// in the config, and dated now unless the date is overridden
+    pub(crate) fn current(role: Role, config: &Config) -> Result<Self> {
+        let (env, section) = match role {
+            Role::Author => ("GIT_AUTHOR", "author"),
+            Role::Committer => ("GIT_COMMITTER", "committer"),
+        };
+        let lookup = |field: &str| {
+            std::env::var(format!("{env}_{}", field.to_ascii_uppercase()))
+                .ok()
+                .or_else(|| {
+                    config
+                        .get(&format!("{section}.{field}"))
+                        .map(str::to_string)
+                })
+                .or_else(|| config.get(&format!("user.{field}")).map(str::to_string))
+        };
+
+        let name = lookup("name");
+        let email = lookup("email").or_else(|| std::env::var("EMAIL").ok());
+        let (Some(name), Some(email)) = (name, email) else {
+            anyhow::bail!(
+                "{section} identity unknown: set user.name and user.email, or {env}_NAME and {env}_EMAIL"
+            );
+        };
+
+        Self::with_identity(role, &name, &email)
+    }
+
+    /// A signature for the given name and email, dated by `GIT_<ROLE>_DATE` or now
+    pub(crate) fn with_identity(role: Role, name: &str, email: &str) -> Result<Self> {
+        let (env, section) = match role {
+            Role::Author => ("GIT_AUTHOR", "author"),
+            Role::Committer => ("GIT_COMMITTER", "committer"),
+        };
+        let name = sanitize(name);
+        anyhow::ensure!(!name.is_empty(), "empty {section} name not allowed");
+
+        let (time, timezone) = match std::env::var(format!("{env}_DATE")) {
+            Ok(raw) => parse_date(&raw).with_context(|| format!("parsing {env}_DATE"))?,
+            Err(_) => now(),
+        };
+
+        Ok(Self {
+            name: name.into_bytes(),
+            email: sanitize(email).into_bytes(),
+            time,
+            timezone,
+            separators: [b" ".to_vec(), b" ".to_vec()],
+        })
+    }
+
+    pub(crate) fn parse(raw: &[u8]) -> Result<Self> {
+        let lossy = || String::from_utf8_lossy(raw);
+        let Some(open) = raw.iter().position(|&b| b == b'<') else {
+            anyhow::bail!("missing email in signature: {}", lossy());
+        };
+        let Some(close) = raw[open..].iter().position(|&b| b == b'>') else {
+            anyhow::bail!("unterminated email in signature: {}", lossy());
+        };
+        let close = open + close;
+
+        let rest = std::str::from_utf8(&raw[close + 1..])
+            .with_context(|| format!("invalid date in signature: {}", lossy()))?;
+        let date = rest.trim_start();
+        let Some((time, timezone)) = date.split_once(' ') else {
+            anyhow::bail!("missing timestamp in signature: {}", lossy());
+        };
+
+        // Only the space that separates the name from the email is split off,
+        // any other whitespace stays part of the name
+        let name = &raw[..open];
+        let name_end = name.strip_suffix(b" ").unwrap_or(name).len();
+        Ok(Self {
+            name: name[..name_end].to_vec(),
+            email: raw[open + 1..close].to_vec(),
+            time: time
+                .parse::<i64>()
+                .with_context(|| format!("parsing signature timestamp {time}"))?,
+            timezone: Timezone::parse(timezone).context("parsing signature timezone")?,
+            separators: [
+                name[name_end..].to_vec(),
+                raw[close + 1..raw.len() - date.len()].to_vec(),
+            ],
+        })
+    }
+
+    /// The signature as it appears in an object
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let [before_email, after_email] = &self.separators;
+        let mut out = self.name.clone();
+        out.extend_from_slice(before_email);
+        out.push(b'<');
+        out.extend_from_slice(&self.email);
+        out.push(b'>');
+        out.extend_from_slice(after_email);
+        out.extend_from_slice(format!("{} {}", self.time, self.timezone).as_bytes());
+        out
+    }
+}
+
+impl std::fmt::Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} <{}> {} {}",
+            String::from_utf8_lossy(&self.name),
+            String::from_utf8_lossy(&self.email),
+            self.time,
+            self.timezone
+        )
+    }
+}
+
+/// Drops the characters that would break a signature line, and the whitespace
+/// around what's left
+fn sanitize(raw: &str) -> String {
+    raw.replace(['<', '>', '\n'], "").trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timezones() {
+        for (raw, seconds) in [
+            ("+0000", 0),
+            ("-0000", 0),
+            ("+0530", 19800),
+            ("-1200", -43200),
+        ] {
+            let timezone = Timezone::parse(raw).unwrap();
+            assert_eq!(timezone.seconds(), seconds);
+            assert_eq!(timezone.to_string(), raw);
+        }
+
+        // Multibyte characters used to be sliced through and panic
+        for raw in [
+            "", "+", "0100", "+01:00", "+100", "+01000", "ü123", "+ü12", "+12ü",
+        ] {
+            assert!(Timezone::parse(raw).is_err(), "{raw}");
+        }
+    }
+
+    #[test]
+    fn signatures_round_trip_byte_for_byte() {
+        for raw in [
+            &b"A U Thor <author@example.com> 1700000000 +0100"[..],
+            b"  Spaced  <s@example.com> 1700000000 -0000",
+            b"Jos\xe9 <jose@example.com> 0 +0000",
+            b"<nameless@example.com> 1700000000 +0000",
+            b"Nobody <> 1700000000 +0000",
+            b"Foo<a@b> 1 +0000",
+            b"Foo <a@b>   1 +0000",
+            b"Foo \t<a@b>\t1 +0000",
+            b"Foo <a@b>1 +0000",
+        ] {
+            let signature = Signature::parse(raw).unwrap();
+            assert_eq!(signature.to_bytes(), raw);
+        }
+
+        let signature = Signature::parse(b"A <a@b> 1700000000 -0230").unwrap();
+        assert_eq!(signature.name, b"A");
+        assert_eq!(signature.email, b"a@b");
+        assert_eq!(signature.timezone.seconds(), -9000);
+
+        let signature = Signature::parse(b"Foo<a@b>   1 +0000").unwrap();
+        assert_eq!(signature.name, b"Foo");
+        assert_eq!(signature.separators, [b"".to_vec(), b"   ".to_vec()]);
+        assert_eq!(signature.time, 1);
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        for raw in [
+            &b"A U Thor"[..],
+            b"A <a@b",
+            b"A <a@b>",
+            b"A <a@b> 1700000000",
+            b"A <a@b> soon +0000",
+            b"A <a@b> 1700000000 +01:00",
+            b"A <a@b> \xff +0000",
+        ] {
+            assert!(Signature::parse(raw).is_err(), "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn sanitizes_new_identities() {
+        assert_eq!(sanitize("  <A> U\nThor  "), "A UThor");
+    }
+}
@@ -0,0 +1,271 @@
+use anyhow::{Context, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use sha1::{Digest, Sha1};
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use super::utils::{create_filepath, loose_path, objects_dir};
+use super::{GitObject, GitObjectType};
+use crate::config::Config;
+use crate::pack;
+
+/// Data is hashed and compressed this much at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Reads an object's content incrementally, checking its size and hash once
+/// the end is reached so large blobs never have to be held in memory
+pub(crate) struct ObjectReader {
+    pub(crate) obj_type: GitObjectType,
+    pub(crate) size: usize,
+    hash: String,
+    inner: Box<dyn Read>,
+    hasher: Sha1,
+    read: usize,
+}
+
+impl std::fmt::Debug for ObjectReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjectReader")
+            .field("obj_type", &self.obj_type)
+            .field("size", &self.size)
+            .field("hash", &self.hash)
+            .field("read", &self.read)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ObjectReader {
+    /// Opens a loose object, or a packed one straight from its pack's zlib stream.
+    /// Deltified objects can only be rebuilt in memory, so those are read whole
+    /// first.
+    pub(crate) fn open(hash: &str) -> Result<Self> {
+        let path = loose_path(hash)?;
+        if !path.exists() {
+            if let Some((obj_type, size, reader)) =
+                pack::stream_object(hash).context("searching packs for object")?
+            {
+                return Ok(Self::new(hash, obj_type, size, Box::new(reader)));
+            }
+
+            let obj = GitObject::load(hash)?;
+            return Ok(Self::new(
+                hash,
+                obj.obj_type,
+                obj.content.len(),
+                Box::new(Cursor::new(obj.content)),
+            ));
+        }
+
+        let file = File::open(&path).with_context(|| format!("opening git object {hash}"))?;
+        let mut decoder = BufReader::new(ZlibDecoder::new(file));
+        let mut buf = Vec::new();
+        decoder
+            .read_until(0, &mut buf)
+            .context("reading object header")?;
+
+        let header = CStr::from_bytes_with_nul(&buf).context("converting header to string")?;
+        let header = header.to_str().context("converting header to string")?;
+        let Some((obj_type, size)) = header.split_once(' ') else {
+            anyhow::bail!("no object type or size");
+        };
+
+        let obj_type =
+            GitObjectType::try_from(obj_type).with_context(|| format!("loading {hash}"))?;
+        let size = size.parse::<usize>().context("converting size")?;
+
+        Ok(Self::new(hash, obj_type, size, Box::new(decoder)))
+    }
+
+    fn new(hash: &str, obj_type: GitObjectType, size: usize, inner: Box<dyn Read>) -> Self {
+        let mut hasher = Sha1::new();
+        hasher.update(format!("{obj_type} {size}\0"));
+
+        Self {
+            obj_type,
+            size,
+            hash: hash.to_string(),
+            inner,
+            hasher,
+            read: 0,
+        }
+    }
+
+    fn corrupt(&self, reason: String) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("object {} is corrupt: {reason}", self.hash),
+        )
+    }
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.read += n;
+
+        if self.read > self.size {
+            return Err(self.corrupt(format!("header claims {} bytes but it has more", self.size)));
+        }
+
+        if n == 0 && !buf.is_empty() {
+            if self.read != self.size {
+                return Err(self.corrupt(format!(
+                    "header claims {} bytes but it has {}",
+                    self.size, self.read
+                )));
+            }
+
+            let actual = hex::encode(self.hasher.clone().finalize());
+            if actual != self.hash {
+                return Err(self.corrupt(format!("its content hashes to {actual}")));
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+/// Hashes `size` bytes from `reader` as an object of the given type, compressing
+/// them into a temporary file that is moved into place when `write` is set
+pub(crate) fn hash_stream(
+    obj_type: GitObjectType,
+    mut reader: impl Read,
+    size: u64,
+    write: bool,
+) -> Result<String> {
+    let header = format!("{obj_type} {size}\0");
+    let mut hasher = Sha1::new();
+    hasher.update(&header);
+
+    let mut temp = None;
+    let mut encoder = None;
+    if write {
+        let (path, file) = create_temp_object().context("creating temporary object")?;
+        let mut zlib = ZlibEncoder::new(file, flate2::Compression::default());
+        zlib.write_all(header.as_bytes())
+            .context("writing object header")?;
+        temp = Some(TempObject(path));
+        encoder = Some(zlib);
+    }
+
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut total = 0u64;
+    loop {
+        let n = reader.read(&mut buf).context("reading object content")?;
+        if n == 0 {
+            break;
+        }
+
+        total += n as u64;
+        anyhow::ensure!(total <= size, "content grew while it was being hashed");
+        hasher.update(&buf[..n]);
+        if let Some(encoder) = encoder.as_mut() {
+            encoder.write_all(&buf[..n]).context("writing git object")?;
+        }
+    }
+    anyhow::ensure!(
+        total == size,
+        "expected {size} bytes of content but read {total}"
+    );
+
+    let hash = hex::encode(hasher.finalize());
+    if let (Some(temp), Some(encoder)) = (temp, encoder) {
+        let file = encoder.finish().context("finishing git object")?;
+        install(temp, file, &hash)?;
+    }
+
+    Ok(hash)
+}
+
+/// Moves a fully written temporary object to its final path, syncing it first
+/// if configured so a crash can never leave a truncated object behind
+fn install(temp: TempObject, file: File, hash: &str) -> Result<()> {
+    let path = create_filepath(hash)?;
+    if path.exists() {
+        return Ok(());
+    }
+
+    let fsync = fsync_objects()?;
+    if fsync {
+        file.sync_all().context("syncing git object")?;
+    }
+    drop(file);
+
+    // Objects never change once written
+    std::fs::set_permissions(&temp.0, std::fs::Permissions::from_mode(0o444))
+        .context("making object read-only")?;
+    std::fs::rename(&temp.0, &path).context("moving object into place")?;
+
+    if fsync {
+        if let Some(dir) = path.parent() {
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .context("syncing object directory")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether loose objects are synced to disk before being renamed into place,
+/// following `core.fsync` (any of `loose-object`, `objects`, `added`, `committed`
+/// or `all`) or the older `core.fsyncObjectFiles`, and on by default
+fn fsync_objects() -> Result<bool> {
+    static FSYNC: OnceLock<bool> = OnceLock::new();
+
+    if let Some(fsync) = FSYNC.get() {
+        return Ok(*fsync);
+    }
+
+    let config = Config::load()?;
+    let components = config.get("core.fsync").map(|value| {
+        value.split(',').any(|component| {
+            matches!(
+                component.trim(),
+                "loose-object" | "objects" | "added" | "committed" | "all"
+            )
+        })
+    });
+    let legacy = config.get_bool("core.fsyncObjectFiles")?;
+
+    Ok(*FSYNC.get_or_init(|| components.or(legacy).unwrap_or(true)))
+}
+
+/// A partially written object, removed unless it's been renamed into place
+struct TempObject(PathBuf);
+
+impl Drop for TempObject {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn create_temp_object() -> Result<(PathBuf, File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = objects_dir();
+    std::fs::create_dir_all(&dir).context("creating object directory")?;
+    loop {
+        let name = format!(
+            "tmp_obj_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("creating {}", path.display()));
+            }
+        }
+    }
+}
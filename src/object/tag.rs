@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+
+use super::utils::{header_text, parse_headers, write_header, Headers};
+use super::{GitObject, GitObjectType, Signature};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Tag {
+    pub(crate) object: String,
+    pub(crate) obj_type: GitObjectType,
+    pub(crate) name: Vec<u8>,
+    /// Very old tags were written without a tagger
+    pub(crate) tagger: Option<Signature>,
+    pub(crate) extra_headers: Headers,
+    pub(crate) message: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl Tag {
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        let mut content = Vec::new();
+        write_header(&mut content, "object", self.object.as_bytes());
+        write_header(&mut content, "type", self.obj_type.to_string().as_bytes());
+        write_header(&mut content, "tag", &self.name);
+        if let Some(tagger) = &self.tagger {
+            write_header(&mut content, "tagger", &tagger.to_bytes());
+        }
+        for (key, value) in &self.extra_headers {
+            write_header(&mut content, key, value);
+        }
+        content.push(b'\n');
+        content.extend_from_slice(&self.message);
+
+        content
+    }
+
+    pub(crate) fn to_object(&self) -> Result<GitObject> {
+        GitObject::create_raw(&self.serialize(), GitObjectType::Tag)
+    }
+}
+
+impl TryFrom<&GitObject> for Tag {
+    type Error = anyhow::Error;
+
+    fn try_from(obj: &GitObject) -> Result<Self> {
+        anyhow::ensure!(
+            obj.obj_type == GitObjectType::Tag,
+            "expected tag, found {} {}",
+            obj.obj_type,
+            obj.hash
+        );
+
+        let (headers, message) = parse_headers(&obj.content).context("parsing tag headers")?;
+        let mut headers = headers.into_iter().peekable();
+
+        let object = match headers.next() {
+            Some((key, value)) if key == "object" => header_text(&key, value)?,
+            _ => anyhow::bail!("tag {} is missing an object", obj.hash),
+        };
+
+        let obj_type = match headers.next() {
+            Some((key, value)) if key == "type" => {
+                GitObjectType::try_from(header_text(&key, value)?.as_str())
+                    .with_context(|| format!("parsing type of tag {}", obj.hash))?
+            }
+            _ => anyhow::bail!("tag {} is missing a type", obj.hash),
+        };
+
+        let name = match headers.next() {
+            Some((key, value)) if key == "tag" => value,
+            _ => anyhow::bail!("tag {} is missing a name", obj.hash),
+        };
+
+        let tagger = match headers.next_if(|(key, _)| key == "tagger") {
+            Some((_, value)) => Some(Signature::parse(&value).context("parsing tagger")?),
+            None => None,
+        };
+
+        Ok(Self {
+            object,
+            obj_type,
+            name,
+            tagger,
+            extra_headers: headers.collect(),
+            message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAG: &[u8] = include_bytes!("../../tests/fixtures/tag");
+
+    #[test]
+    fn round_trips_an_annotated_tag() {
+        let obj = GitObject::create_raw(TAG, GitObjectType::Tag).unwrap();
+        assert_eq!(obj.hash, "826304e4f5dd574e0435d5b70f6d4a138f265a72");
+
+        let tag = Tag::try_from(&obj).unwrap();
+        assert_eq!(tag.object, "cdbbd8e509fdf14b0b56cb5c0d3ca7d538296320");
+        assert_eq!(tag.obj_type, GitObjectType::Commit);
+        assert_eq!(tag.name, b"v1.0");
+        assert_eq!(tag.tagger.as_ref().unwrap().timezone.to_string(), "+0100");
+        assert!(tag.message.ends_with(b"-----END PGP SIGNATURE-----\n"));
+
+        assert_eq!(tag.serialize(), TAG);
+        assert_eq!(tag.to_object().unwrap().hash, obj.hash);
+    }
+
+    #[test]
+    fn taggerless_tags_round_trip() {
+        let content =
+            b"object cdbbd8e509fdf14b0b56cb5c0d3ca7d538296320\ntype commit\ntag old\n\nancient\n";
+        let obj = GitObject::create_raw(content, GitObjectType::Tag).unwrap();
+
+        let tag = Tag::try_from(&obj).unwrap();
+        assert_eq!(tag.tagger, None);
+        assert_eq!(tag.serialize(), content);
+    }
+
+    #[test]
+    fn rejects_unknown_object_types() {
+        let content = b"object cdbbd8e509fdf14b0b56cb5c0d3ca7d538296320\ntype widget\ntag v1\n\n";
+        let obj = GitObject::create_raw(content, GitObjectType::Tag).unwrap();
+        assert!(Tag::try_from(&obj).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use anyhow::{Context, Result};
+
+use std::io::{BufRead, Read, Write};
+
+use super::{GitObject, GitObjectType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FileMode {
+    Regular,
+    Executable,
+    Symlink,
+    Gitlink,
+    Directory,
+    /// Non-canonical modes found in some older trees, kept so they round-trip,
+    /// down to any zero padding as in `040000`
+    Other {
+        mode: u32,
+        width: usize,
+    },
+}
+
+impl FileMode {
+    /// Normalises a `st_mode` from the filesystem the same way git does
+    pub(crate) fn from_mode(mode: u32) -> Self {
+        match mode & 0o170000 {
+            0o040000 => Self::Directory,
+            0o120000 => Self::Symlink,
+            0o160000 => Self::Gitlink,
+            _ if mode & 0o111 != 0 => Self::Executable,
+            _ => Self::Regular,
+        }
+    }
+
+    pub(crate) fn parse(raw: &str) -> Result<Self> {
+        anyhow::ensure!(
+            !raw.is_empty() && raw.bytes().all(|b| (b'0'..=b'7').contains(&b)),
+            "invalid mode {raw}"
+        );
+        let mode = u32::from_str_radix(raw, 8).with_context(|| format!("invalid mode {raw}"))?;
+        let canonical = match mode {
+            0o100644 => Some(Self::Regular),
+            0o100755 => Some(Self::Executable),
+            0o120000 => Some(Self::Symlink),
+            0o160000 => Some(Self::Gitlink),
+            0o040000 => Some(Self::Directory),
+            _ => None,
+        };
+
+        // Zero padding, as in `040000`, makes an otherwise canonical mode odd too
+        Ok(match canonical {
+            Some(canonical) if canonical.to_string() == raw => canonical,
+            _ => Self::Other {
+                mode,
+                width: raw.len(),
+            },
+        })
+    }
+
+    pub(crate) fn as_u32(&self) -> u32 {
+        match *self {
+            Self::Regular => 0o100644,
+            Self::Executable => 0o100755,
+            Self::Symlink => 0o120000,
+            Self::Gitlink => 0o160000,
+            Self::Directory => 0o040000,
+            Self::Other { mode, .. } => mode,
+        }
+    }
+
+    /// The canonical mode an `Other` mode stands for, going by its type bits
+    pub(crate) fn canonical(&self) -> Self {
+        match *self {
+            Self::Other { mode, .. } => Self::from_mode(mode),
+            mode => mode,
+        }
+    }
+
+    pub(crate) fn is_tree(&self) -> bool {
+        self.canonical() == Self::Directory
+    }
+
+    /// The type of object an entry with this mode points at
+    pub(crate) fn object_type(&self) -> GitObjectType {
+        match self.canonical() {
+            Self::Directory => GitObjectType::Tree,
+            Self::Gitlink => GitObjectType::Commit,
+            _ => GitObjectType::Blob,
+        }
+    }
+}
+
+impl std::fmt::Display for FileMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::Other { mode, width } => write!(f, "{mode:0width$o}"),
+            mode => write!(f, "{:o}", mode.as_u32()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TreeEntry {
+    pub(crate) mode: FileMode,
+    /// Any bytes other than `/` and NUL, which needn't be UTF-8
+    pub(crate) name: Vec<u8>,
+    pub(crate) hash: String,
+}
+
+impl TreeEntry {
+    /// Trees are ordered as if directory names had a trailing `/`
+    pub(crate) fn sort_key(&self) -> Vec<u8> {
+        let mut key = self.name.clone();
+        if self.mode.is_tree() {
+            key.push(b'/');
+        }
+
+        key
+    }
+
+    /// Rejects names that would step outside the tree's directory or into the
+    /// git directory once checked out
+    pub(crate) fn check_name(&self) -> Result<()> {
+        let name = &self.name[..];
+        let display = String::from_utf8_lossy(name);
+        anyhow::ensure!(!name.is_empty(), "empty file name");
+        anyhow::ensure!(
+            !name.contains(&b'/'),
+            "file name '{display}' contains a '/'"
+        );
+        anyhow::ensure!(
+            name != b"." && name != b".." && !name.eq_ignore_ascii_case(b".git"),
+            "file name '{display}' is not allowed in a tree"
+        );
+        Ok(())
+    }
+
+    /// The name as a path component, for the parts of the tree that need text
+    pub(crate) fn name_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Tree {
+    pub(crate) entries: Vec<TreeEntry>,
+}
+
+impl Tree {
+    pub(crate) fn sort(&mut self) {
+        self.entries.sort_by_key(|entry| entry.sort_key());
+    }
+
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        for entry in &self.entries {
+            write!(content, "{} ", entry.mode).context("writing tree entry")?;
+            content.extend_from_slice(&entry.name);
+            content.push(0);
+            let hash = hex::decode(&entry.hash)
+                .with_context(|| format!("decoding tree entry hash {}", entry.hash))?;
+            content.extend(hash);
+        }
+
+        Ok(content)
+    }
+
+    pub(crate) fn to_object(&self) -> Result<GitObject> {
+        GitObject::create_raw(&self.serialize()?, GitObjectType::Tree)
+    }
+}
+
+impl TryFrom<&GitObject> for Tree {
+    type Error = anyhow::Error;
+
+    fn try_from(obj: &GitObject) -> Result<Self> {
+        anyhow::ensure!(
+            obj.obj_type == GitObjectType::Tree,
+            "expected tree, found {} {}",
+            obj.obj_type,
+            obj.hash
+        );
+
+        let mut buf = &obj.content[..];
+        let mut entries = Vec::new();
+
+        while !buf.is_empty() {
+            let mut info = Vec::new();
+            buf.read_until(0, &mut info)
+                .context("reading tree entry header")?;
+            anyhow::ensure!(info.pop() == Some(0), "unterminated tree entry");
+
+            let mut hash: [u8; 20] = [0; 20];
+            buf.read_exact(&mut hash)
+                .context("reading tree entry hash")?;
+
+            let Some(space) = info.iter().position(|&b| b == b' ') else {
+                anyhow::bail!(
+                    "missing file mode and name: {}",
+                    String::from_utf8_lossy(&info)
+                );
+            };
+            let mode = std::str::from_utf8(&info[..space]).context("tree entry mode")?;
+
+            entries.push(TreeEntry {
+                mode: FileMode::parse(mode)?,
+                name: info[space + 1..].to_vec(),
+                hash: hex::encode(hash),
+            });
+        }
+
+        Ok(Self { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TREE: &[u8] = include_bytes!("../../tests/fixtures/tree");
+
+    fn entry(mode: FileMode, name: &[u8]) -> TreeEntry {
+        TreeEntry {
+            mode,
+            name: name.to_vec(),
+            hash: "45b983be36b73c0788dc9cbcb76cbb80fc7bb057".to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trips_a_tree_written_by_git() {
+        let obj = GitObject::create_raw(TREE, GitObjectType::Tree).unwrap();
+        assert_eq!(obj.hash, "6a853821d6d9d94df0c6a2b185193ebb4d5a7492");
+
+        let tree = Tree::try_from(&obj).unwrap();
+        let listing: Vec<_> = tree
+            .entries
+            .iter()
+            .map(|entry| (entry.mode.to_string(), entry.name.clone()))
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                ("100644".to_string(), b"caf\xe9.txt".to_vec()),
+                ("100644".to_string(), b"dir.txt".to_vec()),
+                ("40000".to_string(), b"dir".to_vec()),
+                ("120000".to_string(), b"link".to_vec()),
+                ("160000".to_string(), b"module".to_vec()),
+                ("100644".to_string(), b"plain.txt".to_vec()),
+                ("100755".to_string(), b"run.sh".to_vec()),
+            ]
+        );
+        assert_eq!(tree.entries[4].mode.object_type(), GitObjectType::Commit);
+
+        // Directories sort as if they had a trailing slash
+        let mut sorted = tree.clone();
+        sorted.entries.reverse();
+        sorted.sort();
+        assert_eq!(sorted, tree);
+
+        assert_eq!(tree.serialize().unwrap(), TREE);
+        assert_eq!(tree.to_object().unwrap().hash, obj.hash);
+    }
+
+    #[test]
+    fn keeps_non_canonical_modes() {
+        let mut content = b"100664 old\0".to_vec();
+        content.extend([0xab; 20]);
+        let obj = GitObject::create_raw(&content, GitObjectType::Tree).unwrap();
+
+        let tree = Tree::try_from(&obj).unwrap();
+        assert_eq!(
+            tree.entries[0].mode,
+            FileMode::Other {
+                mode: 0o100664,
+                width: 6
+            }
+        );
+        assert_eq!(tree.serialize().unwrap(), content);
+    }
+
+    #[test]
+    fn keeps_zero_padded_modes() {
+        for (raw, kind) in [
+            ("040000", FileMode::Directory),
+            ("0100644", FileMode::Regular),
+            ("00160000", FileMode::Gitlink),
+        ] {
+            let mode = FileMode::parse(raw).unwrap();
+            assert!(matches!(mode, FileMode::Other { .. }), "{raw}");
+            assert_eq!(mode.to_string(), raw);
+            assert_eq!(mode.canonical(), kind);
+        }
+        assert!(FileMode::parse("040000").unwrap().is_tree());
+        assert_eq!(FileMode::parse("40000").unwrap(), FileMode::Directory);
+
+        for raw in ["", "+100644", "100648", "-1"] {
+            assert!(FileMode::parse(raw).is_err(), "{raw}");
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_entries() {
+        let mut hash_cut_short = b"100644 file\0".to_vec();
+        hash_cut_short.extend([0xab; 19]);
+        let mut no_mode = b"file\0".to_vec();
+        no_mode.extend([0xab; 20]);
+        let mut bad_mode = b"10x644 file\0".to_vec();
+        bad_mode.extend([0xab; 20]);
+
+        for content in [b"100644 file".to_vec(), hash_cut_short, no_mode, bad_mode] {
+            let obj = GitObject::create_raw(&content, GitObjectType::Tree).unwrap();
+            assert!(Tree::try_from(&obj).is_err(), "{content:?}");
+        }
+    }
+
+    #[test]
+    fn names_that_escape_the_work_tree_are_rejected() {
+        for name in [&b""[..], b".", b"..", b".git", b".GIT", b"a/b", b"../etc"] {
+            assert!(
+                entry(FileMode::Regular, name).check_name().is_err(),
+                "{name:?}"
+            );
+        }
+        for name in [&b".gitignore"[..], b"...", b"git", b"caf\xe9"] {
+            assert!(
+                entry(FileMode::Regular, name).check_name().is_ok(),
+                "{name:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn normalises_filesystem_modes() {
+        assert_eq!(FileMode::from_mode(0o100664), FileMode::Regular);
+        assert_eq!(FileMode::from_mode(0o100744), FileMode::Executable);
+        assert_eq!(FileMode::from_mode(0o120777), FileMode::Symlink);
+        assert_eq!(FileMode::from_mode(0o040755), FileMode::Directory);
+    }
+}
@@ -1,119 +1,323 @@
 use anyhow::{Context, Result};
-use flate2::read::ZlibEncoder;
 use sha1::{Digest, Sha1};
 
-use std::cmp::Ordering;
-use std::io::{Read, Write};
-use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::collections::{BTreeMap, HashSet};
+use std::os::unix::{ffi::OsStringExt, fs::MetadataExt};
+use std::path::{Path, PathBuf};
 
-use crate::object::GitObject;
+use crate::index::{Ignore, Index};
+use crate::object::{FileMode, GitObject, GitObjectType, Tree, TreeEntry};
+use crate::repository::git_path;
 
-pub(crate) fn create_filepath(hash: &str) -> Result<String> {
-    std::fs::create_dir_all(format!(".git/objects/{}", &hash[..2]))
-        .context("creating dir for object")?;
+pub(crate) fn objects_dir() -> PathBuf {
+    git_path("objects")
+}
+
+/// Where an object is stored loose, whether or not it's there
+pub(crate) fn loose_path(hash: &str) -> Result<PathBuf> {
+    anyhow::ensure!(crate::refs::is_hash(hash), "invalid object id {hash}");
+    Ok(objects_dir().join(&hash[..2]).join(&hash[2..]))
+}
+
+pub(crate) fn create_filepath(hash: &str) -> Result<PathBuf> {
+    let path = loose_path(hash)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).context("creating dir for object")?;
+    }
 
-    Ok(format!(".git/objects/{}/{}", &hash[..2], &hash[2..]))
+    Ok(path)
 }
 
-pub(crate) fn compress(content: impl Read) -> Result<Vec<u8>> {
-    let mut compressed = Vec::new();
-    let mut compressor = ZlibEncoder::new(content, flate2::Compression::default());
-    compressor
-        .read_to_end(&mut compressed)
-        .context("compressing data")?;
+/// Every loose object under `.git/objects`, with the path of its file
+pub(crate) fn loose_objects() -> Result<Vec<(String, PathBuf)>> {
+    let mut objects = Vec::new();
+    for entry in std::fs::read_dir(objects_dir()).context("reading object directory")? {
+        let dir = entry.context("bad object directory entry")?;
+        let prefix = dir.file_name().to_string_lossy().into_owned();
+        if prefix.len() != 2 || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+            continue;
+        }
+
+        for entry in std::fs::read_dir(dir.path())
+            .with_context(|| format!("reading {}", dir.path().display()))?
+        {
+            let file = entry.context("bad object directory entry")?;
+            let hash = format!("{prefix}{}", file.file_name().to_string_lossy());
+            if crate::refs::is_hash(&hash) {
+                objects.push((hash, file.path()));
+            }
+        }
+    }
 
-    Ok(compressed)
+    Ok(objects)
 }
 
-pub(crate) fn hash_content(content: impl AsRef<Vec<u8>>) -> Vec<u8> {
+pub(crate) fn hash_object(obj_type: GitObjectType, data: &[u8]) -> String {
     let mut hasher = Sha1::new();
-    hasher.update(content.as_ref());
-    let raw = hasher.finalize();
-    let raw = Vec::from_iter(raw.into_iter());
+    hasher.update(format!("{obj_type} {}\0", data.len()));
+    hasher.update(data);
 
-    raw
+    hex::encode(hasher.finalize())
 }
 
-pub(crate) fn build_tree(path: impl AsRef<Path>) -> Result<Vec<u8>> {
-    let mut contents = vec![];
+pub(crate) fn build_tree(path: impl AsRef<Path>) -> Result<Tree> {
+    let mut tree = Tree::default();
 
-    let mut dir = std::fs::read_dir(path.as_ref())
+    let dir = std::fs::read_dir(path.as_ref())
         .with_context(|| format!("reading directory {}", path.as_ref().display()))?;
 
-    // Sorting taken from https://github.com/jonhoo/codecrafters-git-rust/blob/master/src/commands/write_tree.rs#L14
-    // Cheers, Jon!
     let mut entries = Vec::new();
-    while let Some(entry) = dir.next() {
+    for entry in dir {
         let entry = entry.context("bad entry")?;
         let name = entry.file_name();
         let meta = entry.metadata().context("getting entry metadata")?;
         entries.push((entry, name, meta));
     }
 
-    entries.sort_unstable_by(|a, b| {
-        let afn = &a.1;
-        let afn = afn.as_encoded_bytes();
-        let bfn = &b.1;
-        let bfn = bfn.as_encoded_bytes();
+    for (entry, filename, metadata) in entries {
+        let path = entry.path();
+        let filename = filename.into_vec();
 
-        let common_len = std::cmp::min(afn.len(), bfn.len());
+        if metadata.is_dir() {
+            if filename == b".git" {
+                continue;
+            }
+            let subtree = build_tree(&path).context("recursive call to tree")?;
+            let obj = subtree.to_object().context("creating subtree object")?;
+            tree.entries.push(TreeEntry {
+                mode: FileMode::Directory,
+                name: filename,
+                hash: obj.hash,
+            });
+        } else {
+            let hash = GitObject::hash_worktree_file(&path, &metadata, false)
+                .context("creating blob for tree")?;
 
-        match afn[..common_len].cmp(&bfn[..common_len]) {
-            Ordering::Equal => {}
-            o => return o,
+            tree.entries.push(TreeEntry {
+                mode: FileMode::from_mode(metadata.mode()),
+                name: filename,
+                hash,
+            });
         }
+    }
 
-        if afn.len() == bfn.len() {
-            return Ordering::Equal;
-        }
+    tree.sort();
 
-        let c1 = if let Some(c) = afn.get(common_len).copied() {
-            Some(c)
-        } else if a.2.is_dir() {
-            Some(b'/')
-        } else {
-            None
-        };
+    Ok(tree)
+}
 
-        let c2 = if let Some(c) = bfn.get(common_len).copied() {
-            Some(c)
-        } else if b.2.is_dir() {
-            Some(b'/')
-        } else {
-            None
-        };
+/// Lists every file (or symlink) under `root` as `/` separated paths relative to it,
+/// skipping any `.git` directories and ignored files that aren't tracked. Names that
+/// aren't valid UTF-8 can't be staged, so they're skipped with a warning. Submodules
+/// are listed as the directory itself without looking inside.
+pub(crate) fn list_files(
+    root: impl AsRef<Path>,
+    ignore: &mut Ignore,
+    index: &Index,
+) -> Result<Vec<String>> {
+    let gitlinks: HashSet<&str> = index
+        .entries
+        .iter()
+        .filter(|e| e.file_mode() == FileMode::Gitlink)
+        .map(|e| e.path.as_str())
+        .collect();
+    let mut files = Vec::new();
+    let mut pending = vec![(root.as_ref().to_path_buf(), String::new())];
 
-        c1.cmp(&c2)
-    });
+    while let Some((dir, prefix)) = pending.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("reading directory {}", dir.display()))?;
 
-    for (entry, filename, metadata) in entries {
-        let path = entry.path();
-        let filename = filename
-            .into_string()
-            .expect("this should be a valid filename");
-
-        if metadata.is_file() {
-            let obj = GitObject::create_blob(&path).context("creating blob for tree")?;
-            let f_mode = metadata.mode();
-            let mode_string = format!("{f_mode:o}");
-            let raw = hash_content(&obj.content);
-            write!(contents, "{mode_string} {filename}\0").context("writing blob to buffer")?;
-            contents.extend(raw);
-        } else {
-            if filename.contains(".git") {
+        for entry in entries {
+            let entry = entry.context("bad entry")?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                eprintln!(
+                    "warning: skipping {}, its name is not valid utf-8",
+                    entry.path().display()
+                );
+                continue;
+            };
+            if name == ".git" {
                 continue;
             }
-            let subtree = build_tree(&path).context("recursive call to tree")?;
-            let raw = hash_content(&subtree);
-            write!(contents, "40000 {filename}\0").context("writing subtree metadata")?;
-            contents.extend(raw);
+
+            let relative = format!("{prefix}{name}");
+            let is_dir = entry
+                .file_type()
+                .context("getting entry file type")?
+                .is_dir();
+            if is_dir && gitlinks.contains(relative.as_str()) {
+                files.push(relative);
+                continue;
+            }
+            let ignored = ignore
+                .matches(&relative, is_dir)
+                .with_context(|| format!("checking whether {relative} is ignored"))?;
+
+            match (is_dir, ignored) {
+                (true, false) => pending.push((entry.path(), format!("{relative}/"))),
+                (false, false) => files.push(relative),
+                // Tracked files stay tracked even if they match an ignore pattern
+                (true, true) => {
+                    let tracked = index
+                        .entries
+                        .iter()
+                        .filter(|e| {
+                            e.path
+                                .strip_prefix(&relative)
+                                .is_some_and(|rest| rest.starts_with('/'))
+                        })
+                        .filter(|e| {
+                            root.as_ref()
+                                .join(&e.path)
+                                .symlink_metadata()
+                                .is_ok_and(|metadata| {
+                                    !metadata.is_dir() || gitlinks.contains(e.path.as_str())
+                                })
+                        })
+                        .map(|e| e.path.clone());
+                    files.extend(tracked);
+                }
+                (false, true) => {
+                    if index.entries.iter().any(|e| e.path == relative) {
+                        files.push(relative);
+                    }
+                }
+            }
         }
     }
 
-    let mut tree = vec![];
-    write!(tree, "tree {}\0", contents.len()).context("writing tree metadata")?;
-    tree.extend(contents);
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
 
-    Ok(tree)
+/// Quotes a path as git does by default when it holds a double quote, backslash,
+/// control character or non-ASCII byte (or a space, if `quote_space` is set), using
+/// C-style escapes and octal for anything without one
+pub(crate) fn quote_path(path: &[u8], quote_space: bool) -> String {
+    let needs_quoting =
+        |b: u8| b == b'"' || b == b'\\' || !(0x20..0x7f).contains(&b) || (quote_space && b == b' ');
+    if !path.iter().any(|&b| needs_quoting(b)) {
+        return String::from_utf8_lossy(path).into_owned();
+    }
+
+    let mut quoted = String::from("\"");
+    for &b in path {
+        match b {
+            b'\x07' => quoted.push_str("\\a"),
+            b'\x08' => quoted.push_str("\\b"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\x0b' => quoted.push_str("\\v"),
+            b'\x0c' => quoted.push_str("\\f"),
+            b'\r' => quoted.push_str("\\r"),
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b' ' => quoted.push(' '),
+            b if needs_quoting(b) => quoted.push_str(&format!("\\{b:03o}")),
+            b => quoted.push(b as char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Recursively walks a tree, returning every non-tree entry keyed by its full path
+pub(crate) fn flatten_tree(hash: &str) -> Result<BTreeMap<String, TreeEntry>> {
+    let mut entries = BTreeMap::new();
+    let mut pending = vec![(hash.to_string(), String::new())];
+
+    while let Some((hash, prefix)) = pending.pop() {
+        let obj = GitObject::load(&hash).with_context(|| format!("loading tree {hash}"))?;
+        let tree = Tree::try_from(&obj)?;
+
+        for entry in tree.entries {
+            let path = format!("{prefix}{}", entry.name_lossy());
+            if entry.mode.is_tree() {
+                pending.push((entry.hash, format!("{path}/")));
+            } else {
+                entries.insert(path, entry);
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Header names and values of a commit or tag, in the order they appear
+pub(crate) type Headers = Vec<(String, Vec<u8>)>;
+
+/// Splits a commit or tag body into its headers and message, both kept as
+/// bytes since neither has to be UTF-8 (see the `encoding` header).
+///
+/// Continuation lines (those starting with a space, as used by `gpgsig` and
+/// `mergetag`) are folded back into the value of the header they belong to.
+pub(crate) fn parse_headers(content: &[u8]) -> Result<(Headers, Vec<u8>)> {
+    let (raw_headers, message) = match content.windows(2).position(|pair| pair == b"\n\n") {
+        Some(end) => (&content[..end], &content[end + 2..]),
+        None => (content.strip_suffix(b"\n").unwrap_or(content), &[][..]),
+    };
+
+    let mut headers = Headers::new();
+    for line in raw_headers.split(|&b| b == b'\n') {
+        if let Some(continuation) = line.strip_prefix(b" ") {
+            let Some((_, value)) = headers.last_mut() else {
+                anyhow::bail!(
+                    "continuation line without a header: {}",
+                    String::from_utf8_lossy(line)
+                );
+            };
+            value.push(b'\n');
+            value.extend_from_slice(continuation);
+            continue;
+        }
+
+        let Some(space) = line.iter().position(|&b| b == b' ') else {
+            anyhow::bail!("malformed header line: {}", String::from_utf8_lossy(line));
+        };
+        let key = std::str::from_utf8(&line[..space]).context("header name is not valid utf-8")?;
+        headers.push((key.to_string(), line[space + 1..].to_vec()));
+    }
+
+    Ok((headers, message.to_vec()))
+}
+
+/// A header value that has to be text, such as an object name
+pub(crate) fn header_text(key: &str, value: Vec<u8>) -> Result<String> {
+    String::from_utf8(value).with_context(|| format!("{key} header is not valid utf-8"))
+}
+
+pub(crate) fn write_header(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+    out.extend_from_slice(key.as_bytes());
+    out.push(b' ');
+    for &b in value {
+        out.push(b);
+        if b == b'\n' {
+            out.push(b' ');
+        }
+    }
+    out.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::test_repository;
+
+    #[test]
+    fn loose_paths_need_a_full_object_id() {
+        let repository = test_repository();
+        let hash = "ab".repeat(20);
+        assert_eq!(
+            loose_path(&hash).unwrap(),
+            repository.git_dir().join("objects/ab").join(&hash[2..])
+        );
+
+        // Multibyte characters used to be sliced through and panic
+        for hash in ["", "a", "üü", &"g".repeat(40), &"ü".repeat(20)] {
+            assert!(loose_path(hash).is_err(), "{hash}");
+        }
+    }
 }
@@ -0,0 +1,244 @@
+use anyhow::{Context, Result};
+
+use std::collections::{BinaryHeap, HashSet};
+
+use super::{Commit, FileMode, GitObject, GitObjectType, Tag, Tree};
+
+/// An object found while walking history, with the path it was first reached by
+/// (empty for commits and tags)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ReachableObject {
+    pub(crate) hash: String,
+    pub(crate) obj_type: GitObjectType,
+    pub(crate) path: String,
+}
+
+/// Walks commit history newest first by committer date, like `git rev-list`
+#[derive(Debug, Default)]
+pub(crate) struct CommitWalker {
+    queue: BinaryHeap<(i64, String)>,
+    seen: HashSet<String>,
+}
+
+impl CommitWalker {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a starting point, peeling tags down to the commit they point at.
+    /// Anything that isn't a commit is ignored.
+    pub(crate) fn push(&mut self, hash: &str) -> Result<()> {
+        let mut obj = GitObject::load(hash).with_context(|| format!("loading {hash}"))?;
+        while obj.obj_type == GitObjectType::Tag {
+            let tag = Tag::try_from(&obj).with_context(|| format!("parsing tag {}", obj.hash))?;
+            obj =
+                GitObject::load(&tag.object).with_context(|| format!("loading {}", tag.object))?;
+        }
+
+        if obj.obj_type == GitObjectType::Commit {
+            let commit = Commit::try_from(&obj).with_context(|| format!("parsing {}", obj.hash))?;
+            self.enqueue(obj.hash, &commit);
+        }
+
+        Ok(())
+    }
+
+    /// Keeps a commit, and so the history behind it, out of the walk
+    pub(crate) fn hide(&mut self, hash: &str) {
+        self.seen.insert(hash.to_string());
+    }
+
+    /// The next commit in date order, queueing its parents behind it
+    pub(crate) fn next_commit(&mut self) -> Result<Option<(String, Commit)>> {
+        let Some((_, hash)) = self.queue.pop() else {
+            return Ok(None);
+        };
+
+        let commit = load_commit(&hash)?;
+        for parent in &commit.parents {
+            if self.seen.contains(parent) {
+                continue;
+            }
+            let parent_commit = load_commit(parent)?;
+            self.enqueue(parent.clone(), &parent_commit);
+        }
+
+        Ok(Some((hash, commit)))
+    }
+
+    fn enqueue(&mut self, hash: String, commit: &Commit) {
+        if self.seen.insert(hash.clone()) {
+            self.queue.push((commit.committer.time, hash));
+        }
+    }
+}
+
+/// Whether `ancestor` is reachable from `descendant` (a commit is its own ancestor)
+pub(crate) fn is_ancestor(ancestor: &str, descendant: &str) -> Result<bool> {
+    let mut walker = CommitWalker::new();
+    walker.push(descendant)?;
+    while let Some((hash, _)) = walker.next_commit()? {
+        if hash == ancestor {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Every object reachable from `tips` but not from `exclude`, as `git rev-list
+/// --objects` would list them: commits newest first, then their trees and blobs.
+/// Excluded objects we don't have locally are ignored.
+pub(crate) fn objects_between(tips: &[String], exclude: &[String]) -> Result<Vec<ReachableObject>> {
+    let mut exclude_walker = CommitWalker::new();
+    let mut excluded = HashSet::new();
+    for hash in exclude {
+        if GitObject::exists(hash)? {
+            excluded.insert(hash.clone());
+            exclude_walker.push(hash)?;
+        }
+    }
+
+    let mut hidden = HashSet::new();
+    while let Some((hash, _)) = exclude_walker.next_commit()? {
+        hidden.insert(hash);
+    }
+
+    let mut objects = Vec::new();
+    let mut walker = CommitWalker::new();
+    for hash in &hidden {
+        walker.hide(hash);
+    }
+
+    // Refs and tags can point straight at a tree or blob as well as a commit
+    let mut trees = Vec::new();
+    let mut blobs = Vec::new();
+    for tip in tips {
+        // Annotated tags are sent along with what they point at
+        let mut obj = GitObject::load(tip).with_context(|| format!("loading {tip}"))?;
+        while obj.obj_type == GitObjectType::Tag && excluded.insert(obj.hash.clone()) {
+            let tag = Tag::try_from(&obj).with_context(|| format!("parsing tag {}", obj.hash))?;
+            objects.push(ReachableObject {
+                hash: obj.hash.clone(),
+                obj_type: GitObjectType::Tag,
+                path: String::new(),
+            });
+            obj =
+                GitObject::load(&tag.object).with_context(|| format!("loading {}", tag.object))?;
+        }
+
+        match obj.obj_type {
+            GitObjectType::Commit => walker.push(&obj.hash)?,
+            GitObjectType::Tree => trees.push(obj.hash),
+            GitObjectType::Blob => blobs.push(obj.hash),
+            // A tag we've already been through
+            GitObjectType::Tag => {}
+        }
+    }
+
+    // Trees and blobs the other side has through the commits at the edge of
+    // what it has, so they aren't sent again
+    let mut seen: HashSet<String> = excluded;
+    let mut commits = Vec::new();
+    while let Some((hash, commit)) = walker.next_commit()? {
+        for parent in commit.parents.iter().filter(|p| hidden.contains(*p)) {
+            let edge = load_commit(parent)?;
+            mark_tree_seen(&edge.tree, &mut seen)?;
+        }
+        objects.push(ReachableObject {
+            hash,
+            obj_type: GitObjectType::Commit,
+            path: String::new(),
+        });
+        commits.push(commit);
+    }
+
+    for tree in commits.iter().map(|commit| &commit.tree).chain(&trees) {
+        collect_tree(tree, "", &mut seen, &mut objects)?;
+    }
+    for blob in blobs {
+        if seen.insert(blob.clone()) {
+            objects.push(ReachableObject {
+                hash: blob,
+                obj_type: GitObjectType::Blob,
+                path: String::new(),
+            });
+        }
+    }
+
+    Ok(objects)
+}
+
+fn mark_tree_seen(hash: &str, seen: &mut HashSet<String>) -> Result<()> {
+    if !seen.insert(hash.to_string()) {
+        return Ok(());
+    }
+
+    for entry in load_tree(hash)?.entries {
+        match entry.mode.canonical() {
+            FileMode::Directory => mark_tree_seen(&entry.hash, seen)?,
+            FileMode::Gitlink => {}
+            _ => {
+                seen.insert(entry.hash);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn collect_tree(
+    hash: &str,
+    path: &str,
+    seen: &mut HashSet<String>,
+    objects: &mut Vec<ReachableObject>,
+) -> Result<()> {
+    if !seen.insert(hash.to_string()) {
+        return Ok(());
+    }
+    objects.push(ReachableObject {
+        hash: hash.to_string(),
+        obj_type: GitObjectType::Tree,
+        path: path.to_string(),
+    });
+
+    for entry in load_tree(hash)?.entries {
+        let entry_path = if path.is_empty() {
+            entry.name_lossy().into_owned()
+        } else {
+            format!("{path}/{}", entry.name_lossy())
+        };
+
+        match entry.mode.canonical() {
+            FileMode::Directory => collect_tree(&entry.hash, &entry_path, seen, objects)?,
+            // Submodule commits live in another repository
+            FileMode::Gitlink => {}
+            _ => {
+                if seen.insert(entry.hash.clone()) {
+                    objects.push(ReachableObject {
+                        hash: entry.hash,
+                        obj_type: GitObjectType::Blob,
+                        path: entry_path,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn load_tree(hash: &str) -> Result<Tree> {
+    let obj = GitObject::load(hash).with_context(|| format!("loading tree {hash}"))?;
+    Tree::try_from(&obj).with_context(|| format!("parsing tree {hash}"))
+}
+
+fn load_commit(hash: &str) -> Result<Commit> {
+    let obj = GitObject::load(hash).with_context(|| format!("loading commit {hash}"))?;
+    anyhow::ensure!(
+        obj.obj_type == GitObjectType::Commit,
+        "{hash} is a {}, not a commit",
+        obj.obj_type
+    );
+    Commit::try_from(&obj).with_context(|| format!("parsing commit {hash}"))
+}
@@ -0,0 +1,250 @@
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, Bytes};
+use sha1::{Digest, Sha1};
+
+use std::path::Path;
+
+const IDX_SIGNATURE: &[u8; 4] = b"\xfftOc";
+const IDX_VERSION: u32 = 2;
+const LARGE_OFFSET_FLAG: u32 = 0x8000_0000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub(crate) hash: String,
+    pub(crate) crc32: u32,
+    pub(crate) offset: u64,
+}
+
+/// A version 2 pack index (`.idx`) mapping object ids to their offsets in a pack
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PackIndex {
+    /// Sorted by hash
+    pub(crate) entries: Vec<IndexEntry>,
+    pub(crate) pack_checksum: String,
+}
+
+impl PackIndex {
+    pub(crate) fn new(mut entries: Vec<IndexEntry>, pack_checksum: String) -> Self {
+        entries.sort_by(|a, b| a.hash.cmp(&b.hash));
+        entries.dedup_by(|a, b| a.hash == b.hash);
+
+        Self {
+            entries,
+            pack_checksum,
+        }
+    }
+
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let raw = std::fs::read(&path)
+            .with_context(|| format!("reading pack index {}", path.as_ref().display()))?;
+        Self::parse(Bytes::from(raw))
+    }
+
+    pub(crate) fn lookup(&self, hash: &str) -> Option<u64> {
+        self.entries
+            .binary_search_by(|entry| entry.hash.as_str().cmp(hash))
+            .ok()
+            .map(|idx| self.entries[idx].offset)
+    }
+
+    pub(crate) fn parse(mut raw: Bytes) -> Result<Self> {
+        anyhow::ensure!(raw.len() >= 8 + 256 * 4 + 40, "pack index is truncated");
+        let checksum = raw.split_off(raw.len() - 20);
+        let mut hasher = Sha1::new();
+        hasher.update(&raw);
+        anyhow::ensure!(
+            hasher.finalize()[..] == checksum[..],
+            "pack index checksum mismatch"
+        );
+
+        anyhow::ensure!(
+            &raw.split_to(4)[..] == IDX_SIGNATURE,
+            "not a valid pack index signature"
+        );
+        let version = raw.get_u32();
+        anyhow::ensure!(
+            version == IDX_VERSION,
+            "unsupported pack index version {version}"
+        );
+
+        let mut count = 0;
+        for _ in 0..256 {
+            let cumulative = raw.get_u32();
+            anyhow::ensure!(cumulative >= count, "pack index fanout is not monotonic");
+            count = cumulative;
+        }
+        let count = count as usize;
+
+        anyhow::ensure!(
+            raw.len() >= count * (20 + 4 + 4) + 20,
+            "pack index is truncated"
+        );
+        let hashes: Vec<_> = (0..count).map(|_| hex::encode(raw.split_to(20))).collect();
+        let crcs: Vec<_> = (0..count).map(|_| raw.get_u32()).collect();
+        let small_offsets: Vec<_> = (0..count).map(|_| raw.get_u32()).collect();
+        let large_count = small_offsets
+            .iter()
+            .filter(|ofs| *ofs & LARGE_OFFSET_FLAG != 0)
+            .count();
+
+        anyhow::ensure!(raw.len() == large_count * 8 + 20, "pack index is truncated");
+        let large_offsets: Vec<_> = (0..large_count).map(|_| raw.get_u64()).collect();
+        let pack_checksum = hex::encode(raw.split_to(20));
+
+        let mut entries = Vec::with_capacity(count);
+        for ((hash, crc32), offset) in hashes.into_iter().zip(crcs).zip(small_offsets) {
+            let offset = if offset & LARGE_OFFSET_FLAG != 0 {
+                let idx = (offset & !LARGE_OFFSET_FLAG) as usize;
+                *large_offsets
+                    .get(idx)
+                    .context("pack index large offset out of range")?
+            } else {
+                offset as u64
+            };
+
+            entries.push(IndexEntry {
+                hash,
+                crc32,
+                offset,
+            });
+        }
+
+        Ok(Self {
+            entries,
+            pack_checksum,
+        })
+    }
+
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        out.put_slice(IDX_SIGNATURE);
+        out.put_u32(IDX_VERSION);
+
+        let mut fanout = [0u32; 256];
+        for entry in &self.entries {
+            let first = u8::from_str_radix(&entry.hash[..2], 16)
+                .with_context(|| format!("invalid object id {}", entry.hash))?;
+            fanout[first as usize] += 1;
+        }
+
+        let mut cumulative = 0;
+        for count in fanout {
+            cumulative += count;
+            out.put_u32(cumulative);
+        }
+
+        for entry in &self.entries {
+            let hash = hex::decode(&entry.hash)
+                .with_context(|| format!("decoding object id {}", entry.hash))?;
+            out.put_slice(&hash);
+        }
+
+        for entry in &self.entries {
+            out.put_u32(entry.crc32);
+        }
+
+        let mut large_offsets = Vec::new();
+        for entry in &self.entries {
+            if entry.offset < LARGE_OFFSET_FLAG as u64 {
+                out.put_u32(entry.offset as u32);
+            } else {
+                out.put_u32(LARGE_OFFSET_FLAG | large_offsets.len() as u32);
+                large_offsets.push(entry.offset);
+            }
+        }
+
+        for offset in large_offsets {
+            out.put_u64(offset);
+        }
+
+        out.put_slice(&hex::decode(&self.pack_checksum).context("decoding pack checksum")?);
+
+        let mut hasher = Sha1::new();
+        hasher.update(&out);
+        out.put_slice(&hasher.finalize());
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pack::PackFile;
+
+    const IDX: &[u8] = include_bytes!("../../tests/fixtures/deltas.idx");
+    const PACK: &[u8] = include_bytes!("../../tests/fixtures/deltas.pack");
+
+    #[test]
+    fn reads_an_index_written_by_git() {
+        let index = PackIndex::parse(Bytes::from_static(IDX)).unwrap();
+
+        assert_eq!(index.entries.len(), 11);
+        assert_eq!(
+            index.pack_checksum,
+            "095edf4f5eed8ad28cf4edaa8510649ffd766a72"
+        );
+        assert_eq!(
+            index.lookup("6c720da77b6a97be8765f4c16485311d53d4ef2d"),
+            Some(12)
+        );
+        assert_eq!(
+            index.lookup("0a07d816472328688d81502f626c14f8eccbe3df"),
+            Some(1841)
+        );
+        assert_eq!(
+            index.lookup("0000000000000000000000000000000000000000"),
+            None
+        );
+    }
+
+    #[test]
+    fn writes_the_same_bytes_git_does() {
+        let index = PackIndex::parse(Bytes::from_static(IDX)).unwrap();
+        assert_eq!(index.serialize().unwrap(), IDX);
+    }
+
+    #[test]
+    fn indexing_a_pack_matches_git() {
+        let mut pack = PackFile::new("deltas", Bytes::from_static(PACK)).unwrap();
+        let index = pack.index().unwrap();
+        let expected = PackIndex::parse(Bytes::from_static(IDX)).unwrap();
+
+        assert_eq!(index.entries, expected.entries);
+        assert_eq!(index.pack_checksum, expected.pack_checksum);
+    }
+
+    #[test]
+    fn round_trips_large_offsets() {
+        let entries = vec![
+            IndexEntry {
+                hash: "1111111111111111111111111111111111111111".to_string(),
+                crc32: 1,
+                offset: 12,
+            },
+            IndexEntry {
+                hash: "ffffffffffffffffffffffffffffffffffffffff".to_string(),
+                crc32: 2,
+                offset: 5 << 32,
+            },
+        ];
+        let index = PackIndex::new(entries.clone(), "ab".repeat(20));
+
+        let parsed = PackIndex::parse(Bytes::from(index.serialize().unwrap())).unwrap();
+        assert_eq!(parsed.entries, entries);
+        assert_eq!(parsed.lookup(&entries[1].hash), Some(5 << 32));
+    }
+
+    #[test]
+    fn rejects_damaged_indexes() {
+        let truncated = Bytes::from_static(&IDX[..IDX.len() - 30]);
+        assert!(PackIndex::parse(truncated).is_err());
+
+        let mut flipped = IDX.to_vec();
+        flipped[100] ^= 1;
+        assert!(PackIndex::parse(Bytes::from(flipped)).is_err());
+
+        assert!(PackIndex::parse(Bytes::from_static(b"short")).is_err());
+    }
+}
@@ -1,11 +1,28 @@
+mod index;
+mod store;
+mod writer;
+
+use std::collections::HashMap;
 use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use bytes::{Buf, Bytes};
 use flate2::read::ZlibDecoder;
+use flate2::Crc;
+use memmap2::Mmap;
 use sha1::{Digest, Sha1};
 
-use crate::object::{GitObject, GitObjectType};
+use crate::object::{hash_object, GitObject, GitObjectType, MAX_PREALLOCATION};
+use crate::repository::git_path;
+pub(crate) use index::{IndexEntry, PackIndex};
+pub(crate) use store::{contains, find_object, stream_object};
+pub(crate) use writer::build_pack;
+
+pub(crate) fn pack_dir() -> PathBuf {
+    git_path("objects/pack")
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum DeltaInstruction {
@@ -13,6 +30,9 @@ enum DeltaInstruction {
     Copy { offset: usize, size: usize },
 }
 
+/// An undeltified entry's type and size, with a decoder over its data in the pack
+pub(crate) type PackedReader = (GitObjectType, usize, ZlibDecoder<Cursor<Bytes>>);
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) enum PackFileObject {
     Commit,
@@ -23,16 +43,42 @@ pub(crate) enum PackFileObject {
     RefDelta,
 }
 
-impl From<u8> for PackFileObject {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for PackFileObject {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(Self::Commit),
+            2 => Ok(Self::Tree),
+            3 => Ok(Self::Blob),
+            4 => Ok(Self::Tag),
+            6 => Ok(Self::OffsetDelta),
+            7 => Ok(Self::RefDelta),
+            _ => anyhow::bail!("invalid pack object type {value}"),
+        }
+    }
+}
+
+impl From<PackFileObject> for u8 {
+    fn from(value: PackFileObject) -> Self {
         match value {
-            1 => Self::Commit,
-            2 => Self::Tree,
-            3 => Self::Blob,
-            4 => Self::Tag,
-            6 => Self::OffsetDelta,
-            7 => Self::RefDelta,
-            _ => panic!("invalid value for packfile object type {value}"),
+            PackFileObject::Commit => 1,
+            PackFileObject::Tree => 2,
+            PackFileObject::Blob => 3,
+            PackFileObject::Tag => 4,
+            PackFileObject::OffsetDelta => 6,
+            PackFileObject::RefDelta => 7,
+        }
+    }
+}
+
+impl From<GitObjectType> for PackFileObject {
+    fn from(value: GitObjectType) -> Self {
+        match value {
+            GitObjectType::Commit => Self::Commit,
+            GitObjectType::Tree => Self::Tree,
+            GitObjectType::Blob => Self::Blob,
+            GitObjectType::Tag => Self::Tag,
         }
     }
 }
@@ -44,51 +90,182 @@ pub struct PackHeader {
     objects: u32,
 }
 
+/// Resolved delta bases are cached up to this many bytes before the cache is reset
+const BASE_CACHE_LIMIT: usize = 64 * 1024 * 1024;
+
 #[derive(Debug, Clone)]
 pub(crate) struct PackFile {
     pub(crate) id: String,
     pub(crate) header: PackHeader,
     pub(crate) content: Cursor<Bytes>,
+    pub(crate) checksum: String,
+    /// Offsets of objects from this pack whose id is already known, used to find
+    /// ref delta bases that live in the same pack
+    offsets: HashMap<String, u64>,
+    /// The pack's index once it's been stored, which finds the rest of them
+    index: Option<Arc<PackIndex>>,
+    /// Ref delta bases from outside the pack, supplied by whoever is reading it
+    external_bases: HashMap<String, (GitObjectType, Bytes)>,
+    base_cache: HashMap<u64, (GitObjectType, Bytes)>,
+    base_cache_size: usize,
 }
 
+/// A ref delta base that lives outside an indexed pack. It's reported rather
+/// than loaded, so that the pack needn't stay locked while it's looked up.
+#[derive(Debug)]
+pub(crate) struct MissingBase(pub(crate) String);
+
+impl std::fmt::Display for MissingBase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ref delta base {} is not in the pack", self.0)
+    }
+}
+
+impl std::error::Error for MissingBase {}
+
 impl PackFile {
-    pub(crate) fn new(id: &str, mut content: Bytes) -> Result<Self> {
+    /// Takes a pack received from elsewhere, checking its trailing checksum
+    pub(crate) fn new(id: &str, content: Bytes) -> Result<Self> {
+        let pack = Self::unverified(id, content)?;
+        pack.verify().context("verify pack checksum")?;
+
+        Ok(pack)
+    }
+
+    /// Maps a pack from disk without hashing it, leaving that to `verify`
+    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("opening packfile {}", path.as_ref().display()))?;
+        // SAFETY: packs are written under their final name and never modified
+        // in place, only replaced or removed
+        let map = unsafe { Mmap::map(&file) }
+            .with_context(|| format!("mapping packfile {}", path.as_ref().display()))?;
+        let id = path
+            .as_ref()
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default()
+            .to_string();
+
+        Self::unverified(&id, Bytes::from_owner(map))
+    }
+
+    /// Hashes the whole pack and compares it against the trailing checksum
+    pub(crate) fn verify(&self) -> Result<()> {
+        Self::verify_checksum(self.content.get_ref(), &self.checksum)
+    }
+
+    fn unverified(id: &str, mut content: Bytes) -> Result<Self> {
+        anyhow::ensure!(content.len() >= 32, "packfile is too short");
         let raw_content = content.split_to(content.len() - 20);
         let checksum = hex::encode(&content);
-        Self::verify_checksum(&raw_content, &checksum).context("verify pack checksum")?;
 
-        Ok(Self {
+        let mut pack = Self {
             id: id.to_string(),
             header: PackHeader::default(),
             content: Cursor::new(raw_content),
-        })
+            checksum,
+            offsets: HashMap::new(),
+            index: None,
+            external_bases: HashMap::new(),
+            base_cache: HashMap::new(),
+            base_cache_size: 0,
+        };
+        pack.validate_pack_header()
+            .context("packfile header validation")?;
+
+        Ok(pack)
     }
 
-    pub(crate) fn parse(&mut self) -> Result<()> {
-        self.validate_pack_header()
-            .context("packfile header validation")?;
+    /// Pairs the pack with its index, after which ref delta bases found in
+    /// neither are reported as `MissingBase` for the caller to `add_base`
+    pub(crate) fn set_index(&mut self, index: Arc<PackIndex>) {
+        self.index = Some(index);
+    }
+
+    pub(crate) fn add_base(&mut self, hash: String, obj_type: GitObjectType, data: Vec<u8>) {
+        self.external_bases
+            .insert(hash, (obj_type, Bytes::from(data)));
+    }
 
-        let mut parsed_objects = 0;
+    /// Resolves every object in the pack, producing the index used to look them up
+    pub(crate) fn index(&mut self) -> Result<PackIndex> {
+        // Every entry takes at least a byte, which bounds a bogus object count
+        let capacity = (self.header.objects as usize).min(self.content.get_ref().len());
+        let mut pending = Vec::with_capacity(capacity);
+        self.content.set_position(12);
         while self.content.has_remaining() {
-            let (object_type, size) = self.get_object_type_and_size();
+            let offset = self.content.position();
+            self.skip_entry()
+                .with_context(|| format!("scanning pack entry at offset {offset}"))?;
+
+            let raw = &self.content.get_ref()[offset as usize..self.content.position() as usize];
+            let mut crc = Crc::new();
+            crc.update(raw);
+            pending.push((offset, crc.sum()));
+        }
 
-            match object_type {
-                PackFileObject::RefDelta => {
-                    self.ref_delta(size).context("parsing ref delta object")?
-                }
-                PackFileObject::OffsetDelta => {
-                    self.ofs_delta().context("parsing ofs delta object")?
+        anyhow::ensure!(
+            pending.len() == self.header.objects as usize,
+            "expected {} objects in pack, found {}",
+            self.header.objects,
+            pending.len()
+        );
+
+        // Ref deltas may point at a base that appears later in the pack, so keep
+        // retrying anything that failed for as long as we're making progress
+        let mut entries = Vec::with_capacity(pending.len());
+        while !pending.is_empty() {
+            let attempted = pending.len();
+            let mut deferred = Vec::new();
+            let mut last_error = None;
+
+            for (offset, crc32) in pending {
+                match self.object_at(offset) {
+                    Ok((obj_type, data)) => {
+                        let hash = hash_object(obj_type, &data);
+                        self.offsets.insert(hash.clone(), offset);
+                        entries.push(IndexEntry {
+                            hash,
+                            crc32,
+                            offset,
+                        });
+                    }
+                    Err(e) => {
+                        deferred.push((offset, crc32));
+                        last_error = Some(e);
+                    }
                 }
-                _ => self
-                    .object(size, object_type)
-                    .context("parsing normal object")?,
             }
-            parsed_objects += 1;
+
+            if let Some(e) = last_error.filter(|_| deferred.len() == attempted) {
+                return Err(e.context("resolving pack entries"));
+            }
+            pending = deferred;
         }
 
-        anyhow::ensure!(parsed_objects == self.header.objects);
+        Ok(PackIndex::new(entries, self.checksum.clone()))
+    }
 
-        Ok(())
+    /// Writes the pack and a freshly generated index into `.git/objects/pack`,
+    /// returning the name they were stored under
+    pub(crate) fn store(&mut self) -> Result<String> {
+        let index = self.index().context("indexing pack")?;
+
+        let dir = pack_dir();
+        std::fs::create_dir_all(&dir).context("creating pack directory")?;
+        let name = format!("pack-{}", self.checksum);
+        let base = dir.join(&name);
+
+        let mut pack = self.content.get_ref().to_vec();
+        pack.extend(hex::decode(&self.checksum).context("decoding pack checksum")?);
+        std::fs::write(base.with_extension("pack"), pack).context("writing pack")?;
+
+        let index = index.serialize().context("serializing pack index")?;
+        std::fs::write(base.with_extension("idx"), index).context("writing pack index")?;
+        store::forget_missing();
+
+        Ok(name)
     }
 
     fn validate_pack_header(&mut self) -> Result<()> {
@@ -107,217 +284,497 @@ impl PackFile {
         Ok(())
     }
 
-    fn get_object_type_and_size(&mut self) -> (PackFileObject, usize) {
+    fn get_object_type_and_size(&mut self) -> Result<(PackFileObject, usize)> {
+        anyhow::ensure!(self.content.has_remaining(), "truncated pack entry header");
         let lead = self.content.get_u8();
-        let o_type = PackFileObject::from((lead & 0b0111_0000) >> 4);
+        let o_type = PackFileObject::try_from((lead & 0b0111_0000) >> 4)?;
         let mut size = (lead & 0b0000_1111) as usize;
 
         if (lead >> 7) & 1 == 0 {
-            return (o_type, size);
+            return Ok((o_type, size));
         }
 
-        let mut ofs = 0;
+        let mut shift = 4;
         loop {
+            anyhow::ensure!(self.content.has_remaining(), "truncated pack entry header");
+            anyhow::ensure!(shift < usize::BITS, "pack entry size is too large");
             let byte = self.content.get_u8();
-            size |= ((byte & 0b0111_1111) as usize) << (7 * ofs + 4);
-            ofs += 1;
+            size |= ((byte & 0b0111_1111) as usize) << shift;
+            shift += 7;
 
             if (byte >> 7) & 1 == 0 {
-                return (o_type, size);
+                return Ok((o_type, size));
             }
         }
     }
 
-    fn ref_delta(&mut self, expected_size: usize) -> Result<()> {
-        let mut buf = Vec::with_capacity(20);
-        for _ in 0..20 {
-            buf.push(self.content.get_u8());
+    /// Reads the entry at the current position, resolving any deltas so the
+    /// returned data is always the full object body.
+    fn entry(&mut self) -> Result<(GitObjectType, Vec<u8>)> {
+        let offset = self.content.position();
+        let (object_type, size) = self.get_object_type_and_size()?;
+
+        match object_type {
+            PackFileObject::RefDelta => self.ref_delta(size).context("parsing ref delta object"),
+            PackFileObject::OffsetDelta => self
+                .ofs_delta(offset, size)
+                .context("parsing ofs delta object"),
+            _ => self
+                .object(size, object_type)
+                .context("parsing normal object"),
         }
+    }
 
-        let base_name = hex::encode(&buf);
+    /// Moves past the entry at the current position without resolving it
+    fn skip_entry(&mut self) -> Result<()> {
+        let (object_type, size) = self.get_object_type_and_size()?;
+        match object_type {
+            PackFileObject::RefDelta => {
+                anyhow::ensure!(self.content.remaining() >= 20, "truncated ref delta base");
+                self.content.advance(20);
+            }
+            PackFileObject::OffsetDelta => {
+                self.get_ofs_delta_offset()?;
+            }
+            _ => {}
+        }
 
-        let mut delta = Vec::new();
         let mut decoder = ZlibDecoder::new(self.content.clone());
-        decoder
-            .read_to_end(&mut delta)
-            .context("decompressing ref delta data")?;
-
-        anyhow::ensure!(decoder.total_out() == expected_size as u64);
+        std::io::copy(&mut decoder, &mut std::io::sink()).context("decompressing entry")?;
+        anyhow::ensure!(decoder.total_out() == size as u64);
         self.content.advance(decoder.total_in() as usize);
 
-        let mut delta = Bytes::from(delta);
-        let _base_size = delta_size(&mut delta);
-        let _reconstructed_size = delta_size(&mut delta);
-        let instructions = delta_instructions(delta);
+        Ok(())
+    }
 
-        let base_obj = GitObject::load(&base_name)?;
-        let raw_data = base_obj.content;
-        let result = resolve_deltas(&raw_data, instructions);
-        let new_obj = GitObject::create_raw(&result, base_obj.obj_type)
-            .context("creating resolved ref delta object")?;
+    /// Resolves the entry at the given pack offset, leaving the current position untouched
+    pub(crate) fn object_at(&mut self, offset: u64) -> Result<(GitObjectType, Vec<u8>)> {
+        if let Some((obj_type, data)) = self.base_cache.get(&offset) {
+            return Ok((*obj_type, data.to_vec()));
+        }
 
-        new_obj
-            .write()
-            .context("writing resolved ref delta object")?;
+        anyhow::ensure!(
+            offset >= 12 && offset < self.content.get_ref().len() as u64,
+            "offset {offset} is outside of the pack"
+        );
 
-        Ok(())
+        let current_position = self.content.position();
+        self.content.set_position(offset);
+        let entry = self.entry();
+        self.content.set_position(current_position);
+
+        let (obj_type, data) = entry?;
+        if self.base_cache_size + data.len() > BASE_CACHE_LIMIT {
+            self.base_cache.clear();
+            self.base_cache_size = 0;
+        }
+        if data.len() <= BASE_CACHE_LIMIT {
+            self.base_cache_size += data.len();
+            self.base_cache
+                .insert(offset, (obj_type, Bytes::from(data.clone())));
+        }
+
+        Ok((obj_type, data))
     }
 
-    fn ofs_delta(&mut self) -> Result<()> {
-        let ofs = self.get_ofs_delta_offset()?;
+    /// Opens a reader over a whole entry's compressed data in the pack, so it can
+    /// be read without decompressing it all at once. Deltas give `None` as they
+    /// can only be resolved in memory.
+    pub(crate) fn reader_at(&mut self, offset: u64) -> Result<Option<PackedReader>> {
+        anyhow::ensure!(
+            offset >= 12 && offset < self.content.get_ref().len() as u64,
+            "offset {offset} is outside of the pack"
+        );
+
         let current_position = self.content.position();
-        self.content.set_position(current_position - ofs);
+        self.content.set_position(offset);
+        let header = self.get_object_type_and_size();
+        let data_start = self.content.position() as usize;
+        self.content.set_position(current_position);
+        let (object_type, size) = header?;
+
+        let obj_type = match object_type {
+            PackFileObject::Blob => GitObjectType::Blob,
+            PackFileObject::Commit => GitObjectType::Commit,
+            PackFileObject::Tree => GitObjectType::Tree,
+            PackFileObject::Tag => GitObjectType::Tag,
+            PackFileObject::RefDelta | PackFileObject::OffsetDelta => return Ok(None),
+        };
 
-        let mut content = Vec::new();
-        let mut decoder = ZlibDecoder::new(self.content.clone());
-        decoder
-            .read_to_end(&mut content)
-            .context("decompressing offset delta stream")?;
+        let data = self.content.get_ref().slice(data_start..);
+        Ok(Some((obj_type, size, ZlibDecoder::new(Cursor::new(data)))))
+    }
 
-        self.content.set_position(current_position);
+    fn ref_delta(&mut self, expected_size: usize) -> Result<(GitObjectType, Vec<u8>)> {
+        anyhow::ensure!(self.content.remaining() >= 20, "truncated ref delta base");
+        let base_name = hex::encode(self.content.copy_to_bytes(20));
+        let delta = self
+            .decompress(expected_size)
+            .context("decompressing ref delta data")?;
 
-        // TODO: Determine correct object type
+        let in_pack = self.offsets.get(&base_name).copied().or_else(|| {
+            self.index
+                .as_ref()
+                .and_then(|index| index.lookup(&base_name))
+        });
+        let (obj_type, base) = match (in_pack, self.external_bases.get(&base_name)) {
+            (Some(offset), _) => self.object_at(offset)?,
+            (None, Some((obj_type, data))) => (*obj_type, data.to_vec()),
+            (None, None) if self.index.is_some() => return Err(MissingBase(base_name).into()),
+            (None, None) => {
+                let base_obj = GitObject::load(&base_name)?;
+                (base_obj.obj_type, base_obj.content)
+            }
+        };
+        let result = apply_delta(&base, delta).context("applying ref delta")?;
 
-        Ok(())
+        Ok((obj_type, result))
+    }
+
+    fn ofs_delta(&mut self, offset: u64, expected_size: usize) -> Result<(GitObjectType, Vec<u8>)> {
+        let ofs = self.get_ofs_delta_offset()?;
+        // The base always comes earlier in the pack, so a zero offset would
+        // make the delta its own base
+        anyhow::ensure!(
+            ofs > 0 && ofs <= offset,
+            "offset delta at {offset} has an invalid base offset {ofs}"
+        );
+
+        let delta = self
+            .decompress(expected_size)
+            .context("decompressing offset delta data")?;
+
+        let base_offset = offset - ofs;
+        let (obj_type, base) = self
+            .object_at(base_offset)
+            .with_context(|| format!("resolving offset delta base at {base_offset}"))?;
+        let result = apply_delta(&base, delta).context("applying offset delta")?;
+
+        Ok((obj_type, result))
+    }
+
+    fn object(
+        &mut self,
+        size: usize,
+        object_type: PackFileObject,
+    ) -> Result<(GitObjectType, Vec<u8>)> {
+        let content = self.decompress(size).context("decompressing object")?;
+
+        let obj_type = match object_type {
+            PackFileObject::Blob => GitObjectType::Blob,
+            PackFileObject::Commit => GitObjectType::Commit,
+            PackFileObject::Tree => GitObjectType::Tree,
+            PackFileObject::Tag => GitObjectType::Tag,
+            _ => anyhow::bail!("{object_type:?} is not a whole object"),
+        };
+
+        Ok((obj_type, content))
     }
 
-    fn object(&mut self, size: usize, object_type: PackFileObject) -> Result<()> {
-        let mut content = Vec::with_capacity(size as usize);
+    fn decompress(&mut self, expected_size: usize) -> Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(expected_size.min(MAX_PREALLOCATION));
         let mut decoder = ZlibDecoder::new(self.content.clone());
-        decoder
+        // One byte past the declared size is enough to tell it was wrong
+        (&mut decoder)
+            .take(expected_size as u64 + 1)
             .read_to_end(&mut content)
-            .context("decompressing object")?;
+            .context("inflating pack entry")?;
 
-        anyhow::ensure!(decoder.total_out() == size as u64);
+        anyhow::ensure!(
+            content.len() == expected_size,
+            "pack entry does not inflate to the {expected_size} bytes its header declares"
+        );
         self.content.advance(decoder.total_in() as usize);
 
-        let object = match object_type {
-            PackFileObject::Blob => GitObject::create_raw(&content, GitObjectType::Blob)?,
-            PackFileObject::Commit => GitObject::create_raw(&content, GitObjectType::Commit)?,
-            PackFileObject::Tree => GitObject::create_raw(&content, GitObjectType::Tree)?,
-            PackFileObject::Tag => GitObject::create_raw(&content, GitObjectType::Tag)?,
-            _ => unimplemented!("unknown type {object_type:?}"),
-        };
-
-        object
-            .write()
-            .with_context(|| format!("writing object {} from pack", object.hash))?;
-
-        Ok(())
+        Ok(content)
     }
 
     fn get_ofs_delta_offset(&mut self) -> Result<u64> {
-        let mut ofs = 0;
-        loop {
-            let byte = self.content.get_u8();
-            ofs |= (ofs << 7) | byte as u64;
-            if (byte >> 7) & 1 == 0 {
-                return Ok(ofs);
-            }
-
-            ofs += 1;
+        anyhow::ensure!(self.content.has_remaining(), "truncated delta offset");
+        let mut byte = self.content.get_u8();
+        let mut ofs = (byte & 0b0111_1111) as u64;
+        while (byte >> 7) & 1 == 1 {
+            anyhow::ensure!(self.content.has_remaining(), "truncated delta offset");
+            anyhow::ensure!(ofs < 1 << 56, "delta offset overflows");
+            byte = self.content.get_u8();
+            ofs = ((ofs + 1) << 7) | (byte & 0b0111_1111) as u64;
         }
+
+        Ok(ofs)
     }
 
     fn verify_checksum(content: &Bytes, checksum: &str) -> Result<()> {
         let mut encoder = Sha1::new();
-        encoder.update(&content);
+        encoder.update(content);
         let check = encoder.finalize();
         let check = hex::encode(check);
-        anyhow::ensure!(&check == checksum);
+        anyhow::ensure!(
+            check == checksum,
+            "pack hashes to {check}, but its trailer says {checksum}"
+        );
 
         Ok(())
     }
 }
 
-fn delta_size(delta: &mut Bytes) -> u64 {
+fn delta_size(delta: &mut Bytes) -> Result<u64> {
     let mut size = 0;
-    let mut idx = 0;
+    let mut shift = 0;
 
     loop {
+        anyhow::ensure!(delta.has_remaining(), "truncated delta size");
+        anyhow::ensure!(shift < 64, "delta size overflows");
         let byte = delta.get_u8();
-        size |= ((byte & 0b0111_1111) as u64) << (7 * idx);
+        size |= ((byte & 0b0111_1111) as u64) << shift;
 
         if (byte >> 7) & 1 == 0 {
             break;
         }
 
-        idx += 1;
+        shift += 7;
     }
 
-    size
+    Ok(size)
 }
 
-fn delta_instructions(mut delta: Bytes) -> Vec<DeltaInstruction> {
+fn delta_instructions(mut delta: Bytes) -> Result<Vec<DeltaInstruction>> {
     let mut instructions = Vec::new();
 
-    // TODO: Cleanup
     while !delta.is_empty() {
         let lead = delta.get_u8();
 
-        if (lead >> 7) & 1 == 0 {
+        if lead == 0 {
+            anyhow::bail!("delta uses the reserved instruction 0");
+        } else if (lead >> 7) & 1 == 0 {
             // Data instruction
+            anyhow::ensure!(
+                delta.remaining() >= lead as usize,
+                "delta data instruction runs past the end of the delta"
+            );
             instructions.push(DeltaInstruction::Data {
                 data: delta.split_to(lead as usize),
             });
         } else {
-            // Copy instruction
-            let mut offset: usize = 0;
-            let mut size: usize = 0;
-
-            if lead & 1 == 1 {
-                let byte = delta.get_u8();
-                offset |= byte as usize;
-            }
-
-            if (lead >> 1) & 1 == 1 {
-                let byte = delta.get_u8();
-                offset |= (byte as usize) << 8;
-            }
-
-            if (lead >> 2) & 1 == 1 {
-                let byte = delta.get_u8();
-                offset |= (byte as usize) << 16;
-            }
-
-            if (lead >> 3) & 1 == 1 {
-                let byte = delta.get_u8();
-                offset |= (byte as usize) << 24;
-            }
-
-            if (lead >> 4) & 1 == 1 {
-                let byte = delta.get_u8();
-                size |= byte as usize;
-            }
-
-            if (lead >> 5) & 1 == 1 {
-                let byte = delta.get_u8();
-                size |= (byte as usize) << 8;
+            // Copy instruction: bits 0-3 say which offset bytes follow and
+            // bits 4-6 which size bytes, least significant first
+            let mut fields = [0usize; 2];
+            for bit in 0..7 {
+                if (lead >> bit) & 1 == 0 {
+                    continue;
+                }
+                anyhow::ensure!(
+                    delta.has_remaining(),
+                    "delta copy instruction runs past the end of the delta"
+                );
+                let (field, shift) = if bit < 4 { (0, bit) } else { (1, bit - 4) };
+                fields[field] |= (delta.get_u8() as usize) << (8 * shift);
             }
+            let [offset, mut size] = fields;
 
-            if (lead >> 6) & 1 == 1 {
-                let byte = delta.get_u8();
-                size |= (byte as usize) << 16;
+            // A size of zero is shorthand for 0x10000
+            if size == 0 {
+                size = 0x10000;
             }
 
             instructions.push(DeltaInstruction::Copy { offset, size });
         }
     }
 
-    instructions
+    Ok(instructions)
+}
+
+fn apply_delta(base: &[u8], delta: Vec<u8>) -> Result<Vec<u8>> {
+    let mut delta = Bytes::from(delta);
+    let base_size = delta_size(&mut delta)?;
+    anyhow::ensure!(
+        base_size == base.len() as u64,
+        "delta base size mismatch: expected {base_size}, got {}",
+        base.len()
+    );
+    let reconstructed_size = delta_size(&mut delta)?;
+    let instructions = delta_instructions(delta)?;
+    let result = resolve_deltas(base, instructions)?;
+    anyhow::ensure!(
+        reconstructed_size == result.len() as u64,
+        "delta result size mismatch: expected {reconstructed_size}, got {}",
+        result.len()
+    );
+
+    Ok(result)
 }
 
-fn resolve_deltas(obj: &[u8], instructions: Vec<DeltaInstruction>) -> Vec<u8> {
+fn resolve_deltas(obj: &[u8], instructions: Vec<DeltaInstruction>) -> Result<Vec<u8>> {
     let mut result = Vec::new();
 
     for instr in instructions {
         match instr {
             DeltaInstruction::Data { data } => result.extend_from_slice(&data),
             DeltaInstruction::Copy { offset, size } => {
-                result.extend_from_slice(&obj[offset..offset + size])
+                let copied = offset
+                    .checked_add(size)
+                    .and_then(|end| obj.get(offset..end))
+                    .with_context(|| {
+                        format!(
+                            "delta copies {size} bytes from offset {offset} of a {} byte base",
+                            obj.len()
+                        )
+                    })?;
+                result.extend_from_slice(copied);
             }
         }
     }
 
-    result
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACK: &[u8] = include_bytes!("../../tests/fixtures/deltas.pack");
+
+    /// A pack header for `objects` entries followed by `entries`, with its checksum
+    fn seal(objects: u32, entries: &[u8]) -> Bytes {
+        let mut pack = b"PACK\0\0\0\x02".to_vec();
+        pack.extend(objects.to_be_bytes());
+        pack.extend(entries);
+        pack.extend(Sha1::digest(&pack));
+        Bytes::from(pack)
+    }
+
+    fn index(pack: Bytes) -> Result<PackIndex> {
+        PackFile::new("test", pack)?.index()
+    }
+
+    #[test]
+    fn resolves_every_object_in_a_pack_written_by_git() {
+        let mut pack = PackFile::new("deltas", Bytes::from_static(PACK)).unwrap();
+        let index = pack.index().unwrap();
+
+        assert_eq!(index.entries.len(), 11);
+        for entry in &index.entries {
+            let (obj_type, data) = pack.object_at(entry.offset).unwrap();
+            assert_eq!(hash_object(obj_type, &data), entry.hash);
+        }
+    }
+
+    #[test]
+    fn applies_copy_and_data_instructions() {
+        // Base size 11, result size 6, copy 5 bytes from offset 6, insert "!"
+        let delta = vec![11, 6, 0b1001_0001, 6, 5, 1, b'!'];
+        assert_eq!(apply_delta(b"hello world", delta).unwrap(), b"world!");
+    }
+
+    #[test]
+    fn copy_size_of_zero_means_64_kib() {
+        let instructions = delta_instructions(Bytes::from_static(&[0x80])).unwrap();
+        assert_eq!(
+            instructions,
+            vec![DeltaInstruction::Copy {
+                offset: 0,
+                size: 0x10000
+            }]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_deltas() {
+        let base = b"hello world";
+        let malformed: &[&[u8]] = &[
+            // Base size doesn't match
+            &[10, 5, 0b1001_0001, 6, 5],
+            // Result size doesn't match
+            &[11, 4, 0b1001_0001, 6, 5],
+            // Copy runs past the end of the base
+            &[11, 6, 0b1001_0001, 6, 6],
+            // Copy offset byte is missing
+            &[11, 5, 0b1001_0001],
+            // Data runs past the end of the delta
+            &[11, 5, 5, b'a'],
+            // Reserved instruction
+            &[11, 0, 0],
+            // Truncated and overlong sizes
+            &[0x80],
+            &[
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+            ],
+        ];
+
+        for delta in malformed {
+            assert!(apply_delta(base, delta.to_vec()).is_err(), "{delta:?}");
+        }
+    }
+
+    #[test]
+    fn damaged_packs_are_errors_not_panics() {
+        let damaged: &[&[u8]] = &[
+            // Object types 0 and 5 are reserved
+            &[0x00],
+            &[0x50],
+            // Size continues past the end of the pack
+            &[0xb5],
+            &[
+                0xb5, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            ],
+            // Ref delta base id is cut short
+            &[0x75, 0xaa, 0xbb, 0xcc],
+            // Offset delta whose base would be before the start of the pack
+            &[0x65, 0x7f],
+            // Offset delta pointing at itself
+            &[0x65, 0x00],
+            // Not zlib data
+            &[0x35, 0xde, 0xad, 0xbe, 0xef],
+        ];
+
+        for entries in damaged {
+            assert!(index(seal(1, entries)).is_err(), "{entries:?}");
+        }
+        assert!(PackFile::new("test", Bytes::from_static(b"PACK")).is_err());
+    }
+
+    #[test]
+    fn entries_must_inflate_to_their_declared_size() {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        std::io::Write::write_all(&mut encoder, b"hello").unwrap();
+        let deflated = encoder.finish().unwrap();
+
+        // Blobs declaring 1, 5 and 6 bytes
+        for (lead, ok) in [(0x31, false), (0x35, true), (0x36, false)] {
+            let mut entry = vec![lead];
+            entry.extend(&deflated);
+            assert_eq!(index(seal(1, &entry)).is_ok(), ok, "{lead:#x}");
+        }
+    }
+
+    #[test]
+    fn truncated_packs_are_errors_not_panics() {
+        let content = &PACK[..PACK.len() - 20];
+        for len in (12..content.len()).step_by(7) {
+            let objects = u32::from_be_bytes(content[8..12].try_into().unwrap());
+            assert!(index(seal(objects, &content[12..len])).is_err(), "{len}");
+        }
+    }
+
+    #[test]
+    fn unpacking_objects_checks_their_type() {
+        let mut pack = PackFile::new("deltas", Bytes::from_static(PACK)).unwrap();
+        assert!(pack.object(0, PackFileObject::OffsetDelta).is_err());
+    }
+
+    #[test]
+    fn opening_a_pack_leaves_the_checksum_to_verify() {
+        let mut damaged = PACK.to_vec();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        let path = std::env::temp_dir().join(format!("damaged-{}.pack", std::process::id()));
+        std::fs::write(&path, &damaged).unwrap();
+
+        let opened = PackFile::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        let mut pack = opened.unwrap();
+        assert!(pack.verify().is_err());
+        assert!(pack.index().is_ok());
+
+        assert!(PackFile::new("damaged", Bytes::from(damaged)).is_err());
+    }
 }
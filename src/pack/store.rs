@@ -0,0 +1,163 @@
+use anyhow::{Context, Result};
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use super::{pack_dir, MissingBase, PackFile, PackIndex, PackedReader};
+use crate::object::{GitObject, GitObjectType};
+
+#[derive(Debug)]
+struct LoadedPack {
+    path: PathBuf,
+    index: Arc<PackIndex>,
+    /// Shared so every lookup benefits from the pack's delta base cache
+    pack: Mutex<PackFile>,
+}
+
+/// Packs are mapped once per process
+static PACKS: Mutex<Vec<Arc<LoadedPack>>> = Mutex::new(Vec::new());
+
+/// Hashes no pack had after a refresh, so repeated misses (loose objects,
+/// existence checks) don't rescan the pack directory every time
+static MISSING: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Looks up an object in the packs under `.git/objects/pack`, resolving deltas
+pub(crate) fn find_object(hash: &str) -> Result<Option<(GitObjectType, Vec<u8>)>> {
+    let packs = packs_for(hash)?;
+
+    for loaded in packs {
+        let Some(offset) = loaded.index.lookup(hash) else {
+            continue;
+        };
+
+        loop {
+            let result = loaded.pack.lock().expect("pack poisoned").object_at(offset);
+            let missing = match &result {
+                Ok(_) => None,
+                Err(e) => e.chain().find_map(|e| e.downcast_ref::<MissingBase>()),
+            };
+
+            // Bases outside the pack are looked up without holding its lock, as
+            // they may come from another pack or even lead back to this one
+            let Some(MissingBase(base)) = missing else {
+                let object = result
+                    .with_context(|| format!("reading {hash} from {}", loaded.path.display()))?;
+                return Ok(Some(object));
+            };
+            let base = base.clone();
+            let obj = GitObject::load(&base)
+                .with_context(|| format!("loading ref delta base {base} of {hash}"))?;
+            loaded
+                .pack
+                .lock()
+                .expect("pack poisoned")
+                .add_base(base, obj.obj_type, obj.content);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Opens a reader over an object stored whole in a pack, giving `None` if it
+/// isn't packed or is stored as a delta, which `find_object` has to resolve
+pub(crate) fn stream_object(hash: &str) -> Result<Option<PackedReader>> {
+    let packs = packs_for(hash)?;
+
+    let Some((loaded, offset)) = packs
+        .iter()
+        .find_map(|p| p.index.lookup(hash).map(|offset| (p, offset)))
+    else {
+        return Ok(None);
+    };
+
+    let reader = loaded.pack.lock().expect("pack poisoned").reader_at(offset);
+    reader.with_context(|| format!("reading {hash} from {}", loaded.path.display()))
+}
+
+/// Whether any pack under `.git/objects/pack` has an object, without reading it
+pub(crate) fn contains(hash: &str) -> Result<bool> {
+    let packs = packs_for(hash)?;
+    Ok(packs.iter().any(|p| p.index.lookup(hash).is_some()))
+}
+
+/// Called once a pack is written so earlier misses get looked up again
+pub(crate) fn forget_missing() {
+    *MISSING.lock().expect("missing cache poisoned") = None;
+}
+
+/// The loaded packs, rescanning the pack directory first if none of them has
+/// `hash` and it hasn't already been missed
+fn packs_for(hash: &str) -> Result<Vec<Arc<LoadedPack>>> {
+    let packs = loaded_packs();
+    if packs.iter().any(|p| p.index.lookup(hash).is_some()) {
+        return Ok(packs);
+    }
+
+    let mut missing = MISSING.lock().expect("missing cache poisoned");
+    if missing
+        .as_ref()
+        .is_some_and(|missing| missing.contains(hash))
+    {
+        return Ok(packs);
+    }
+
+    let packs = refresh_packs().context("loading packs")?;
+    if !packs.iter().any(|p| p.index.lookup(hash).is_some()) {
+        missing
+            .get_or_insert_with(HashSet::new)
+            .insert(hash.to_string());
+    }
+
+    Ok(packs)
+}
+
+fn loaded_packs() -> Vec<Arc<LoadedPack>> {
+    PACKS.lock().expect("pack cache poisoned").clone()
+}
+
+/// Picks up any packs written since the cache was last populated
+fn refresh_packs() -> Result<Vec<Arc<LoadedPack>>> {
+    let dir = pack_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut idx_paths = Vec::new();
+    for entry in std::fs::read_dir(&dir).context("reading pack directory")? {
+        let path = entry.context("bad pack directory entry")?.path();
+        if path.extension().is_some_and(|ext| ext == "idx") {
+            idx_paths.push(path);
+        }
+    }
+
+    let mut packs = PACKS.lock().expect("pack cache poisoned");
+    packs.retain(|p| p.path.exists());
+
+    for idx_path in idx_paths {
+        let path = std::fs::canonicalize(idx_path.with_extension("pack"))
+            .with_context(|| format!("finding pack for {}", idx_path.display()))?;
+        if packs.iter().any(|p| p.path == path) {
+            continue;
+        }
+
+        let index = Arc::new(PackIndex::load(&idx_path)?);
+        let mut pack = PackFile::open(&path)?;
+        anyhow::ensure!(
+            index.pack_checksum == pack.checksum,
+            "{} does not belong to {}",
+            idx_path.display(),
+            path.display()
+        );
+
+        pack.set_index(index.clone());
+
+        packs.push(Arc::new(LoadedPack {
+            path,
+            index,
+            pack: Mutex::new(pack),
+        }));
+    }
+
+    Ok(packs.clone())
+}
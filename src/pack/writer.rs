@@ -0,0 +1,450 @@
+use anyhow::{Context, Result};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use sha1::{Digest, Sha1};
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+
+use super::PackFileObject;
+use crate::object::{GitObject, GitObjectType, ObjectReader, ReachableObject};
+
+/// How many similar objects each object is compared against when looking for a delta base
+const WINDOW: usize = 10;
+
+/// Longest chain of deltas allowed before an object must be stored whole
+const MAX_DEPTH: usize = 50;
+
+/// Objects smaller than this aren't worth deltifying
+const MIN_DELTA_SIZE: usize = 64;
+
+/// Base blocks of this size are indexed to find copies
+const BLOCK: usize = 16;
+
+/// Largest copy a single instruction can express with three size bytes
+const MAX_COPY: usize = 0xff_ffff;
+
+/// Copy offsets are four bytes, so only the start of a larger base can be copied from
+const MAX_COPY_OFFSET: usize = u32::MAX as usize;
+
+/// An object to be packed, along with the delta chosen for it. Contents are
+/// only loaded while an object is in the delta window or being written out.
+#[derive(Debug)]
+struct Entry {
+    hash: String,
+    obj_type: GitObjectType,
+    name_hash: u32,
+    size: usize,
+    /// The entry this one is stored as a delta against, and the delta itself
+    delta: Option<(usize, Vec<u8>)>,
+    depth: usize,
+}
+
+/// Builds a version 2 pack holding the given objects, storing objects as deltas
+/// against similar ones where that saves space. Bases are referenced by offset
+/// when `ofs_delta` is set and by object id otherwise.
+pub(crate) fn build_pack(objects: &[ReachableObject], ofs_delta: bool) -> Result<Vec<u8>> {
+    let count = u32::try_from(objects.len()).context("too many objects for one pack")?;
+
+    let mut entries = Vec::with_capacity(objects.len());
+    for object in objects {
+        let reader =
+            ObjectReader::open(&object.hash).with_context(|| format!("opening {}", object.hash))?;
+        entries.push(Entry {
+            hash: object.hash.clone(),
+            obj_type: reader.obj_type,
+            name_hash: name_hash(&object.path),
+            size: reader.size,
+            delta: None,
+            depth: 0,
+        });
+    }
+
+    find_deltas(&mut entries)?;
+
+    let mut pack = Vec::new();
+    pack.extend_from_slice(b"PACK");
+    pack.extend_from_slice(&2u32.to_be_bytes());
+    pack.extend_from_slice(&count.to_be_bytes());
+
+    // Deltas must come after their base for offset references, so bases are
+    // written early when needed
+    let mut offsets: Vec<Option<usize>> = vec![None; entries.len()];
+    for idx in 0..entries.len() {
+        write_entry(&mut pack, &entries, &mut offsets, idx, ofs_delta)?;
+    }
+
+    let checksum = Sha1::digest(&pack);
+    pack.extend_from_slice(&checksum);
+
+    Ok(pack)
+}
+
+/// Compares each object against the few before it once sorted so that objects
+/// of the same type with the same name and similar sizes sit next to each other,
+/// holding only those few in memory at a time
+fn find_deltas(entries: &mut [Entry]) -> Result<()> {
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by(|&a, &b| {
+        let (a, b) = (&entries[a], &entries[b]);
+        (a.obj_type as u8)
+            .cmp(&(b.obj_type as u8))
+            .then(a.name_hash.cmp(&b.name_hash))
+            .then(b.size.cmp(&a.size))
+    });
+
+    let mut window: VecDeque<(usize, Vec<u8>)> = VecDeque::with_capacity(WINDOW + 1);
+    for target in order {
+        let hash = &entries[target].hash;
+        let content = GitObject::load(hash)
+            .with_context(|| format!("loading {hash}"))?
+            .content;
+
+        if let Some((base, delta)) = best_delta(entries, &window, target, &content) {
+            entries[target].depth = entries[base].depth + 1;
+            entries[target].delta = Some((base, delta));
+        }
+
+        window.push_back((target, content));
+        if window.len() > WINDOW {
+            window.pop_front();
+        }
+    }
+
+    Ok(())
+}
+
+/// The smallest delta for `target` against any object in the window, if one is
+/// worth storing
+fn best_delta(
+    entries: &[Entry],
+    window: &VecDeque<(usize, Vec<u8>)>,
+    target: usize,
+    content: &[u8],
+) -> Option<(usize, Vec<u8>)> {
+    if content.len() < MIN_DELTA_SIZE {
+        return None;
+    }
+
+    let mut best: Option<(usize, Vec<u8>)> = None;
+    for (base, base_content) in window.iter().rev() {
+        let (base_entry, target_entry) = (&entries[*base], &entries[target]);
+        if base_entry.obj_type != target_entry.obj_type || base_entry.depth >= MAX_DEPTH {
+            continue;
+        }
+
+        // Only worth it if the delta is well under half the object's size,
+        // and smaller than anything found so far
+        let limit = best
+            .as_ref()
+            .map_or(content.len() / 2 - 20, |(_, delta)| delta.len());
+        if base_content.len().abs_diff(content.len()) >= limit {
+            continue;
+        }
+
+        if let Some(delta) = encode_delta(base_content, content, limit) {
+            best = Some((*base, delta));
+        }
+    }
+
+    best
+}
+
+fn write_entry(
+    pack: &mut Vec<u8>,
+    entries: &[Entry],
+    offsets: &mut [Option<usize>],
+    idx: usize,
+    ofs_delta: bool,
+) -> Result<()> {
+    if offsets[idx].is_some() {
+        return Ok(());
+    }
+
+    let entry = &entries[idx];
+    let whole;
+    let (data, base) = match &entry.delta {
+        Some((base, delta)) => {
+            write_entry(pack, entries, offsets, *base, ofs_delta)?;
+            (delta.as_slice(), Some(*base))
+        }
+        None => {
+            whole = GitObject::load(&entry.hash)
+                .with_context(|| format!("loading {}", entry.hash))?
+                .content;
+            (whole.as_slice(), None)
+        }
+    };
+
+    let offset = pack.len();
+    offsets[idx] = Some(offset);
+
+    match base {
+        Some(base) if ofs_delta => {
+            let base_offset = offsets[base].expect("delta base written first");
+            write_entry_header(pack, PackFileObject::OffsetDelta, data.len());
+            write_base_offset(pack, offset - base_offset);
+        }
+        Some(base) => {
+            write_entry_header(pack, PackFileObject::RefDelta, data.len());
+            pack.extend(hex::decode(&entries[base].hash).context("decoding base id")?);
+        }
+        None => {
+            write_entry_header(pack, PackFileObject::from(entry.obj_type), data.len());
+        }
+    }
+
+    let mut encoder = ZlibEncoder::new(pack, Compression::default());
+    encoder
+        .write_all(data)
+        .and_then(|_| encoder.finish().map(|_| ()))
+        .with_context(|| format!("compressing {}", entry.hash))
+}
+
+/// The type and inflated size of an entry: three type bits and the low four size
+/// bits in the first byte, then seven more size bits per byte
+fn write_entry_header(pack: &mut Vec<u8>, obj_type: PackFileObject, size: usize) {
+    let mut byte = (u8::from(obj_type) << 4) | (size & 0x0f) as u8;
+    let mut size = size >> 4;
+    while size > 0 {
+        pack.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    pack.push(byte);
+}
+
+/// The distance back to an offset delta's base, most significant group first,
+/// with each continuation adding one to avoid redundant encodings
+fn write_base_offset(pack: &mut Vec<u8>, mut distance: usize) {
+    let mut encoded = vec![(distance & 0x7f) as u8];
+    distance >>= 7;
+    while distance > 0 {
+        distance -= 1;
+        encoded.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    encoded.reverse();
+    pack.extend(encoded);
+}
+
+/// Git's path name hash, which groups files by their last few characters (and
+/// so by extension) when sorting for delta candidates
+fn name_hash(path: &str) -> u32 {
+    path.bytes()
+        .filter(|b| !b.is_ascii_whitespace())
+        .fold(0u32, |hash, b| (hash >> 2).wrapping_add((b as u32) << 24))
+}
+
+/// Encodes `target` as copy and insert instructions against `base`, giving up
+/// once the delta reaches `limit` bytes
+fn encode_delta(base: &[u8], target: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let mut delta = Vec::new();
+    write_size(&mut delta, base.len());
+    write_size(&mut delta, target.len());
+
+    let base = &base[..base.len().min(MAX_COPY_OFFSET)];
+    let mut index: HashMap<&[u8], usize> = HashMap::new();
+    for start in (0..base.len().saturating_sub(BLOCK - 1))
+        .step_by(BLOCK)
+        .rev()
+    {
+        index.insert(&base[start..start + BLOCK], start);
+    }
+
+    let mut pending = 0..0;
+    let mut pos = 0;
+    while pos < target.len() {
+        let found = target
+            .get(pos..pos + BLOCK)
+            .and_then(|block| index.get(block).copied());
+        let Some(start) = found else {
+            pos += 1;
+            pending.end = pos;
+            continue;
+        };
+
+        // Grow the match backwards into pending inserts and forwards as far as it goes
+        let mut base_start = start;
+        let mut target_start = pos;
+        while base_start > 0
+            && target_start > pending.start
+            && base[base_start - 1] == target[target_start - 1]
+        {
+            base_start -= 1;
+            target_start -= 1;
+        }
+        let mut len = pos - target_start;
+        while base_start + len < base.len()
+            && target_start + len < target.len()
+            && base[base_start + len] == target[target_start + len]
+        {
+            len += 1;
+        }
+
+        pending.end = target_start;
+        write_inserts(&mut delta, &target[pending.clone()]);
+        write_copies(&mut delta, base_start, len);
+        pos = target_start + len;
+        pending = pos..pos;
+
+        if delta.len() >= limit {
+            return None;
+        }
+    }
+
+    write_inserts(&mut delta, &target[pending]);
+    (delta.len() < limit).then_some(delta)
+}
+
+fn write_size(delta: &mut Vec<u8>, mut size: usize) {
+    loop {
+        let byte = (size & 0x7f) as u8;
+        size >>= 7;
+        if size == 0 {
+            delta.push(byte);
+            return;
+        }
+        delta.push(byte | 0x80);
+    }
+}
+
+fn write_inserts(delta: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(0x7f) {
+        delta.push(chunk.len() as u8);
+        delta.extend_from_slice(chunk);
+    }
+}
+
+fn write_copies(delta: &mut Vec<u8>, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(MAX_COPY);
+        let mut lead = 0x80u8;
+        let mut args = Vec::with_capacity(7);
+
+        for (bit, byte) in (offset as u32).to_le_bytes().into_iter().enumerate() {
+            if byte != 0 {
+                lead |= 1 << bit;
+                args.push(byte);
+            }
+        }
+        for (bit, byte) in (size as u32).to_le_bytes().into_iter().take(3).enumerate() {
+            if byte != 0 {
+                lead |= 1 << (4 + bit);
+                args.push(byte);
+            }
+        }
+
+        delta.push(lead);
+        delta.extend(args);
+        offset += size;
+        len -= size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    use crate::pack::{apply_delta, delta_instructions, DeltaInstruction, PackFile};
+
+    /// Some text long enough to be worth deltifying, differing a little each time
+    fn text(variant: &str) -> Vec<u8> {
+        (0..200)
+            .map(|line| format!("line {line} of the {variant} version\n"))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    /// A pack holding nothing but `entries`, positioned at its first entry
+    fn pack_of(entries: &[u8]) -> PackFile {
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\0".to_vec();
+        pack.extend(entries);
+        pack.extend(Sha1::digest(&pack));
+        let mut pack = PackFile::new("test", Bytes::from(pack)).unwrap();
+        pack.content.set_position(12);
+        pack
+    }
+
+    #[test]
+    fn deltas_reproduce_their_target() {
+        let base = text("first");
+        let mut target = text("first");
+        target.splice(1000..1010, b"an edit in the middle".iter().copied());
+        target.extend(b"and a new line at the end\n");
+        let unrelated: Vec<u8> = (0..=255).cycle().take(3000).collect();
+
+        for (base, target) in [
+            (&base, &target),
+            (&target, &base),
+            (&base, &base),
+            (&base, &text("second")),
+            (&base, &unrelated),
+            (&Vec::new(), &target),
+        ] {
+            let delta = encode_delta(base, target, usize::MAX).unwrap();
+            assert_eq!(&apply_delta(base, delta).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn deltas_of_similar_objects_are_small() {
+        let base = text("first");
+        let mut target = base.clone();
+        target.extend(b"one more line\n");
+
+        let delta = encode_delta(&base, &target, usize::MAX).unwrap();
+        assert!(delta.len() < 32, "{} byte delta", delta.len());
+    }
+
+    #[test]
+    fn gives_up_once_the_delta_reaches_the_limit() {
+        let unrelated: Vec<u8> = (0..=255).cycle().take(3000).collect();
+        assert!(encode_delta(&text("first"), &unrelated, 1000).is_none());
+    }
+
+    #[test]
+    fn splits_long_copies_and_keeps_four_byte_offsets() {
+        let mut delta = Vec::new();
+        write_copies(&mut delta, 0x8000_0001, MAX_COPY + 5);
+
+        assert_eq!(
+            delta_instructions(Bytes::from(delta)).unwrap(),
+            vec![
+                DeltaInstruction::Copy {
+                    offset: 0x8000_0001,
+                    size: MAX_COPY
+                },
+                DeltaInstruction::Copy {
+                    offset: 0x8100_0000,
+                    size: 5
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn entry_headers_round_trip() {
+        for size in [0, 15, 16, 127, 128, 0xffff, 0x1234_5678, usize::MAX >> 4] {
+            let mut entry = Vec::new();
+            write_entry_header(&mut entry, PackFileObject::Blob, size);
+
+            let mut pack = pack_of(&entry);
+            let (obj_type, read) = pack.get_object_type_and_size().unwrap();
+            assert_eq!((obj_type, read), (PackFileObject::Blob, size));
+        }
+    }
+
+    #[test]
+    fn base_offsets_round_trip() {
+        for distance in [1, 127, 128, 16511, 16512, 0xdead_beef, 1 << 40] {
+            let mut encoded = Vec::new();
+            write_base_offset(&mut encoded, distance);
+
+            let mut pack = pack_of(&encoded);
+            assert_eq!(pack.get_ofs_delta_offset().unwrap(), distance as u64);
+        }
+    }
+}
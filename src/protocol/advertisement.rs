@@ -0,0 +1,94 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use super::{line_text, AdvertisedRef, PktLine, PktLineReader};
+
+/// The refs and capabilities a server lists in response to ref discovery
+#[derive(Debug, Default)]
+pub(crate) struct Advertisement {
+    /// Only filled in by protocol v0, where every ref is advertised up front
+    pub(crate) refs: Vec<AdvertisedRef>,
+    pub(crate) capabilities: Vec<String>,
+}
+
+impl Advertisement {
+    /// Parses a v0 ref advertisement, where the first ref carries the
+    /// capability list after a NUL and tags are peeled on `^{}` lines
+    pub(crate) fn parse(lines: &[Bytes]) -> Result<Self> {
+        let mut advertisement = Self::default();
+        for (idx, line) in lines.iter().enumerate() {
+            let mut line = line_text(line)?;
+            if idx == 0 {
+                let Some((first, capabilities)) = line.split_once('\0') else {
+                    anyhow::bail!("missing capabilities in ref advertisement");
+                };
+                advertisement.capabilities =
+                    capabilities.split(' ').map(|c| c.to_string()).collect();
+                line = first;
+            }
+
+            let Some((hash, name)) = line.split_once(' ') else {
+                anyhow::bail!("malformed ref advertisement line: {line}");
+            };
+
+            // An empty repository advertises only its capabilities
+            if name == "capabilities^{}" {
+                continue;
+            }
+
+            if let Some(tag) = name.strip_suffix("^{}") {
+                let mut refs = advertisement.refs.iter_mut().rev();
+                if let Some(reference) = refs.find(|r| r.name == tag) {
+                    reference.peeled = Some(hash.to_string());
+                }
+                continue;
+            }
+
+            advertisement.refs.push(AdvertisedRef::new(name, hash));
+        }
+
+        for cap in &advertisement.capabilities {
+            let Some((name, target)) = cap
+                .strip_prefix("symref=")
+                .and_then(|symref| symref.split_once(':'))
+            else {
+                continue;
+            };
+            if let Some(reference) = advertisement.refs.iter_mut().find(|r| r.name == name) {
+                reference.symref_target = Some(target.to_string());
+            }
+        }
+
+        Ok(advertisement)
+    }
+
+    /// Whether the server advertised a capability, ignoring any value it carries
+    pub(crate) fn supports(&self, name: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|cap| cap == name || cap.split_once('=').is_some_and(|(key, _)| key == name))
+    }
+}
+
+/// Reads past the `# service=<service>` header and flush that smart HTTP ref
+/// discovery responses open with, returning the first line after them
+pub(crate) async fn read_service_header(
+    reader: &mut PktLineReader,
+    service: &str,
+) -> Result<PktLine> {
+    let first = reader.expect().await.context("reading ref discovery")?;
+    if !first.as_text().is_some_and(|t| t.starts_with("# service=")) {
+        return Ok(first);
+    }
+
+    anyhow::ensure!(
+        first.as_text() == Some(format!("# service={service}").as_str()),
+        "unexpected ref discovery header {first:?}"
+    );
+    anyhow::ensure!(
+        reader.expect().await? == PktLine::Flush,
+        "missing flush after ref discovery header"
+    );
+
+    reader.expect().await.context("reading ref advertisement")
+}
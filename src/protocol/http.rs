@@ -0,0 +1,77 @@
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+
+use super::{PktLineReader, ProtocolVersion};
+
+/// The smart HTTP transport: ref discovery through `info/refs` and service
+/// requests POSTed to `<url>/<service>`
+#[derive(Debug, Clone)]
+pub(crate) struct HttpTransport {
+    url: String,
+    client: Client,
+}
+
+impl HttpTransport {
+    pub(crate) fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            client: Client::new(),
+        }
+    }
+
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub(crate) async fn discover(
+        &self,
+        service: &str,
+        version: ProtocolVersion,
+    ) -> Result<PktLineReader> {
+        let mut request = self
+            .client
+            .get(format!("{}/info/refs", self.url))
+            .query(&[("service", service)]);
+        if version == ProtocolVersion::V2 {
+            request = request.header("Git-Protocol", "version=2");
+        }
+
+        let response = request.send().await.context("initiating ref discovery")?;
+        let status = response.status();
+        anyhow::ensure!(
+            matches!(status, StatusCode::OK | StatusCode::NOT_MODIFIED),
+            "ref discovery failed with status {status}"
+        );
+
+        Ok(PktLineReader::streaming(response))
+    }
+
+    pub(crate) async fn post(
+        &self,
+        service: &str,
+        body: Vec<u8>,
+        version: ProtocolVersion,
+    ) -> Result<PktLineReader> {
+        let mut request = self
+            .client
+            .post(format!("{}/{service}", self.url))
+            .header("Content-Type", format!("application/x-{service}-request"))
+            .header("Accept", format!("application/x-{service}-result"))
+            .body(body);
+        if version == ProtocolVersion::V2 {
+            request = request.header("Git-Protocol", "version=2");
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("sending {service} request"))?;
+        let status = response.status();
+        anyhow::ensure!(
+            status.is_success(),
+            "{service} request failed with status {status}"
+        );
+
+        Ok(PktLineReader::streaming(response))
+    }
+}
@@ -0,0 +1,42 @@
+mod advertisement;
+mod http;
+mod pktline;
+mod receive_pack;
+mod sideband;
+mod upload_pack;
+
+pub(crate) use http::HttpTransport;
+pub(crate) use pktline::{line_text, PktLine, PktLineReader, PktLineWriter};
+pub(crate) use receive_pack::{ReceivePack, RefUpdate};
+pub(crate) use upload_pack::UploadPack;
+
+pub(crate) const AGENT: &str = concat!("agent=git-starter-rust/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ProtocolVersion {
+    /// The original protocol, also used for version 1 which only adds a version line
+    V0,
+    V2,
+}
+
+/// A ref as advertised by a remote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AdvertisedRef {
+    pub(crate) name: String,
+    pub(crate) hash: String,
+    /// The object an annotated tag ultimately points at
+    pub(crate) peeled: Option<String>,
+    /// The ref a symbolic ref (e.g. HEAD) points at
+    pub(crate) symref_target: Option<String>,
+}
+
+impl AdvertisedRef {
+    fn new(name: &str, hash: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            hash: hash.to_string(),
+            peeled: None,
+            symref_target: None,
+        }
+    }
+}
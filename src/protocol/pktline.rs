@@ -0,0 +1,267 @@
+use anyhow::{Context, Result};
+use bytes::{Buf, Bytes, BytesMut};
+
+/// The largest pkt-line allowed, including its four byte length prefix
+pub(crate) const MAX_PKT_LEN: usize = 65520;
+pub(crate) const MAX_PKT_DATA_LEN: usize = MAX_PKT_LEN - 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PktLine {
+    Data(Bytes),
+    /// `0000`, the end of a message section
+    Flush,
+    /// `0001`, separates sections within a protocol v2 message
+    Delim,
+    /// `0002`, the end of a stateless protocol v2 response
+    ResponseEnd,
+}
+
+impl PktLine {
+    /// The payload as text with any trailing newline removed, if it is valid utf-8
+    pub(crate) fn as_text(&self) -> Option<&str> {
+        match self {
+            Self::Data(data) => line_text(data).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes a text pkt-line payload, removing its trailing newline
+pub(crate) fn line_text(data: &[u8]) -> Result<&str> {
+    let text = std::str::from_utf8(data).context("pkt-line is not valid utf-8")?;
+    Ok(text.strip_suffix('\n').unwrap_or(text))
+}
+
+/// Reads pkt-lines out of a response, pulling in more of an HTTP body only as
+/// each line needs it so long responses are handled while they arrive
+#[derive(Debug)]
+pub(crate) struct PktLineReader {
+    buf: BytesMut,
+    /// The rest of the response, until it's been read to the end
+    body: Option<reqwest::Response>,
+}
+
+impl PktLineReader {
+    pub(crate) fn new(buf: Bytes) -> Self {
+        Self {
+            buf: BytesMut::from(&buf[..]),
+            body: None,
+        }
+    }
+
+    pub(crate) fn streaming(body: reqwest::Response) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            body: Some(body),
+        }
+    }
+
+    /// Buffers at least `len` bytes, returning false if the response ends first
+    async fn fill(&mut self, len: usize) -> Result<bool> {
+        while self.buf.len() < len {
+            let Some(body) = &mut self.body else {
+                return Ok(false);
+            };
+            match body.chunk().await.context("reading response body")? {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None => self.body = None,
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Reads the next pkt-line, or `None` once the response is exhausted
+    pub(crate) async fn read(&mut self) -> Result<Option<PktLine>> {
+        if !self.fill(1).await? {
+            return Ok(None);
+        }
+
+        anyhow::ensure!(self.fill(4).await?, "truncated pkt-line length");
+        let raw_len = std::str::from_utf8(&self.buf[..4])
+            .ok()
+            .filter(|len| len.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|len| usize::from_str_radix(len, 16).ok())
+            .with_context(|| format!("invalid pkt-line length {:?}", &self.buf[..4]))?;
+
+        let line = match raw_len {
+            0 => PktLine::Flush,
+            1 => PktLine::Delim,
+            2 => PktLine::ResponseEnd,
+            3 => anyhow::bail!("invalid pkt-line length 3"),
+            len => {
+                anyhow::ensure!(len <= MAX_PKT_LEN, "pkt-line of {len} bytes is too long");
+                anyhow::ensure!(self.fill(len).await?, "truncated pkt-line");
+                let mut line = self.buf.split_to(len).freeze();
+                line.advance(4);
+                return Ok(Some(PktLine::Data(line)));
+            }
+        };
+
+        self.buf.advance(4);
+        Ok(Some(line))
+    }
+
+    /// Reads the next pkt-line, failing if the response is exhausted
+    pub(crate) async fn expect(&mut self) -> Result<PktLine> {
+        self.read()
+            .await?
+            .context("unexpected end of pkt-line stream")
+    }
+
+    /// Reads data lines up to (and consuming) the next flush, delim or response end
+    pub(crate) async fn read_section(&mut self) -> Result<Vec<Bytes>> {
+        let mut lines = Vec::new();
+        loop {
+            match self.expect().await? {
+                PktLine::Data(data) => lines.push(data),
+                _ => return Ok(lines),
+            }
+        }
+    }
+
+    /// Whatever follows the pkt-lines read so far, e.g. a raw packfile
+    pub(crate) async fn into_remaining(mut self) -> Result<Bytes> {
+        self.fill(usize::MAX).await?;
+        Ok(self.buf.freeze())
+    }
+}
+
+/// Builds up a request out of pkt-lines
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PktLineWriter {
+    buf: Vec<u8>,
+}
+
+impl PktLineWriter {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn data(&mut self, data: &[u8]) -> Result<&mut Self> {
+        anyhow::ensure!(!data.is_empty(), "pkt-lines cannot be empty");
+        anyhow::ensure!(
+            data.len() <= MAX_PKT_DATA_LEN,
+            "{} bytes is too long for a pkt-line",
+            data.len()
+        );
+
+        self.buf
+            .extend_from_slice(format!("{:04x}", data.len() + 4).as_bytes());
+        self.buf.extend_from_slice(data);
+        Ok(self)
+    }
+
+    /// Writes a line of text, terminating it with a newline
+    pub(crate) fn text(&mut self, line: &str) -> Result<&mut Self> {
+        if line.ends_with('\n') {
+            self.data(line.as_bytes())
+        } else {
+            self.data(format!("{line}\n").as_bytes())
+        }
+    }
+
+    pub(crate) fn flush(&mut self) -> &mut Self {
+        self.buf.extend_from_slice(b"0000");
+        self
+    }
+
+    pub(crate) fn delim(&mut self) -> &mut Self {
+        self.buf.extend_from_slice(b"0001");
+        self
+    }
+
+    pub(crate) fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+
+    fn reader(raw: &'static [u8]) -> PktLineReader {
+        PktLineReader::new(Bytes::from_static(raw))
+    }
+
+    #[tokio::test]
+    async fn reads_data_and_special_lines() {
+        let mut lines = reader(b"000ahello\n0000000100020004rest");
+
+        assert_eq!(
+            lines.read().await.unwrap(),
+            Some(PktLine::Data(Bytes::from_static(b"hello\n")))
+        );
+        assert_eq!(lines.read().await.unwrap(), Some(PktLine::Flush));
+        assert_eq!(lines.read().await.unwrap(), Some(PktLine::Delim));
+        assert_eq!(lines.read().await.unwrap(), Some(PktLine::ResponseEnd));
+        assert_eq!(
+            lines.read().await.unwrap(),
+            Some(PktLine::Data(Bytes::new()))
+        );
+        assert_eq!(lines.into_remaining().await.unwrap(), "rest");
+    }
+
+    #[tokio::test]
+    async fn reads_sections() {
+        let mut lines = reader(b"0006a\n0006b\n00010006c\n0000");
+
+        assert_eq!(lines.read_section().await.unwrap(), ["a\n", "b\n"]);
+        assert_eq!(lines.read_section().await.unwrap(), ["c\n"]);
+        assert_eq!(lines.read().await.unwrap(), None);
+        assert!(lines.expect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_lengths() {
+        for raw in [&b"0003"[..], b"00", b"zzzz", b"+00a", b"0009abc", b"fff0"] {
+            let mut lines = PktLineReader::new(Bytes::from_static(raw));
+            assert!(lines.read().await.is_err(), "{raw:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_lines_split_across_body_chunks() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+
+            let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            for chunk in [&b"00"[..], b"0afir", b"st\n0", b"00", b"8two\n0000PACK"] {
+                response.extend(format!("{:x}\r\n", chunk.len()).as_bytes());
+                response.extend(chunk);
+                response.extend(b"\r\n");
+            }
+            response.extend(b"0\r\n\r\n");
+            stream.write_all(&response).unwrap();
+        });
+
+        let response = reqwest::get(url).await.unwrap();
+        let mut lines = PktLineReader::streaming(response);
+        assert_eq!(lines.read_section().await.unwrap(), ["first\n", "two\n"]);
+        assert_eq!(lines.into_remaining().await.unwrap(), "PACK");
+    }
+
+    #[test]
+    fn writes_lines() {
+        let mut writer = PktLineWriter::new();
+        writer.text("want abc").unwrap();
+        writer.data(b"raw").unwrap();
+        writer.delim().flush();
+
+        assert_eq!(writer.into_inner(), b"000dwant abc\n0007raw00010000");
+    }
+
+    #[test]
+    fn refuses_lines_that_cannot_be_framed() {
+        let mut writer = PktLineWriter::new();
+        assert!(writer.data(b"").is_err());
+        assert!(writer.data(&vec![b'x'; MAX_PKT_DATA_LEN + 1]).is_err());
+        assert!(writer.data(&vec![b'x'; MAX_PKT_DATA_LEN]).is_ok());
+    }
+}
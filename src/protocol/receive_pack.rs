@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+
+use super::advertisement::{read_service_header, Advertisement};
+use super::{
+    line_text, sideband, AdvertisedRef, HttpTransport, PktLine, PktLineReader, PktLineWriter,
+    ProtocolVersion, AGENT,
+};
+use crate::refs::NULL_HASH;
+
+const SERVICE: &str = "git-receive-pack";
+
+/// A ref the remote should move from `old` to `new`, where `NULL_HASH` on
+/// either side creates or deletes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RefUpdate {
+    pub(crate) name: String,
+    pub(crate) old: String,
+    pub(crate) new: String,
+}
+
+impl RefUpdate {
+    pub(crate) fn is_delete(&self) -> bool {
+        self.new == NULL_HASH
+    }
+}
+
+/// What the remote reported for each ref update, `Err` carrying its reason
+pub(crate) type RefStatus = (String, std::result::Result<(), String>);
+
+/// A connection to a remote's receive-pack service, which only speaks protocol v0
+#[derive(Debug)]
+pub(crate) struct ReceivePack {
+    transport: HttpTransport,
+    advertisement: Advertisement,
+}
+
+impl ReceivePack {
+    pub(crate) async fn connect(url: &str) -> Result<Self> {
+        let transport = HttpTransport::new(url);
+        let mut reader = transport.discover(SERVICE, ProtocolVersion::V0).await?;
+
+        let advertisement = match read_service_header(&mut reader, SERVICE).await? {
+            PktLine::Data(first) => {
+                let mut lines = vec![first];
+                lines.extend(reader.read_section().await?);
+                Advertisement::parse(&lines)?
+            }
+            _ => Advertisement::default(),
+        };
+
+        Ok(Self {
+            transport,
+            advertisement,
+        })
+    }
+
+    pub(crate) fn url(&self) -> &str {
+        self.transport.url()
+    }
+
+    pub(crate) fn refs(&self) -> &[AdvertisedRef] {
+        &self.advertisement.refs
+    }
+
+    pub(crate) fn supports(&self, name: &str) -> bool {
+        self.advertisement.supports(name)
+    }
+
+    /// Sends ref update commands followed by `pack`, which must be present
+    /// unless every update is a deletion, returning the status of each update
+    pub(crate) async fn push(
+        &self,
+        updates: &[RefUpdate],
+        pack: Option<Vec<u8>>,
+        quiet: bool,
+    ) -> Result<Vec<RefStatus>> {
+        anyhow::ensure!(!updates.is_empty(), "nothing to push");
+        if updates.iter().any(|update| update.is_delete()) {
+            anyhow::ensure!(
+                self.supports("delete-refs"),
+                "the remote does not support deleting refs"
+            );
+        }
+
+        let report_status = self.supports("report-status");
+        let sideband = self.supports("side-band-64k");
+        let mut capabilities = Vec::new();
+        if report_status {
+            capabilities.push("report-status");
+        }
+        if sideband {
+            capabilities.push("side-band-64k");
+        }
+        if quiet && self.supports("quiet") {
+            capabilities.push("quiet");
+        }
+        capabilities.push(AGENT);
+
+        let mut request = PktLineWriter::new();
+        for (idx, update) in updates.iter().enumerate() {
+            let command = format!("{} {} {}", update.old, update.new, update.name);
+            // Capabilities ride along on the first command only
+            if idx == 0 {
+                request.data(format!("{command}\0{}", capabilities.join(" ")).as_bytes())?;
+            } else {
+                request.text(&command)?;
+            }
+        }
+        request.flush();
+
+        let mut body = request.into_inner();
+        body.extend(pack.unwrap_or_default());
+
+        let mut reader = self
+            .transport
+            .post(SERVICE, body, ProtocolVersion::V0)
+            .await?;
+
+        // Without report-status there's nothing more to learn than that the request succeeded
+        if !report_status {
+            return Ok(updates.iter().map(|u| (u.name.clone(), Ok(()))).collect());
+        }
+
+        if sideband {
+            let report = sideband::demultiplex(&mut reader, quiet).await?;
+            reader = PktLineReader::new(report);
+        }
+
+        parse_report(&mut reader).await
+    }
+}
+
+/// Parses a report-status response: `unpack ok` then `ok <ref>` or `ng <ref> <reason>`
+async fn parse_report(reader: &mut PktLineReader) -> Result<Vec<RefStatus>> {
+    let unpack = reader.expect().await.context("reading push status")?;
+    match unpack
+        .as_text()
+        .and_then(|text| text.strip_prefix("unpack "))
+    {
+        Some("ok") => {}
+        Some(error) => anyhow::bail!("remote failed to unpack objects: {error}"),
+        None => anyhow::bail!("expected unpack status, got {unpack:?}"),
+    }
+
+    let mut statuses = Vec::new();
+    for line in reader.read_section().await? {
+        let line = line_text(&line)?;
+        if let Some(name) = line.strip_prefix("ok ") {
+            statuses.push((name.to_string(), Ok(())));
+        } else if let Some(rejected) = line.strip_prefix("ng ") {
+            let (name, reason) = rejected.split_once(' ').unwrap_or((rejected, "failed"));
+            statuses.push((name.to_string(), Err(reason.to_string())));
+        } else {
+            anyhow::bail!("unexpected push status '{line}'");
+        }
+    }
+
+    Ok(statuses)
+}
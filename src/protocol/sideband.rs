@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+
+use std::io::Write;
+
+use super::{PktLine, PktLineReader};
+
+const CHANNEL_DATA: u8 = 1;
+const CHANNEL_PROGRESS: u8 = 2;
+const CHANNEL_ERROR: u8 = 3;
+
+/// Reassembles the data channel of a side-band stream up to the next flush,
+/// copying progress messages to stderr unless `quiet` and failing on anything
+/// the remote reports on the error channel
+pub(crate) async fn demultiplex(reader: &mut PktLineReader, quiet: bool) -> Result<Bytes> {
+    let mut data = BytesMut::new();
+    let mut stderr = std::io::stderr().lock();
+    // Packets can split messages anywhere, so track where lines start to prefix them
+    let mut line_start = true;
+    loop {
+        let line = match reader.expect().await.context("reading side-band stream")? {
+            PktLine::Data(line) => line,
+            PktLine::Flush | PktLine::ResponseEnd => return Ok(data.freeze()),
+            PktLine::Delim => anyhow::bail!("unexpected delim in side-band stream"),
+        };
+
+        let Some((&channel, payload)) = line.split_first() else {
+            anyhow::bail!("empty side-band packet");
+        };
+        match channel {
+            CHANNEL_DATA => data.extend_from_slice(payload),
+            CHANNEL_PROGRESS if quiet => {}
+            CHANNEL_PROGRESS => {
+                // Messages carry their own `\r`/`\n` so progress bars redraw in place
+                for chunk in payload.split_inclusive(|&b| b == b'\r' || b == b'\n') {
+                    if line_start {
+                        stderr.write_all(b"remote: ").context("writing progress")?;
+                    }
+                    stderr.write_all(chunk).context("writing progress")?;
+                    line_start = chunk.ends_with(b"\r") || chunk.ends_with(b"\n");
+                }
+            }
+            CHANNEL_ERROR => anyhow::bail!(
+                "remote error: {}",
+                String::from_utf8_lossy(payload).trim_end()
+            ),
+            other => anyhow::bail!("unknown side-band channel {other}"),
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use super::advertisement::{read_service_header, Advertisement};
+use super::{
+    line_text, sideband, AdvertisedRef, HttpTransport, PktLine, PktLineReader, PktLineWriter,
+    ProtocolVersion, AGENT,
+};
+
+const SERVICE: &str = "git-upload-pack";
+
+/// Capabilities we ask for when a v0 server supports them
+const WANTED_CAPABILITIES: &[&str] = &["ofs-delta", "multi_ack_detailed", "include-tag"];
+
+/// What the server made of a round of `have` lines
+#[derive(Debug, Default)]
+pub(crate) struct Acknowledgments {
+    /// The haves the server also has
+    pub(crate) common: Vec<String>,
+    /// The server has enough common history to send a pack
+    pub(crate) ready: bool,
+    /// Protocol v2 servers send the pack straight away once ready
+    pub(crate) pack: Option<Bytes>,
+}
+
+/// A connection to a remote's upload-pack service, speaking protocol v2 when
+/// the server supports it and falling back to v0 otherwise
+#[derive(Debug)]
+pub(crate) struct UploadPack {
+    transport: HttpTransport,
+    version: ProtocolVersion,
+    advertisement: Advertisement,
+}
+
+impl UploadPack {
+    pub(crate) async fn connect(url: &str) -> Result<Self> {
+        let transport = HttpTransport::new(url);
+        let mut reader = transport.discover(SERVICE, ProtocolVersion::V2).await?;
+
+        // v0 responses (and some v2 ones) open with the service name
+        let first = read_service_header(&mut reader, SERVICE).await?;
+
+        let mut connection = Self {
+            transport,
+            version: ProtocolVersion::V0,
+            advertisement: Advertisement::default(),
+        };
+
+        let first = match first {
+            PktLine::Data(first) => first,
+            // Nothing at all is advertised for an empty repository by some servers
+            _ => return Ok(connection),
+        };
+
+        match line_text(&first)? {
+            "version 2" => {
+                connection.version = ProtocolVersion::V2;
+                for line in reader.read_section().await? {
+                    let capability = line_text(&line)?.to_string();
+                    connection.advertisement.capabilities.push(capability);
+                }
+            }
+            "version 1" => {
+                connection.advertisement = Advertisement::parse(&reader.read_section().await?)?;
+            }
+            _ => {
+                let mut lines = vec![first];
+                lines.extend(reader.read_section().await?);
+                connection.advertisement = Advertisement::parse(&lines)?;
+            }
+        }
+
+        Ok(connection)
+    }
+
+    pub(crate) fn url(&self) -> &str {
+        self.transport.url()
+    }
+
+    pub(crate) fn supports(&self, name: &str) -> bool {
+        self.advertisement.supports(name)
+    }
+
+    /// The remote's refs whose names start with one of `prefixes`, with symbolic
+    /// refs and peeled tags filled in
+    pub(crate) async fn ls_refs(&self, prefixes: &[&str]) -> Result<Vec<AdvertisedRef>> {
+        if self.version == ProtocolVersion::V0 {
+            return Ok(self
+                .advertisement
+                .refs
+                .iter()
+                .filter(|r| prefixes.iter().any(|prefix| r.name.starts_with(prefix)))
+                .cloned()
+                .collect());
+        }
+
+        let mut request = self.command("ls-refs")?;
+        request.text("peel")?.text("symrefs")?;
+        for prefix in prefixes {
+            request.text(&format!("ref-prefix {prefix}"))?;
+        }
+        request.flush();
+
+        let mut reader = self
+            .transport
+            .post(SERVICE, request.into_inner(), self.version)
+            .await?;
+
+        let mut refs = Vec::new();
+        for line in reader.read_section().await? {
+            let line = line_text(&line)?;
+            let mut parts = line.split(' ');
+            let (Some(hash), Some(name)) = (parts.next(), parts.next()) else {
+                anyhow::bail!("malformed ls-refs line: {line}");
+            };
+
+            let mut reference = AdvertisedRef::new(name, hash);
+            for attribute in parts {
+                if let Some(target) = attribute.strip_prefix("symref-target:") {
+                    reference.symref_target = Some(target.to_string());
+                } else if let Some(peeled) = attribute.strip_prefix("peeled:") {
+                    reference.peeled = Some(peeled.to_string());
+                }
+            }
+            refs.push(reference);
+        }
+
+        Ok(refs)
+    }
+
+    /// Sends one round of `haves` without finishing negotiation, returning those
+    /// the server has in common with us
+    pub(crate) async fn negotiate(
+        &self,
+        wants: &[String],
+        haves: &[String],
+        quiet: bool,
+    ) -> Result<Acknowledgments> {
+        match self.version {
+            ProtocolVersion::V0 => {
+                let (request, _) = self.request_v0(wants, haves, false, quiet)?;
+                let mut reader = self.post(request).await?;
+
+                // multi_ack_detailed ends every round with a NAK
+                let mut acks = Acknowledgments::default();
+                while let Some(line) = reader.read().await? {
+                    let Some((hash, status)) = parse_ack(&line)? else {
+                        break;
+                    };
+                    acks.ready |= status == Some("ready");
+                    acks.common.push(hash);
+                }
+
+                Ok(acks)
+            }
+            ProtocolVersion::V2 => {
+                let request = self.request_v2(wants, haves, false, quiet)?;
+                let mut reader = self.post(request).await?;
+
+                let header = reader.expect().await.context("reading fetch response")?;
+                anyhow::ensure!(
+                    header.as_text() == Some("acknowledgments"),
+                    "expected acknowledgments, got {header:?}"
+                );
+
+                let mut acks = Acknowledgments::default();
+                loop {
+                    let line = reader.expect().await.context("reading acknowledgments")?;
+                    match line.as_text() {
+                        Some("ready") => acks.ready = true,
+                        Some(_) => acks.common.extend(parse_ack(&line)?.map(|(hash, _)| hash)),
+                        // Once ready the pack follows in the same response
+                        None if line == PktLine::Delim => {
+                            acks.pack = Some(read_packfile(&mut reader, quiet).await?);
+                            return Ok(acks);
+                        }
+                        None => return Ok(acks),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Asks for a pack containing everything reachable from `wants` but not from
+    /// `haves`, returning the raw pack. Progress the remote reports is shown on
+    /// stderr unless `quiet`.
+    pub(crate) async fn fetch(
+        &self,
+        wants: &[String],
+        haves: &[String],
+        quiet: bool,
+    ) -> Result<Bytes> {
+        match self.version {
+            ProtocolVersion::V0 => {
+                let (request, sideband) = self.request_v0(wants, haves, true, quiet)?;
+                let mut reader = self.post(request).await?;
+
+                // Acknowledgments end with a NAK or an ACK carrying no status
+                loop {
+                    let line = reader
+                        .expect()
+                        .await
+                        .context("reading upload pack acknowledgement")?;
+                    match parse_ack(&line)? {
+                        Some((_, Some(_))) => continue,
+                        _ => break,
+                    }
+                }
+
+                match sideband {
+                    Some(_) => sideband::demultiplex(&mut reader, quiet).await,
+                    None => reader.into_remaining().await,
+                }
+            }
+            ProtocolVersion::V2 => {
+                let request = self.request_v2(wants, haves, true, quiet)?;
+                let mut reader = self.post(request).await?;
+                read_packfile(&mut reader, quiet).await
+            }
+        }
+    }
+
+    async fn post(&self, request: PktLineWriter) -> Result<PktLineReader> {
+        self.transport
+            .post(SERVICE, request.into_inner(), self.version)
+            .await
+    }
+
+    /// Builds a v0 upload request, returning the side-band capability it asked for
+    fn request_v0(
+        &self,
+        wants: &[String],
+        haves: &[String],
+        done: bool,
+        quiet: bool,
+    ) -> Result<(PktLineWriter, Option<&'static str>)> {
+        let mut capabilities: Vec<&str> = WANTED_CAPABILITIES
+            .iter()
+            .copied()
+            .filter(|cap| self.supports(cap))
+            .collect();
+
+        // Prefer the larger packets of side-band-64k over the original side-band
+        let sideband = ["side-band-64k", "side-band"]
+            .into_iter()
+            .find(|cap| self.supports(cap));
+        capabilities.extend(sideband);
+        if quiet && self.supports("no-progress") {
+            capabilities.push("no-progress");
+        }
+        capabilities.push(AGENT);
+
+        let mut request = PktLineWriter::new();
+        for (idx, hash) in wants.iter().enumerate() {
+            // Capabilities ride along on the first want line only
+            if idx == 0 {
+                request.text(&format!("want {hash} {}", capabilities.join(" ")))?;
+            } else {
+                request.text(&format!("want {hash}"))?;
+            }
+        }
+        request.flush();
+
+        for hash in haves {
+            request.text(&format!("have {hash}"))?;
+        }
+        if done {
+            request.text("done")?;
+        } else {
+            request.flush();
+        }
+
+        Ok((request, sideband))
+    }
+
+    fn request_v2(
+        &self,
+        wants: &[String],
+        haves: &[String],
+        done: bool,
+        quiet: bool,
+    ) -> Result<PktLineWriter> {
+        let mut request = self.command("fetch")?;
+        request.text("ofs-delta")?.text("include-tag")?;
+        if quiet {
+            request.text("no-progress")?;
+        }
+        for hash in wants {
+            request.text(&format!("want {hash}"))?;
+        }
+        for hash in haves {
+            request.text(&format!("have {hash}"))?;
+        }
+        if done {
+            request.text("done")?;
+        }
+        request.flush();
+
+        Ok(request)
+    }
+
+    /// Starts a protocol v2 command request, up to and including its delim
+    fn command(&self, name: &str) -> Result<PktLineWriter> {
+        let mut request = PktLineWriter::new();
+        request.text(&format!("command={name}"))?.text(AGENT)?;
+        if self.supports("object-format") {
+            request.text("object-format=sha1")?;
+        }
+        request.delim();
+
+        Ok(request)
+    }
+}
+
+/// Parses an `ACK <hash> [status]` line, or `None` for a `NAK`
+fn parse_ack(line: &PktLine) -> Result<Option<(String, Option<&str>)>> {
+    let text = line
+        .as_text()
+        .with_context(|| format!("expected ACK or NAK, got {line:?}"))?;
+    if text == "NAK" {
+        return Ok(None);
+    }
+
+    let Some(ack) = text.strip_prefix("ACK ") else {
+        anyhow::bail!("expected ACK or NAK, got '{text}'");
+    };
+    match ack.split_once(' ') {
+        Some((hash, status)) => Ok(Some((hash.to_string(), Some(status)))),
+        None => Ok(Some((ack.to_string(), None))),
+    }
+}
+
+/// Skips the v2 fetch response sections we don't use (acknowledgments,
+/// shallow-info, ...) and demultiplexes the pack from the packfile section
+async fn read_packfile(reader: &mut PktLineReader, quiet: bool) -> Result<Bytes> {
+    loop {
+        let section = reader.expect().await.context("reading fetch response")?;
+        match section.as_text() {
+            Some("packfile") => return sideband::demultiplex(reader, quiet).await,
+            Some(_) => {
+                reader.read_section().await?;
+            }
+            None => anyhow::bail!("unexpected {section:?} in fetch response"),
+        }
+    }
+}
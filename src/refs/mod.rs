@@ -0,0 +1,459 @@
+mod packed;
+mod reflog;
+mod refspec;
+
+use anyhow::{Context, Result};
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::lockfile::LockFile;
+use crate::object::{GitObject, GitObjectType, Role, Signature, Tag};
+use crate::repository::{git_path, repository};
+
+pub(crate) use packed::{PackedRef, PackedRefs};
+pub(crate) use reflog::logged_objects;
+pub(crate) use refspec::{shorthand_candidates, Refspec};
+
+pub(crate) const NULL_HASH: &str = "0000000000000000000000000000000000000000";
+
+/// Symbolic refs pointing at symbolic refs are followed at most this many times
+const MAX_SYMREF_DEPTH: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Ref {
+    Direct(String),
+    Symbolic(String),
+}
+
+fn ref_path(name: &str) -> PathBuf {
+    git_path(name)
+}
+
+pub(crate) fn is_hash(value: &str) -> bool {
+    value.len() == 40 && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// A ref name without its `refs/heads/`, `refs/tags/` or `refs/remotes/` prefix
+pub(crate) fn short_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+/// Checks a ref name against the rules of `git check-ref-format`
+pub(crate) fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name != "@"
+        && !name.starts_with('/')
+        && !name.ends_with('/')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("//")
+        && !name.contains("@{")
+        && !name
+            .bytes()
+            .any(|b| b.is_ascii_control() || b" ~^:?*[\\".contains(&b))
+        && name
+            .split('/')
+            .all(|part| !part.starts_with('.') && !part.ends_with(".lock"));
+
+    anyhow::ensure!(valid, "'{name}' is not a valid ref name");
+    Ok(())
+}
+
+/// Reads a single ref without following symbolic refs, checking the loose
+/// file first and then `packed-refs`
+pub(crate) fn read_ref(name: &str) -> Result<Option<Ref>> {
+    let path = ref_path(name);
+    if path.is_file() {
+        let raw = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading ref {}", path.display()))?;
+        let raw = raw.trim_end();
+
+        if let Some(target) = raw.strip_prefix("ref:") {
+            return Ok(Some(Ref::Symbolic(target.trim().to_string())));
+        }
+
+        anyhow::ensure!(is_hash(raw), "ref {name} has invalid content: {raw}");
+        return Ok(Some(Ref::Direct(raw.to_string())));
+    }
+
+    let packed = PackedRefs::load().context("loading packed-refs")?;
+    Ok(packed.get(name).map(|r| Ref::Direct(r.hash.clone())))
+}
+
+/// Follows symbolic refs to the name of the ref that actually holds a hash,
+/// which may not exist yet (e.g. HEAD on an unborn branch)
+pub(crate) fn resolve_name(name: &str) -> Result<String> {
+    let mut name = name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match read_ref(&name)? {
+            Some(Ref::Symbolic(target)) => name = target,
+            _ => return Ok(name),
+        }
+    }
+
+    anyhow::bail!("too many levels of symbolic refs resolving {name}")
+}
+
+/// Resolves a ref, following symbolic refs, to the hash it points at
+pub(crate) fn resolve(name: &str) -> Result<Option<String>> {
+    let name = resolve_name(name)?;
+    match read_ref(&name)? {
+        Some(Ref::Direct(hash)) => Ok(Some(hash)),
+        _ => Ok(None),
+    }
+}
+
+/// The branch HEAD points at, if it isn't detached
+pub(crate) fn current_branch() -> Result<Option<String>> {
+    match read_ref("HEAD")? {
+        Some(Ref::Symbolic(target)) => Ok(Some(target)),
+        _ => Ok(None),
+    }
+}
+
+/// Lists every ref under `prefix` (e.g. `refs/heads/`) with symbolic refs resolved,
+/// loose refs taking precedence over packed ones
+pub(crate) fn list_refs(prefix: &str) -> Result<BTreeMap<String, String>> {
+    let mut refs: BTreeMap<String, String> = PackedRefs::load()
+        .context("loading packed-refs")?
+        .refs
+        .into_iter()
+        .filter(|r| r.name.starts_with(prefix))
+        .map(|r| (r.name, r.hash))
+        .collect();
+
+    for name in loose_ref_names()? {
+        if !name.starts_with(prefix) {
+            continue;
+        }
+        if let Some(hash) = resolve(&name)? {
+            refs.insert(name, hash);
+        }
+    }
+
+    Ok(refs)
+}
+
+/// Names of every ref stored as its own file under `.git/refs`
+fn loose_ref_names() -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut pending = vec![ref_path("refs")];
+    while let Some(dir) = pending.pop() {
+        if !dir.is_dir() {
+            continue;
+        }
+
+        for entry in
+            std::fs::read_dir(&dir).with_context(|| format!("reading {}", dir.display()))?
+        {
+            let path = entry.context("bad ref directory entry")?.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+
+            let Some(name) = path
+                .strip_prefix(repository().git_dir())
+                .ok()
+                .and_then(|name| name.to_str())
+            else {
+                continue;
+            };
+            if !name.ends_with(".lock") {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+/// Moves every loose ref that holds a hash into `packed-refs`, along with what
+/// annotated tags peel to, and deletes the loose files. Returns how many were packed.
+pub(crate) fn pack_refs() -> Result<usize> {
+    let mut loose = Vec::new();
+    for name in loose_ref_names()? {
+        if let Some(Ref::Direct(hash)) = read_ref(&name)? {
+            loose.push((name, hash));
+        }
+    }
+    if loose.is_empty() {
+        return Ok(0);
+    }
+
+    let mut packed = PackedRefs::load().context("loading packed-refs")?;
+    for (name, hash) in &loose {
+        packed.insert(PackedRef {
+            name: name.clone(),
+            hash: hash.clone(),
+            peeled: peel(hash).with_context(|| format!("peeling {name}"))?,
+        });
+    }
+    packed.write()?;
+
+    // A ref updated since it was read keeps its newer loose value
+    for (name, hash) in &loose {
+        let lock = LockFile::acquire(ref_path(name))?;
+        if read_ref(name)? == Some(Ref::Direct(hash.clone())) {
+            std::fs::remove_file(ref_path(name))
+                .with_context(|| format!("removing loose ref {name}"))?;
+        }
+        drop(lock);
+    }
+
+    Ok(loose.len())
+}
+
+/// The object an annotated tag ultimately points at, or `None` for anything else
+fn peel(hash: &str) -> Result<Option<String>> {
+    let mut obj = GitObject::load(hash).with_context(|| format!("loading {hash}"))?;
+    if obj.obj_type != GitObjectType::Tag {
+        return Ok(None);
+    }
+
+    while obj.obj_type == GitObjectType::Tag {
+        let tag = Tag::try_from(&obj).with_context(|| format!("parsing tag {}", obj.hash))?;
+        obj = GitObject::load(&tag.object).with_context(|| format!("loading {}", tag.object))?;
+    }
+
+    Ok(Some(obj.hash))
+}
+
+/// Points a ref (or the ref a symbolic ref resolves to) at `hash`. When `expected`
+/// is given the update only happens if the ref currently has that value, with
+/// `NULL_HASH` meaning the ref must not exist yet.
+pub(crate) fn update_ref(name: &str, hash: &str, expected: Option<&str>) -> Result<()> {
+    anyhow::ensure!(is_hash(hash), "refusing to point {name} at {hash}");
+    let name = resolve_name(name)?;
+    if name != "HEAD" {
+        validate_name(&name)?;
+    }
+
+    let lock = LockFile::acquire(ref_path(&name))?;
+    check_expected(&name, expected)?;
+    lock.commit(format!("{hash}\n").as_bytes())
+        .with_context(|| format!("updating ref {name}"))
+}
+
+/// Updates a ref like `update_ref`, also recording the change in the reflog of
+/// the ref that moved and, when `name` is a symbolic ref such as HEAD, in its own
+pub(crate) fn update_ref_logged(
+    name: &str,
+    hash: &str,
+    expected: Option<&str>,
+    message: &str,
+) -> Result<()> {
+    let resolved = resolve_name(name)?;
+    let old = resolve(&resolved)?.unwrap_or_else(|| NULL_HASH.to_string());
+    update_ref(&resolved, hash, expected)?;
+
+    let config = Config::load()?;
+    let committer = Signature::current(Role::Committer, &config).context("getting committer")?;
+    let mut logged = vec![resolved.as_str()];
+    if name != resolved {
+        logged.push(name);
+    }
+    for logged in logged {
+        if reflog::enabled(logged, &config)? {
+            reflog::append(logged, &old, hash, &committer, message)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Points HEAD directly at a commit rather than at a branch
+pub(crate) fn detach_head(hash: &str) -> Result<()> {
+    anyhow::ensure!(is_hash(hash), "refusing to point HEAD at {hash}");
+
+    let lock = LockFile::acquire(ref_path("HEAD"))?;
+    lock.commit(format!("{hash}\n").as_bytes())
+        .context("detaching HEAD")
+}
+
+/// Makes `name` a symbolic ref pointing at `target`
+pub(crate) fn write_symref(name: &str, target: &str) -> Result<()> {
+    validate_name(target)?;
+
+    let lock = LockFile::acquire(ref_path(name))?;
+    lock.commit(format!("ref: {target}\n").as_bytes())
+        .with_context(|| format!("writing symbolic ref {name}"))
+}
+
+/// Removes a ref from both its loose file and `packed-refs`
+pub(crate) fn delete_ref(name: &str, expected: Option<&str>) -> Result<()> {
+    let path = ref_path(name);
+    let lock = LockFile::acquire(&path)?;
+    check_expected(name, expected)?;
+
+    let mut packed = PackedRefs::load().context("loading packed-refs")?;
+    if packed.remove(name) {
+        packed.write()?;
+    }
+
+    if path.is_file() {
+        std::fs::remove_file(&path).with_context(|| format!("deleting ref {name}"))?;
+    }
+    drop(lock);
+
+    Ok(())
+}
+
+fn check_expected(name: &str, expected: Option<&str>) -> Result<()> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+
+    let current = match read_ref(name)? {
+        Some(Ref::Direct(hash)) => hash,
+        Some(Ref::Symbolic(target)) => anyhow::bail!("{name} is a symbolic ref to {target}"),
+        None => NULL_HASH.to_string(),
+    };
+
+    anyhow::ensure!(
+        current == expected,
+        "{name} is at {current} but expected {expected}"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::repository::test_repository;
+
+    const ONE: &str = "1111111111111111111111111111111111111111";
+    const TWO: &str = "2222222222222222222222222222222222222222";
+
+    #[test]
+    fn validates_names_like_check_ref_format() {
+        for name in [
+            "refs/heads/main",
+            "refs/heads/feature/x",
+            "HEAD",
+            "refs/tags/v1.0",
+        ] {
+            assert!(validate_name(name).is_ok(), "{name}");
+        }
+        for name in [
+            "",
+            "@",
+            "refs/heads/",
+            "/refs/heads/main",
+            "refs/heads/a..b",
+            "refs/heads//main",
+            "refs/heads/.hidden",
+            "refs/heads/main.lock",
+            "refs/heads/main.",
+            "refs/heads/a b",
+            "refs/heads/a~1",
+            "refs/heads/a^",
+            "refs/heads/a:b",
+            "refs/heads/a@{1}",
+            "refs/tags/../../config",
+        ] {
+            assert!(validate_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn follows_symbolic_refs() {
+        test_repository();
+        write_symref("refs/symref-test/link", "refs/symref-test/target").unwrap();
+        write_symref("refs/symref-test/outer", "refs/symref-test/link").unwrap();
+
+        // The target doesn't exist yet, like HEAD on an unborn branch
+        assert_eq!(
+            resolve_name("refs/symref-test/outer").unwrap(),
+            "refs/symref-test/target"
+        );
+        assert_eq!(resolve("refs/symref-test/outer").unwrap(), None);
+
+        // Updating through a symbolic ref moves its target
+        update_ref("refs/symref-test/outer", ONE, None).unwrap();
+        assert_eq!(
+            read_ref("refs/symref-test/link").unwrap(),
+            Some(Ref::Symbolic("refs/symref-test/target".into()))
+        );
+        assert_eq!(
+            resolve("refs/symref-test/outer").unwrap().as_deref(),
+            Some(ONE)
+        );
+    }
+
+    #[test]
+    fn symbolic_ref_cycles_are_errors() {
+        test_repository();
+        write_symref("refs/cycle-test/a", "refs/cycle-test/b").unwrap();
+        write_symref("refs/cycle-test/b", "refs/cycle-test/a").unwrap();
+
+        assert!(resolve("refs/cycle-test/a").is_err());
+    }
+
+    #[test]
+    fn updates_check_the_expected_old_value() {
+        test_repository();
+        let name = "refs/heads/expected-test";
+
+        update_ref(name, ONE, Some(NULL_HASH)).unwrap();
+        assert!(update_ref(name, TWO, Some(NULL_HASH)).is_err());
+        assert!(update_ref(name, TWO, Some(TWO)).is_err());
+        assert_eq!(resolve(name).unwrap().as_deref(), Some(ONE));
+
+        update_ref(name, TWO, Some(ONE)).unwrap();
+        assert_eq!(resolve(name).unwrap().as_deref(), Some(TWO));
+
+        assert!(delete_ref(name, Some(ONE)).is_err());
+        delete_ref(name, Some(TWO)).unwrap();
+        assert_eq!(resolve(name).unwrap(), None);
+
+        // A failed check leaves no lock behind to block the next update
+        assert!(!git_path(format!("{name}.lock")).exists());
+        assert!(update_ref(name, "not a hash", None).is_err());
+        assert!(update_ref("refs/heads/bad..name", ONE, None).is_err());
+    }
+
+    #[test]
+    fn loose_refs_take_precedence_over_packed_ones() {
+        test_repository();
+        let mut packed = PackedRefs::load().unwrap();
+        for (name, hash) in [
+            ("refs/packed-test/packed", ONE),
+            ("refs/packed-test/both", ONE),
+        ] {
+            packed.insert(PackedRef {
+                name: name.into(),
+                hash: hash.into(),
+                peeled: None,
+            });
+        }
+        packed.write().unwrap();
+        update_ref("refs/packed-test/both", TWO, Some(ONE)).unwrap();
+        update_ref("refs/packed-test/loose", TWO, None).unwrap();
+
+        let listed = list_refs("refs/packed-test/").unwrap();
+        assert_eq!(
+            listed.into_iter().collect::<Vec<_>>(),
+            [
+                ("refs/packed-test/both".to_string(), TWO.to_string()),
+                ("refs/packed-test/loose".to_string(), TWO.to_string()),
+                ("refs/packed-test/packed".to_string(), ONE.to_string()),
+            ]
+        );
+
+        // Deleting removes the packed copy too, rather than uncovering it
+        delete_ref("refs/packed-test/both", None).unwrap();
+        assert_eq!(read_ref("refs/packed-test/both").unwrap(), None);
+        assert_eq!(
+            PackedRefs::load().unwrap().get("refs/packed-test/both"),
+            None
+        );
+    }
+}
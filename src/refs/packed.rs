@@ -0,0 +1,188 @@
+use anyhow::{Context, Result};
+
+use std::path::PathBuf;
+
+use crate::lockfile::LockFile;
+use crate::repository::git_path;
+
+fn packed_refs_path() -> PathBuf {
+    git_path("packed-refs")
+}
+
+const PACKED_REFS_HEADER: &str = "# pack-refs with: peeled fully-peeled sorted ";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PackedRef {
+    pub(crate) name: String,
+    pub(crate) hash: String,
+    /// The object an annotated tag ultimately points at, from the `^` line
+    pub(crate) peeled: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PackedRefs {
+    /// Sorted by name
+    pub(crate) refs: Vec<PackedRef>,
+}
+
+impl PackedRefs {
+    /// Loads `.git/packed-refs`, treating a missing file as empty
+    pub(crate) fn load() -> Result<Self> {
+        let path = packed_refs_path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let raw = std::fs::read_to_string(path).context("reading packed-refs")?;
+        Self::parse(&raw)
+    }
+
+    pub(crate) fn parse(raw: &str) -> Result<Self> {
+        let mut refs: Vec<PackedRef> = Vec::new();
+
+        for line in raw.lines() {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+
+            if let Some(peeled) = line.strip_prefix('^') {
+                let Some(last) = refs.last_mut() else {
+                    anyhow::bail!("peeled line without a ref in packed-refs");
+                };
+                last.peeled = Some(peeled.to_string());
+                continue;
+            }
+
+            let Some((hash, name)) = line.split_once(' ') else {
+                anyhow::bail!("malformed packed-refs line: {line}");
+            };
+            anyhow::ensure!(super::is_hash(hash), "invalid hash in packed-refs: {hash}");
+
+            refs.push(PackedRef {
+                name: name.to_string(),
+                hash: hash.to_string(),
+                peeled: None,
+            });
+        }
+
+        refs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Self { refs })
+    }
+
+    pub(crate) fn serialize(&self) -> String {
+        let mut out = format!("{PACKED_REFS_HEADER}\n");
+        for reference in &self.refs {
+            out.push_str(&format!("{} {}\n", reference.hash, reference.name));
+            if let Some(peeled) = &reference.peeled {
+                out.push_str(&format!("^{peeled}\n"));
+            }
+        }
+
+        out
+    }
+
+    pub(crate) fn write(&self) -> Result<()> {
+        let lock = LockFile::acquire(packed_refs_path())?;
+        lock.commit(self.serialize().as_bytes())
+            .context("writing packed-refs")
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&PackedRef> {
+        self.position(name).ok().map(|idx| &self.refs[idx])
+    }
+
+    pub(crate) fn insert(&mut self, reference: PackedRef) {
+        match self.position(&reference.name) {
+            Ok(idx) => self.refs[idx] = reference,
+            Err(idx) => self.refs.insert(idx, reference),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        match self.position(name) {
+            Ok(idx) => {
+                self.refs.remove(idx);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn position(&self, name: &str) -> std::result::Result<usize, usize> {
+        self.refs.binary_search_by(|r| r.name.as_str().cmp(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMIT: &str = "1111111111111111111111111111111111111111";
+    const TAG: &str = "2222222222222222222222222222222222222222";
+
+    #[test]
+    fn reads_peeled_lines_into_the_ref_above() {
+        let raw = format!(
+            "{PACKED_REFS_HEADER}\n{TAG} refs/tags/v1.0\n^{COMMIT}\n{COMMIT} refs/heads/main\n"
+        );
+        let packed = PackedRefs::parse(&raw).unwrap();
+
+        assert_eq!(
+            packed.refs,
+            [
+                PackedRef {
+                    name: "refs/heads/main".into(),
+                    hash: COMMIT.into(),
+                    peeled: None,
+                },
+                PackedRef {
+                    name: "refs/tags/v1.0".into(),
+                    hash: TAG.into(),
+                    peeled: Some(COMMIT.into()),
+                },
+            ]
+        );
+        assert_eq!(
+            packed.serialize(),
+            format!(
+                "{PACKED_REFS_HEADER}\n{COMMIT} refs/heads/main\n{TAG} refs/tags/v1.0\n^{COMMIT}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_refs_sorted_as_they_change() {
+        let mut packed = PackedRefs::default();
+        for name in ["refs/tags/b", "refs/heads/z", "refs/tags/a"] {
+            packed.insert(PackedRef {
+                name: name.into(),
+                hash: COMMIT.into(),
+                peeled: None,
+            });
+        }
+        packed.insert(PackedRef {
+            name: "refs/tags/a".into(),
+            hash: TAG.into(),
+            peeled: None,
+        });
+
+        let names: Vec<_> = packed.refs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["refs/heads/z", "refs/tags/a", "refs/tags/b"]);
+        assert_eq!(packed.get("refs/tags/a").unwrap().hash, TAG);
+
+        assert!(packed.remove("refs/heads/z"));
+        assert!(!packed.remove("refs/heads/z"));
+        assert_eq!(packed.get("refs/heads/z"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        for raw in [
+            format!("^{COMMIT}\n"),
+            format!("{COMMIT}\n"),
+            "not-a-hash refs/heads/main\n".to_string(),
+        ] {
+            assert!(PackedRefs::parse(&raw).is_err(), "{raw:?}");
+        }
+    }
+}
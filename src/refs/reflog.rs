@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::object::Signature;
+use crate::repository::{git_path, repository};
+
+/// Refs that are logged when `core.logAllRefUpdates` is true
+const LOGGED_PREFIXES: &[&str] = &["refs/heads/", "refs/remotes/", "refs/notes/"];
+
+fn log_path(name: &str) -> PathBuf {
+    git_path("logs").join(name)
+}
+
+/// Whether updates to a ref should be logged: always if it already has a log,
+/// otherwise as `core.logAllRefUpdates` says, which defaults to true outside
+/// bare repositories
+pub(crate) fn enabled(name: &str, config: &Config) -> Result<bool> {
+    if log_path(name).is_file() {
+        return Ok(true);
+    }
+
+    if config
+        .get("core.logAllRefUpdates")
+        .is_some_and(|value| value.eq_ignore_ascii_case("always"))
+    {
+        return Ok(true);
+    }
+    let log_all = match config.get_bool("core.logAllRefUpdates")? {
+        Some(log_all) => log_all,
+        None => repository().work_tree().is_ok(),
+    };
+
+    Ok(log_all && (name == "HEAD" || LOGGED_PREFIXES.iter().any(|p| name.starts_with(p))))
+}
+
+/// Appends `<old> <new> <committer>\t<message>` to a ref's log
+pub(crate) fn append(
+    name: &str,
+    old: &str,
+    new: &str,
+    committer: &Signature,
+    message: &str,
+) -> Result<()> {
+    let path = log_path(name);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating {}", parent.display()))?;
+    }
+
+    // Each entry has to stay on one line
+    let message = message.trim().replace('\n', " ");
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .with_context(|| format!("opening reflog for {name}"))?;
+    writeln!(log, "{old} {new} {committer}\t{message}")
+        .with_context(|| format!("writing reflog for {name}"))
+}
+
+/// Every object id recorded in any ref's log, which `gc` keeps alive so old
+/// entries can still be checked out
+pub(crate) fn logged_objects() -> Result<Vec<String>> {
+    let mut hashes = Vec::new();
+    let mut pending = vec![git_path("logs")];
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+        };
+
+        for entry in entries {
+            let path = entry.context("bad reflog directory entry")?.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+
+            let log = std::fs::read(&path)
+                .with_context(|| format!("reading reflog {}", path.display()))?;
+            for line in log.split(|&b| b == b'\n') {
+                let ids = line.split(|&b| b == b' ').take(2);
+                hashes.extend(
+                    ids.filter_map(|id| std::str::from_utf8(id).ok())
+                        .filter(|id| super::is_hash(id) && *id != super::NULL_HASH)
+                        .map(str::to_string),
+                );
+            }
+        }
+    }
+
+    Ok(hashes)
+}
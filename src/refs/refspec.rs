@@ -0,0 +1,181 @@
+use anyhow::Result;
+
+/// A `[+]<src>[:<dst>]` mapping between ref names on two sides of a fetch or push,
+/// where `*` in both sides matches any run of characters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Refspec {
+    /// Whether non-fast-forward updates are allowed, from a leading `+`
+    pub(crate) force: bool,
+    /// Empty for a push refspec like `:branch` that deletes the destination
+    pub(crate) src: String,
+    pub(crate) dst: Option<String>,
+}
+
+impl Refspec {
+    pub(crate) fn parse(spec: &str) -> Result<Self> {
+        let (force, spec) = match spec.strip_prefix('+') {
+            Some(spec) => (true, spec),
+            None => (false, spec),
+        };
+
+        let (src, dst) = match spec.split_once(':') {
+            Some((src, dst)) => (src, (!dst.is_empty()).then(|| dst.to_string())),
+            None => (spec, None),
+        };
+
+        anyhow::ensure!(!src.is_empty() || dst.is_some(), "invalid refspec '{spec}'");
+        let globs = src.matches('*').count();
+        anyhow::ensure!(
+            globs <= 1 && dst.as_ref().map_or(globs, |dst| dst.matches('*').count()) == globs,
+            "invalid refspec '{spec}': patterns must have one '*' on each side"
+        );
+
+        Ok(Self {
+            force,
+            src: src.to_string(),
+            dst,
+        })
+    }
+
+    pub(crate) fn is_glob(&self) -> bool {
+        self.src.contains('*')
+    }
+
+    /// Prefixes that cover every ref the source side could match, for `ls-refs`
+    pub(crate) fn prefixes(&self) -> Vec<String> {
+        if let Some((prefix, _)) = self.src.split_once('*') {
+            return vec![prefix.to_string()];
+        }
+
+        if self.src.starts_with("refs/") || self.src == "HEAD" {
+            vec![self.src.clone()]
+        } else {
+            shorthand_candidates(&self.src)
+        }
+    }
+
+    /// Whether the source side matches a fully qualified ref name
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        self.capture(name).is_some()
+    }
+
+    /// Maps a ref matched by the source side to its destination, if there is one
+    pub(crate) fn destination(&self, name: &str) -> Option<String> {
+        let captured = self.capture(name)?;
+        let dst = self.dst.as_ref()?;
+
+        Some(match captured {
+            Some(captured) => dst.replacen('*', captured, 1),
+            None => dst.clone(),
+        })
+    }
+
+    /// `Some(Some(_))` with the part matched by `*` for a glob, `Some(None)` for an
+    /// exact match and `None` if `name` doesn't match at all
+    fn capture<'a>(&self, name: &'a str) -> Option<Option<&'a str>> {
+        if let Some((prefix, suffix)) = self.src.split_once('*') {
+            let captured = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            return Some(Some(captured));
+        }
+
+        let exact = name == self.src
+            || (!self.src.starts_with("refs/")
+                && shorthand_candidates(&self.src).iter().any(|c| c == name));
+        exact.then_some(None)
+    }
+}
+
+/// The full names a short ref name like `main` may refer to, in order of precedence
+pub(crate) fn shorthand_candidates(short: &str) -> Vec<String> {
+    vec![
+        format!("refs/{short}"),
+        format!("refs/tags/{short}"),
+        format!("refs/heads/{short}"),
+        format!("refs/remotes/{short}"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_force_source_and_destination() {
+        let spec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap();
+        assert!(spec.force);
+        assert!(spec.is_glob());
+        assert_eq!(spec.src, "refs/heads/*");
+        assert_eq!(spec.dst.as_deref(), Some("refs/remotes/origin/*"));
+
+        let spec = Refspec::parse("main").unwrap();
+        assert!(!spec.force);
+        assert_eq!(spec.dst, None);
+
+        // Deleting on push
+        let spec = Refspec::parse(":refs/heads/gone").unwrap();
+        assert_eq!(spec.src, "");
+        assert_eq!(spec.dst.as_deref(), Some("refs/heads/gone"));
+    }
+
+    #[test]
+    fn rejects_unbalanced_patterns() {
+        for spec in [
+            "",
+            ":",
+            "+",
+            "refs/heads/*:refs/remotes/origin/main",
+            "refs/heads/main:refs/remotes/origin/*",
+            "refs/*/*:refs/*/*",
+        ] {
+            assert!(Refspec::parse(spec).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn maps_globs_to_their_destination() {
+        let spec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap();
+        assert_eq!(
+            spec.destination("refs/heads/topic/nested").as_deref(),
+            Some("refs/remotes/origin/topic/nested")
+        );
+        assert!(!spec.matches("refs/tags/v1.0"));
+        assert_eq!(spec.destination("refs/tags/v1.0"), None);
+
+        let spec = Refspec::parse("refs/heads/*-wip:refs/wip/*").unwrap();
+        assert_eq!(
+            spec.destination("refs/heads/parser-wip").as_deref(),
+            Some("refs/wip/parser")
+        );
+        assert!(!spec.matches("refs/heads/parser"));
+    }
+
+    #[test]
+    fn matches_short_names_by_precedence() {
+        let spec = Refspec::parse("main:refs/heads/main").unwrap();
+        assert!(spec.matches("refs/heads/main"));
+        assert!(spec.matches("refs/tags/main"));
+        assert!(!spec.matches("refs/heads/main-2"));
+        assert_eq!(
+            spec.prefixes(),
+            [
+                "refs/main",
+                "refs/tags/main",
+                "refs/heads/main",
+                "refs/remotes/main"
+            ]
+        );
+
+        // Fully qualified names only match themselves
+        let spec = Refspec::parse("refs/heads/main").unwrap();
+        assert!(spec.matches("refs/heads/main"));
+        assert!(!spec.matches("refs/remotes/refs/heads/main"));
+        assert_eq!(spec.prefixes(), ["refs/heads/main"]);
+        assert_eq!(spec.destination("refs/heads/main"), None);
+    }
+
+    #[test]
+    fn glob_prefixes_stop_at_the_star() {
+        let spec = Refspec::parse("refs/tags/v*:refs/tags/v*").unwrap();
+        assert_eq!(spec.prefixes(), ["refs/tags/v"]);
+    }
+}
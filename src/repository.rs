@@ -0,0 +1,351 @@
+use anyhow::{Context, Result};
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::config::Config;
+
+/// The name of the git directory inside a work tree, or of a file pointing at it
+const DOT_GIT: &str = ".git";
+
+/// The repository every command in this process operates on
+static REPOSITORY: OnceLock<Repository> = OnceLock::new();
+
+/// Where a repository's git directory and work tree are
+#[derive(Debug, Clone)]
+pub(crate) struct Repository {
+    git_dir: PathBuf,
+    /// `None` for bare repositories
+    work_tree: Option<PathBuf>,
+    /// Where the command was run from, before moving to the top of the work tree
+    cwd: PathBuf,
+}
+
+impl Repository {
+    /// Finds the repository for the current directory the way git does: `GIT_DIR`
+    /// if set, otherwise the nearest directory that holds a `.git` directory or
+    /// file or is itself a bare repository, stopping at `GIT_CEILING_DIRECTORIES`
+    pub(crate) fn discover() -> Result<Self> {
+        let cwd = std::env::current_dir()
+            .and_then(std::fs::canonicalize)
+            .context("getting current directory")?;
+        let env_work_tree = env_path("GIT_WORK_TREE", &cwd);
+
+        if let Some(git_dir) = env_path("GIT_DIR", &cwd) {
+            anyhow::ensure!(
+                is_git_dir(&git_dir),
+                "not a git repository: '{}'",
+                git_dir.display()
+            );
+            let work_tree = match env_work_tree {
+                Some(work_tree) => Some(work_tree),
+                None if is_bare(&git_dir)? => None,
+                None => Some(cwd.clone()),
+            };
+            return Self::new(git_dir, work_tree, &cwd);
+        }
+
+        let ceilings = ceiling_directories();
+        let mut dir = cwd.as_path();
+        loop {
+            let dot_git = dir.join(DOT_GIT);
+            if let Some(git_dir) = resolve_dot_git(&dot_git)? {
+                let work_tree = env_work_tree.or_else(|| Some(dir.to_path_buf()));
+                return Self::new(git_dir, work_tree, &cwd);
+            }
+
+            if is_git_dir(dir) {
+                return Self::new(dir.to_path_buf(), env_work_tree, &cwd);
+            }
+
+            match dir.parent() {
+                Some(parent) if !ceilings.iter().any(|ceiling| ceiling == parent) => dir = parent,
+                _ => anyhow::bail!(
+                    "not a git repository (or any of the parent directories): {DOT_GIT}"
+                ),
+            }
+        }
+    }
+
+    /// A repository about to be created, with its git directory at `GIT_DIR`,
+    /// or in the current directory itself when bare, or in `.git` otherwise
+    pub(crate) fn for_init(bare: bool) -> Result<Self> {
+        let cwd = std::env::current_dir().context("getting current directory")?;
+        let git_dir = env_path("GIT_DIR", &cwd).unwrap_or_else(|| {
+            if bare {
+                cwd.clone()
+            } else {
+                cwd.join(DOT_GIT)
+            }
+        });
+        let work_tree = match env_path("GIT_WORK_TREE", &cwd) {
+            Some(work_tree) => Some(work_tree),
+            None if bare => None,
+            None => Some(cwd.clone()),
+        };
+
+        Ok(Self {
+            git_dir,
+            work_tree,
+            cwd,
+        })
+    }
+
+    fn new(git_dir: PathBuf, work_tree: Option<PathBuf>, cwd: &Path) -> Result<Self> {
+        let git_dir = std::fs::canonicalize(&git_dir)
+            .with_context(|| format!("resolving {}", git_dir.display()))?;
+        let work_tree = work_tree
+            .map(|work_tree| {
+                std::fs::canonicalize(&work_tree)
+                    .with_context(|| format!("resolving work tree {}", work_tree.display()))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            git_dir,
+            work_tree,
+            cwd: cwd.to_path_buf(),
+        })
+    }
+
+    /// Makes this the repository the rest of the process works in, moving to the
+    /// top of the work tree so paths in it can be used as they appear in the index
+    pub(crate) fn install(self) -> Result<&'static Self> {
+        if let Some(work_tree) = &self.work_tree {
+            std::env::set_current_dir(work_tree)
+                .with_context(|| format!("changing to work tree {}", work_tree.display()))?;
+        }
+
+        REPOSITORY
+            .set(self)
+            .map_err(|_| anyhow::anyhow!("a repository has already been set up"))?;
+        Ok(repository())
+    }
+
+    /// The repository set up for this process, if there is one
+    pub(crate) fn current() -> Option<&'static Self> {
+        REPOSITORY.get()
+    }
+
+    pub(crate) fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    /// The work tree, for commands that can't run in a bare repository
+    pub(crate) fn work_tree(&self) -> Result<&Path> {
+        self.work_tree
+            .as_deref()
+            .context("this operation must be run in a work tree")
+    }
+
+    /// A path given relative to where the command was run, as one relative to
+    /// the top of the work tree
+    fn prefixed(&self, path: &Path) -> Result<PathBuf> {
+        let work_tree = self.work_tree()?;
+        let absolute = normalise(&self.cwd.join(path));
+        // The work tree has had symlinks resolved, which an absolute path given
+        // on the command line may not have
+        let real = std::fs::canonicalize(&absolute).ok();
+
+        let relative = [Some(&absolute), real.as_ref()]
+            .into_iter()
+            .flatten()
+            .find_map(|candidate| candidate.strip_prefix(work_tree).ok())
+            .map(Path::to_path_buf)
+            .with_context(|| {
+                format!(
+                    "'{}' is outside repository at '{}'",
+                    path.display(),
+                    work_tree.display()
+                )
+            });
+        relative
+    }
+}
+
+/// An empty bare repository set up for unit tests that need one, shared by all
+/// of them, so each test should stick to ref names of its own
+#[cfg(test)]
+pub(crate) fn test_repository() -> &'static Repository {
+    static SETUP: std::sync::Once = std::sync::Once::new();
+    SETUP.call_once(|| {
+        let git_dir =
+            std::env::temp_dir().join(format!("git-starter-rust-unit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&git_dir);
+        for dir in ["objects", "refs/heads", "refs/tags"] {
+            std::fs::create_dir_all(git_dir.join(dir)).unwrap();
+        }
+        std::fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+
+        let cwd = std::env::current_dir().unwrap();
+        Repository::new(git_dir, None, &cwd)
+            .and_then(Repository::install)
+            .unwrap();
+    });
+
+    repository()
+}
+
+/// The repository set up for this process
+pub(crate) fn repository() -> &'static Repository {
+    REPOSITORY
+        .get()
+        .expect("repository used before it was set up")
+}
+
+/// A file named on the command line, which may be anywhere, as a path that
+/// still finds it after the process has moved to the top of the work tree.
+/// Paths are left alone when there's no repository, as nothing has moved.
+pub(crate) fn cli_path(path: impl AsRef<Path>) -> PathBuf {
+    match REPOSITORY.get() {
+        Some(repository) => repository.cwd.join(path),
+        None => path.as_ref().to_path_buf(),
+    }
+}
+
+/// Maps a path given on the command line, relative to where the command was
+/// run, to one relative to the top of the work tree as the index holds them,
+/// failing if it lies outside the work tree
+pub(crate) fn work_tree_path(path: impl AsRef<Path>) -> Result<PathBuf> {
+    repository().prefixed(path.as_ref())
+}
+
+/// A path inside the git directory, e.g. `git_path("refs/heads/main")`
+pub(crate) fn git_path(relative: impl AsRef<Path>) -> PathBuf {
+    repository().git_dir.join(relative)
+}
+
+fn is_bare(git_dir: &Path) -> Result<bool> {
+    let config = Config::load_for(Some(git_dir))?;
+    Ok(config.get_bool("core.bare")?.unwrap_or(false))
+}
+
+/// Whether a directory has the layout of a git directory
+fn is_git_dir(dir: &Path) -> bool {
+    dir.join("HEAD").is_file() && dir.join("objects").is_dir() && dir.join("refs").is_dir()
+}
+
+/// The git directory a `.git` entry stands for: itself if it's a directory, or
+/// the target of a `gitdir: <path>` file as used by worktrees and submodules
+fn resolve_dot_git(dot_git: &Path) -> Result<Option<PathBuf>> {
+    if dot_git.is_dir() {
+        return Ok(is_git_dir(dot_git).then(|| dot_git.to_path_buf()));
+    }
+    if !dot_git.is_file() {
+        return Ok(None);
+    }
+
+    let raw = std::fs::read_to_string(dot_git)
+        .with_context(|| format!("reading {}", dot_git.display()))?;
+    let target = raw
+        .trim_end()
+        .strip_prefix("gitdir: ")
+        .with_context(|| format!("invalid gitfile format: {}", dot_git.display()))?;
+
+    // Relative targets are relative to the directory holding the file
+    let base = dot_git.parent().unwrap_or(Path::new(""));
+    let git_dir = base.join(target);
+    anyhow::ensure!(
+        is_git_dir(&git_dir),
+        "{} points at '{}', which is not a git repository",
+        dot_git.display(),
+        git_dir.display()
+    );
+
+    Ok(Some(git_dir))
+}
+
+/// An environment variable holding a path, made absolute against `cwd`
+fn env_path(name: &str, cwd: &Path) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .filter(|value| !value.is_empty())
+        .map(|value| cwd.join(value))
+}
+
+/// Resolves `.` and `..` without touching the filesystem, `..` at the root
+/// staying there
+fn normalise(path: &Path) -> PathBuf {
+    let mut normalised = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalised.pop();
+            }
+            other => normalised.push(other),
+        }
+    }
+
+    normalised
+}
+
+/// The absolute directories in `GIT_CEILING_DIRECTORIES`, which discovery won't
+/// move up into
+fn ceiling_directories() -> Vec<PathBuf> {
+    let raw = std::env::var_os("GIT_CEILING_DIRECTORIES").unwrap_or_default();
+    std::env::split_paths(&raw)
+        .filter(|path| path.is_absolute())
+        .map(|path| std::fs::canonicalize(&path).unwrap_or(path))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repository(work_tree: &str, cwd: &str) -> Repository {
+        Repository {
+            git_dir: Path::new(work_tree).join(DOT_GIT),
+            work_tree: Some(PathBuf::from(work_tree)),
+            cwd: PathBuf::from(cwd),
+        }
+    }
+
+    #[test]
+    fn normalises_without_leaving_the_root() {
+        for (path, expected) in [
+            ("/a/./b/../c", "/a/c"),
+            ("/a/b/../../..", "/"),
+            ("/../../etc/passwd", "/etc/passwd"),
+            ("a/../../b", "b"),
+        ] {
+            assert_eq!(normalise(Path::new(path)), Path::new(expected), "{path}");
+        }
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_top_of_the_work_tree() {
+        let repository = repository("/nonexistent/work", "/nonexistent/work/sub");
+
+        for (path, expected) in [
+            ("file", "sub/file"),
+            ("../file", "file"),
+            ("./deeper/../file", "sub/file"),
+            ("/nonexistent/work/other/file", "other/file"),
+        ] {
+            assert_eq!(
+                repository.prefixed(Path::new(path)).unwrap(),
+                Path::new(expected),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn paths_outside_the_work_tree_are_rejected() {
+        let repository = repository("/nonexistent/work", "/nonexistent/work/sub");
+
+        for path in [
+            "../../outside",
+            "../../work-sibling/file",
+            "/etc/passwd",
+            "../../../../../etc/passwd",
+        ] {
+            let error = repository.prefixed(Path::new(path)).unwrap_err();
+            assert!(
+                error.to_string().contains("is outside repository"),
+                "{path}"
+            );
+        }
+    }
+}
@@ -0,0 +1,106 @@
+/// Path globbing where `*`, `?` and `[...]` stay within a path component and
+/// `**` crosses them, `**/` also matching no directories at all. A backslash
+/// matches the character after it literally.
+pub(crate) fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            if let Some(after) = rest.strip_prefix(b"/") {
+                if wildmatch(after, text) {
+                    return true;
+                }
+            }
+            (0..=text.len()).any(|i| wildmatch(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| wildmatch(rest, &text[i..])),
+        [b'?', rest @ ..] => {
+            text.first().is_some_and(|&c| c != b'/') && wildmatch(rest, &text[1..])
+        }
+        [b'[', rest @ ..] => {
+            let Some(&c) = text.first().filter(|&&c| c != b'/') else {
+                return false;
+            };
+            match match_class(pattern, c) {
+                Some((matched, len)) => matched && wildmatch(&pattern[len..], &text[1..]),
+                None => c == b'[' && wildmatch(rest, &text[1..]),
+            }
+        }
+        [b'\\', c, rest @ ..] => text.first() == Some(c) && wildmatch(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && wildmatch(rest, &text[1..]),
+    }
+}
+
+/// Matches a `[...]` character class, returning whether it matched and its length
+pub(crate) fn match_class(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut idx = 1;
+    let negated = matches!(pattern.get(idx), Some(b'!') | Some(b'^'));
+    if negated {
+        idx += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let current = *pattern.get(idx)?;
+        if current == b']' && !first {
+            return Some((matched != negated, idx + 1));
+        }
+        first = false;
+
+        if pattern.get(idx + 1) == Some(&b'-') && pattern.get(idx + 2).is_some_and(|&e| e != b']') {
+            let end = pattern[idx + 2];
+            matched |= (current..=end).contains(&c);
+            idx += 3;
+        } else {
+            matched |= current == c;
+            idx += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        wildmatch(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn single_stars_stay_within_a_component() {
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(matches("*", ""));
+        assert!(!matches("*.txt", "dir/notes.txt"));
+        assert!(matches("dir/*", "dir/notes.txt"));
+        assert!(!matches("dir/*", "dir/sub/notes.txt"));
+        assert!(matches("?at", "cat"));
+        assert!(!matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn double_stars_cross_components() {
+        assert!(matches("**/notes.txt", "notes.txt"));
+        assert!(matches("**/notes.txt", "a/b/notes.txt"));
+        assert!(matches("dir/**", "dir/a/b"));
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+        assert!(!matches("a/**/b", "a/x/y/c"));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(matches("[a-c]at", "bat"));
+        assert!(!matches("[a-c]at", "rat"));
+        assert!(matches("[!a-c]at", "rat"));
+        assert!(matches("[^a-c]at", "rat"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(!matches("[a-z]", "/"));
+        // An unterminated class is a literal bracket
+        assert!(matches("[abc", "[abc"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+    }
+}
@@ -0,0 +1,17 @@
+mod common;
+
+use common::{git, ours, repository_with_submodule, Scratch};
+
+#[test]
+fn add_all_leaves_submodules_staged() {
+    let scratch = Scratch::new("add-submodule");
+    let work = repository_with_submodule(&scratch);
+    let staged = git(&work, &["ls-files", "--stage", "module"]);
+
+    std::fs::write(work.join("file.txt"), "changed\n").unwrap();
+    assert_eq!(ours(&work, &["add", "-A", "-n"]), "add 'file.txt'\n");
+
+    ours(&work, &["add", "-A"]);
+    assert_eq!(git(&work, &["ls-files", "--stage", "module"]), staged);
+    assert_eq!(git(&work, &["status", "--porcelain"]), "M  file.txt");
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use common::{bare_repository, git, ours, ours_output, serve, Scratch};
+
+use std::path::Path;
+
+/// Writes a tree from raw `(mode, name, hash)` entries, skipping the checks
+/// `git mktree` would make so that hostile names get through
+fn raw_tree(repository: &Path, entries: &[(&str, &[u8], &str)]) -> String {
+    let mut content = Vec::new();
+    for (mode, name, hash) in entries {
+        content.extend_from_slice(format!("{mode} ").as_bytes());
+        content.extend_from_slice(name);
+        content.push(0);
+        content.extend(
+            (0..hash.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hash[i..i + 2], 16).unwrap()),
+        );
+    }
+
+    let file = repository.join("raw-tree");
+    std::fs::write(&file, content).unwrap();
+    let hash = git(
+        repository,
+        &["hash-object", "-w", "--literally", "-t", "tree", "raw-tree"],
+    );
+    std::fs::remove_file(file).unwrap();
+    hash
+}
+
+fn blob(repository: &Path, content: &str) -> String {
+    let file = repository.join("blob");
+    std::fs::write(&file, content).unwrap();
+    let hash = git(repository, &["hash-object", "-w", "blob"]);
+    std::fs::remove_file(file).unwrap();
+    hash
+}
+
+/// Points the remote's `main` at a commit of `tree`
+fn publish(repository: &Path, tree: &str) {
+    let commit = git(repository, &["commit-tree", "-m", "evil", tree]);
+    git(repository, &["update-ref", "refs/heads/main", &commit]);
+}
+
+/// Clones a remote whose `main` has the tree built by `build`, expecting it to
+/// fail for `reason` without writing anything next to the work tree
+fn assert_clone_refused(name: &str, reason: &str, build: impl Fn(&Path) -> String) {
+    let scratch = Scratch::new(name);
+    let remote = bare_repository(&scratch.join("srv"), "remote.git");
+    let tree = build(&remote);
+    publish(&remote, &tree);
+
+    let url = format!("{}/remote.git", serve(&scratch.join("srv")));
+    let output = ours_output(&scratch.0, &["clone", &url, "work/clone"]);
+    assert!(
+        !output.status.success(),
+        "clone of {name} succeeded: {}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(reason), "{stderr}");
+
+    for escaped in ["escape", "work/escape", "work/clone/.git/hooks/escape"] {
+        assert!(
+            !scratch.join(escaped).exists(),
+            "clone of {name} wrote {escaped}"
+        );
+    }
+}
+
+#[test]
+fn clone_checks_out_a_usable_repository() {
+    let scratch = Scratch::new("clone");
+    let remote = bare_repository(&scratch.join("srv"), "remote.git");
+
+    let seed = scratch.join("seed");
+    git(&scratch.0, &["init", "--quiet", "-b", "main", "seed"]);
+    std::fs::create_dir(seed.join("dir")).unwrap();
+    std::fs::write(seed.join("dir/nested.txt"), "nested\n").unwrap();
+    std::fs::write(seed.join("README"), "hello\n").unwrap();
+    std::os::unix::fs::symlink("README", seed.join("link")).unwrap();
+    git(&seed, &["add", "."]);
+    git(&seed, &["commit", "--quiet", "-m", "initial"]);
+    git(&seed, &["tag", "v1.0"]);
+    git(
+        &seed,
+        &["push", "--quiet", remote.to_str().unwrap(), "main", "v1.0"],
+    );
+
+    let url = format!("{}/remote.git", serve(&scratch.join("srv")));
+    ours(&scratch.0, &["clone", &url, "work"]);
+    let work = scratch.join("work");
+
+    assert_eq!(
+        std::fs::read_to_string(work.join("dir/nested.txt")).unwrap(),
+        "nested\n"
+    );
+    assert_eq!(
+        std::fs::read_link(work.join("link")).unwrap(),
+        Path::new("README")
+    );
+    assert_eq!(
+        git(&work, &["rev-parse", "HEAD", "origin/main", "v1.0"]),
+        vec![git(&seed, &["rev-parse", "HEAD"]); 3].join("\n")
+    );
+    assert_eq!(git(&work, &["status", "--porcelain"]), "");
+    git(&work, &["fsck", "--strict"]);
+}
+
+#[test]
+fn clone_refuses_parent_directory_entries() {
+    assert_clone_refused("clone-dotdot", "file name '..' is not allowed", |remote| {
+        let file = blob(remote, "pwned\n");
+        let inner = raw_tree(remote, &[("100644", b"escape", &file)]);
+        raw_tree(remote, &[("40000", b"..", &inner)])
+    });
+}
+
+#[test]
+fn clone_refuses_names_with_slashes() {
+    assert_clone_refused("clone-slash", "contains a '/'", |remote| {
+        let file = blob(remote, "pwned\n");
+        raw_tree(remote, &[("100644", b"../escape", &file)])
+    });
+}
+
+#[test]
+fn clone_refuses_to_write_into_the_git_directory() {
+    assert_clone_refused(
+        "clone-git-dir",
+        "file name '.GIT' is not allowed",
+        |remote| {
+            let file = blob(remote, "pwned\n");
+            let hooks = raw_tree(remote, &[("100755", b"escape", &file)]);
+            let git_dir = raw_tree(remote, &[("40000", b"hooks", &hooks)]);
+            raw_tree(remote, &[("40000", b".GIT", &git_dir)])
+        },
+    );
+}
+
+#[test]
+fn clone_refuses_to_write_through_a_symlink() {
+    assert_clone_refused(
+        "clone-symlink",
+        "more than one entry named 'link'",
+        |remote| {
+            let target = blob(remote, "..");
+            let file = blob(remote, "pwned\n");
+            let inner = raw_tree(remote, &[("100644", b"escape", &file)]);
+            // The same name twice, so the directory is written after the symlink
+            raw_tree(
+                remote,
+                &[("120000", b"link", &target), ("40000", b"link", &inner)],
+            )
+        },
+    );
+}
@@ -0,0 +1,247 @@
+//! Helpers shared by the integration tests: scratch directories, running both
+//! our binary and the real git, and a tiny smart HTTP server in front of
+//! `git http-backend`.
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A directory under the system temp dir, removed when dropped
+pub struct Scratch(pub PathBuf);
+
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "git-starter-rust-{name}-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn join(&self, path: &str) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn command(program: &str, dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_AUTHOR_NAME", "Tester")
+        .env("GIT_AUTHOR_EMAIL", "tester@example.com")
+        .env("GIT_COMMITTER_NAME", "Tester")
+        .env("GIT_COMMITTER_EMAIL", "tester@example.com")
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_WORK_TREE");
+    command
+}
+
+/// Runs our binary, returning its output whether or not it succeeded
+pub fn ours_output(dir: &Path, args: &[&str]) -> Output {
+    command(env!("CARGO_BIN_EXE_git-starter-rust"), dir, args)
+        .output()
+        .unwrap()
+}
+
+/// Runs our binary, panicking with its stderr if it fails
+pub fn ours(dir: &Path, args: &[&str]) -> String {
+    let output = ours_output(dir, args);
+    assert!(
+        output.status.success(),
+        "git-starter-rust {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Runs the real git, returning its output whether or not it succeeded
+pub fn git_output(dir: &Path, args: &[&str]) -> Output {
+    command("git", dir, args).output().unwrap()
+}
+
+/// Runs the real git, panicking with its stderr if it fails
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let output = git_output(dir, args);
+    assert!(
+        output.status.success(),
+        "git {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_string()
+}
+
+/// Serves every repository under `root` over smart HTTP by running `git
+/// http-backend` as a CGI script for each request, returning the base url
+pub fn serve(root: &Path) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let root = root.to_path_buf();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let root = root.clone();
+            std::thread::spawn(move || {
+                let _ = handle(stream, &root);
+            });
+        }
+    });
+
+    url
+}
+
+fn handle(stream: TcpStream, root: &Path) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+
+    let mut body = Vec::new();
+    if header("transfer-encoding").is_some_and(|value| value.eq_ignore_ascii_case("chunked")) {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size)?;
+            let size = usize::from_str_radix(size.trim(), 16).unwrap_or(0);
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = header("content-length") {
+        body.resize(length.parse().unwrap_or(0), 0);
+        reader.read_exact(&mut body)?;
+    }
+
+    let mut backend = Command::new("git");
+    backend
+        .arg("http-backend")
+        .env("GIT_PROJECT_ROOT", root)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("PATH_INFO", path)
+        .env("QUERY_STRING", query)
+        .env("REQUEST_METHOD", &method)
+        .env("CONTENT_TYPE", header("content-type").unwrap_or_default())
+        .env("CONTENT_LENGTH", body.len().to_string())
+        // Receive-pack is only enabled for authenticated users
+        .env("REMOTE_USER", "tester")
+        .env("REMOTE_ADDR", "127.0.0.1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    if let Some(protocol) = header("git-protocol") {
+        backend.env("GIT_PROTOCOL", protocol);
+    }
+
+    let mut child = backend.spawn()?;
+    child.stdin.take().unwrap().write_all(&body)?;
+    let output = child.wait_with_output()?;
+
+    let split = output
+        .stdout
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap_or(output.stdout.len());
+    let cgi_headers = String::from_utf8_lossy(&output.stdout[..split]).into_owned();
+    let payload = output.stdout.get(split + 4..).unwrap_or_default();
+
+    let mut status = "200 OK".to_string();
+    let mut response = String::new();
+    for line in cgi_headers.lines() {
+        match line.split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("status") => {
+                status = value.trim().to_string();
+            }
+            Some(_) => response.push_str(&format!("{line}\r\n")),
+            None => {}
+        }
+    }
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n{response}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        payload.len()
+    )?;
+    stream.write_all(payload)?;
+    stream.flush()
+}
+
+/// A bare repository named `name` under `root` with `main` as its default branch
+pub fn bare_repository(root: &Path, name: &str) -> PathBuf {
+    let path = root.join(name);
+    std::fs::create_dir_all(root).unwrap();
+    git(root, &["init", "--quiet", "--bare", name]);
+    git(&path, &["symbolic-ref", "HEAD", "refs/heads/main"]);
+    path
+}
+
+/// A repository with one commit holding `file.txt` and a submodule checked out
+/// at `module`, recorded as a gitlink in the index and HEAD
+pub fn repository_with_submodule(scratch: &Scratch) -> PathBuf {
+    git(&scratch.0, &["init", "--quiet", "-b", "main", "work"]);
+    let work = scratch.join("work");
+
+    let module = work.join("module");
+    git(&work, &["init", "--quiet", "module"]);
+    std::fs::write(module.join("inner.txt"), "inner\n").unwrap();
+    git(&module, &["add", "inner.txt"]);
+    git(&module, &["commit", "--quiet", "-m", "inner"]);
+    let commit = git(&module, &["rev-parse", "HEAD"]);
+
+    std::fs::write(work.join("file.txt"), "file\n").unwrap();
+    git(&work, &["add", "file.txt"]);
+    git(
+        &work,
+        &[
+            "update-index",
+            "--add",
+            "--cacheinfo",
+            &format!("160000,{commit},module"),
+        ],
+    );
+    git(&work, &["commit", "--quiet", "-m", "initial"]);
+    work
+}
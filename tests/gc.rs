@@ -0,0 +1,110 @@
+mod common;
+
+use common::{git, ours, Scratch};
+
+use std::path::{Path, PathBuf};
+
+/// A repository with a couple of commits, a blob tagged directly and a tree
+/// behind an annotated tag, neither reachable from any commit
+fn setup(scratch: &Scratch) -> PathBuf {
+    git(&scratch.0, &["init", "--quiet", "-b", "main", "work"]);
+    let work = scratch.join("work");
+    for (name, content) in [("a.txt", "a\n"), ("b.txt", "b\n")] {
+        std::fs::write(work.join(name), content).unwrap();
+        git(&work, &["add", name]);
+        git(&work, &["commit", "--quiet", "-m", name]);
+    }
+
+    let blob = hash_object(&work, "tagged blob\n");
+    git(&work, &["tag", "keyblob", &blob]);
+
+    std::fs::create_dir(work.join("dir")).unwrap();
+    std::fs::write(work.join("dir/only-in-tag.txt"), "tree\n").unwrap();
+    git(&work, &["add", "dir"]);
+    let tree = git(&work, &["write-tree"]);
+    git(&work, &["rm", "--quiet", "-r", "--cached", "dir"]);
+    std::fs::remove_dir_all(work.join("dir")).unwrap();
+    git(&work, &["tag", "-a", "-m", "a tree", "treetag", &tree]);
+
+    work
+}
+
+fn hash_object(work: &Path, content: &str) -> String {
+    std::fs::write(work.join("object"), content).unwrap();
+    let hash = git(work, &["hash-object", "-w", "object"]);
+    std::fs::remove_file(work.join("object")).unwrap();
+    hash
+}
+
+fn exists(work: &Path, hash: &str) -> bool {
+    common::git_output(work, &["cat-file", "-e", hash])
+        .status
+        .success()
+}
+
+#[test]
+fn gc_keeps_everything_refs_point_at() {
+    let scratch = Scratch::new("gc");
+    let work = setup(&scratch);
+    let unreachable = hash_object(&work, "nobody refers to this\n");
+
+    ours(&work, &["gc", "--quiet", "--prune=now"]);
+
+    git(&work, &["fsck", "--strict", "--no-dangling"]);
+    assert!(exists(&work, "keyblob"));
+    assert!(exists(&work, "treetag^{tree}:dir/only-in-tag.txt"));
+    assert!(!exists(&work, &unreachable));
+    assert_eq!(git(&work, &["count-objects"]), "0 objects, 0 kilobytes");
+}
+
+#[test]
+fn gc_keeps_recent_unreachable_objects() {
+    let scratch = Scratch::new("gc-recent");
+    let work = setup(&scratch);
+    let unreachable = hash_object(&work, "nobody refers to this\n");
+
+    ours(&work, &["gc", "--quiet"]);
+
+    git(&work, &["fsck", "--strict", "--no-dangling"]);
+    assert!(exists(&work, &unreachable));
+}
+
+#[test]
+fn repack_all_consolidates_packs() {
+    let scratch = Scratch::new("repack");
+    let work = setup(&scratch);
+    ours(&work, &["repack", "--quiet"]);
+
+    std::fs::write(work.join("c.txt"), "c\n").unwrap();
+    git(&work, &["add", "c.txt"]);
+    git(&work, &["commit", "--quiet", "-m", "c"]);
+    ours(&work, &["repack", "--quiet"]);
+
+    let packs = || {
+        std::fs::read_dir(work.join(".git/objects/pack"))
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .is_some_and(|ext| ext == "pack")
+            })
+            .count()
+    };
+    assert_eq!(packs(), 2);
+
+    ours(&work, &["repack", "-a", "--quiet"]);
+    assert_eq!(packs(), 1);
+    git(&work, &["fsck", "--strict", "--no-dangling"]);
+    assert!(exists(&work, "keyblob"));
+    assert!(exists(&work, "treetag^{tree}:dir/only-in-tag.txt"));
+    assert_eq!(
+        git(&work, &["rev-list", "--count", "--objects", "--all"]),
+        git(&work, &["count-objects", "-v"])
+            .lines()
+            .find_map(|line| line.strip_prefix("in-pack: "))
+            .unwrap()
+    );
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use common::{bare_repository, git, ours, ours_output, serve, Scratch};
+
+use std::path::{Path, PathBuf};
+
+/// A remote with one commit on `main` served over HTTP, and our clone of it
+fn setup(scratch: &Scratch) -> (PathBuf, PathBuf) {
+    let remote = bare_repository(&scratch.join("srv"), "remote.git");
+
+    let seed = scratch.join("seed");
+    git(&scratch.0, &["init", "--quiet", "-b", "main", "seed"]);
+    std::fs::write(seed.join("README"), "hello\n").unwrap();
+    git(&seed, &["add", "README"]);
+    git(&seed, &["commit", "--quiet", "-m", "initial"]);
+    git(
+        &seed,
+        &["push", "--quiet", remote.to_str().unwrap(), "main"],
+    );
+
+    let url = format!("{}/remote.git", serve(&scratch.join("srv")));
+    ours(&scratch.0, &["clone", &url, "work"]);
+
+    (remote, scratch.join("work"))
+}
+
+fn commit_file(dir: &Path, name: &str, content: &str, message: &str) {
+    std::fs::write(dir.join(name), content).unwrap();
+    ours(dir, &["add", name]);
+    ours(dir, &["commit", "-m", message]);
+}
+
+#[test]
+fn push_fast_forwards_remote_branch() {
+    let scratch = Scratch::new("push");
+    let (remote, work) = setup(&scratch);
+
+    commit_file(&work, "a.txt", "a\n", "add a");
+    commit_file(&work, "b.txt", "b\n", "add b");
+    ours(&work, &["push", "--quiet"]);
+
+    assert_eq!(
+        git(&remote, &["rev-parse", "main"]),
+        git(&work, &["rev-parse", "HEAD"])
+    );
+    assert_eq!(
+        git(&work, &["rev-parse", "refs/remotes/origin/main"]),
+        git(&work, &["rev-parse", "HEAD"])
+    );
+    git(&remote, &["fsck", "--strict"]);
+}
+
+#[test]
+fn push_rejects_non_fast_forward_without_force() {
+    let scratch = Scratch::new("push-rejected");
+    let (remote, work) = setup(&scratch);
+    let before = git(&remote, &["rev-parse", "main"]);
+
+    let other = scratch.join("other");
+    git(
+        &scratch.0,
+        &["clone", "--quiet", remote.to_str().unwrap(), "other"],
+    );
+    std::fs::write(other.join("theirs.txt"), "theirs\n").unwrap();
+    git(&other, &["add", "theirs.txt"]);
+    git(&other, &["commit", "--quiet", "-m", "theirs"]);
+    git(&other, &["push", "--quiet", "origin", "main"]);
+    let theirs = git(&remote, &["rev-parse", "main"]);
+    assert_ne!(before, theirs);
+
+    commit_file(&work, "ours.txt", "ours\n", "ours");
+    let output = ours_output(&work, &["push"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("(fetch first)"));
+    assert_eq!(git(&remote, &["rev-parse", "main"]), theirs);
+}
+
+#[test]
+fn force_with_lease_only_overwrites_what_we_have_seen() {
+    let scratch = Scratch::new("push-lease");
+    let (remote, work) = setup(&scratch);
+    let seen = git(&remote, &["rev-parse", "main"]);
+
+    let other = scratch.join("other");
+    git(
+        &scratch.0,
+        &["clone", "--quiet", remote.to_str().unwrap(), "other"],
+    );
+    std::fs::write(other.join("theirs.txt"), "theirs\n").unwrap();
+    git(&other, &["add", "theirs.txt"]);
+    git(&other, &["commit", "--quiet", "-m", "theirs"]);
+    git(&other, &["push", "--quiet", "origin", "main"]);
+    let theirs = git(&remote, &["rev-parse", "main"]);
+
+    commit_file(&work, "ours.txt", "ours\n", "ours");
+
+    // Our remote-tracking ref still says `seen`, so the lease has expired
+    let output = ours_output(&work, &["push", "--force-with-lease"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("(stale info)"));
+    assert_eq!(git(&remote, &["rev-parse", "main"]), theirs);
+
+    let stale = format!("--force-with-lease=main:{seen}");
+    let output = ours_output(&work, &["push", &stale]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("(stale info)"));
+    assert_eq!(git(&remote, &["rev-parse", "main"]), theirs);
+
+    // Having seen their commit, overwriting it is allowed
+    ours(&work, &["fetch"]);
+    ours(&work, &["push", "--quiet", "--force-with-lease"]);
+    assert_eq!(
+        git(&remote, &["rev-parse", "main"]),
+        git(&work, &["rev-parse", "HEAD"])
+    );
+    git(&remote, &["fsck", "--strict"]);
+}
+
+#[test]
+fn push_sends_trees_and_blobs_refs_point_at() {
+    let scratch = Scratch::new("push-non-commits");
+    let (remote, work) = setup(&scratch);
+
+    std::fs::write(work.join("blob"), "pushed on its own\n").unwrap();
+    let blob = git(&work, &["hash-object", "-w", "blob"]);
+    std::fs::remove_file(work.join("blob")).unwrap();
+
+    std::fs::create_dir(work.join("dir")).unwrap();
+    std::fs::write(work.join("dir/only-in-tag.txt"), "tree\n").unwrap();
+    git(&work, &["add", "dir"]);
+    let tree = git(&work, &["write-tree"]);
+    git(&work, &["rm", "--quiet", "-r", "--cached", "dir"]);
+    git(&work, &["tag", "-a", "-m", "a tree", "treetag", &tree]);
+
+    ours(
+        &work,
+        &["push", "--quiet", "origin", &format!("{blob}:refs/blobs/x")],
+    );
+    ours(&work, &["push", "--quiet", "origin", "treetag"]);
+
+    git(&remote, &["fsck", "--strict", "--no-dangling"]);
+    assert_eq!(git(&remote, &["rev-parse", "refs/blobs/x"]), blob);
+    assert_eq!(
+        git(&remote, &["rev-parse", "treetag^{tree}"]),
+        tree.to_string()
+    );
+    git(
+        &remote,
+        &["cat-file", "-e", "treetag^{tree}:dir/only-in-tag.txt"],
+    );
+}
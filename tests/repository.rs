@@ -0,0 +1,84 @@
+mod common;
+
+use common::{git, ours, ours_output, Scratch};
+
+#[test]
+fn commands_find_the_repository_from_a_subdirectory() {
+    let scratch = Scratch::new("discover");
+    git(&scratch.0, &["init", "--quiet", "work"]);
+    let work = scratch.join("work");
+    std::fs::create_dir_all(work.join("a/b")).unwrap();
+    std::fs::write(work.join("a/b/file.txt"), "file\n").unwrap();
+    std::fs::write(work.join("top.txt"), "top\n").unwrap();
+
+    ours(&work.join("a/b"), &["add", "file.txt", "../../top.txt"]);
+    assert_eq!(git(&work, &["ls-files"]), "a/b/file.txt\ntop.txt");
+
+    // `-C` behaves as if the command had been run from there
+    std::fs::write(work.join("a/other.txt"), "other\n").unwrap();
+    ours(&scratch.0, &["-C", "work/a", "add", "other.txt"]);
+    assert_eq!(
+        git(&work, &["ls-files"]),
+        "a/b/file.txt\na/other.txt\ntop.txt"
+    );
+}
+
+#[test]
+fn a_git_file_points_at_the_git_directory() {
+    let scratch = Scratch::new("gitfile");
+    git(
+        &scratch.0,
+        &[
+            "init",
+            "--quiet",
+            "--separate-git-dir",
+            "elsewhere.git",
+            "work",
+        ],
+    );
+    let work = scratch.join("work");
+    std::fs::write(work.join("file.txt"), "file\n").unwrap();
+
+    ours(&work, &["add", "file.txt"]);
+    assert_eq!(git(&work, &["ls-files"]), "file.txt");
+    assert!(scratch.join("elsewhere.git/index").is_file());
+}
+
+#[test]
+fn add_refuses_paths_outside_the_work_tree() {
+    let scratch = Scratch::new("add-outside");
+    git(&scratch.0, &["init", "--quiet", "work"]);
+    std::fs::write(scratch.join("outside.txt"), "outside\n").unwrap();
+    std::fs::create_dir(scratch.join("work/dir")).unwrap();
+
+    for path in ["../outside.txt", "dir/../../outside.txt"] {
+        let output = ours_output(&scratch.join("work"), &["add", path]);
+        assert!(!output.status.success(), "added {path}");
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("is outside repository"),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let absolute = scratch.join("outside.txt");
+    let output = ours_output(
+        &scratch.join("work/dir"),
+        &["add", absolute.to_str().unwrap()],
+    );
+    assert!(!output.status.success());
+    assert_eq!(git(&scratch.join("work"), &["ls-files"]), "");
+}
+
+#[test]
+fn init_again_keeps_config_and_head() {
+    let scratch = Scratch::new("reinit");
+    ours(&scratch.0, &["init"]);
+    ours(&scratch.0, &["config", "user.name", "Someone"]);
+    git(&scratch.0, &["symbolic-ref", "HEAD", "refs/heads/dev"]);
+
+    let output = ours(&scratch.0, &["init"]);
+    assert!(output.starts_with("Reinitialized"), "{output}");
+    assert_eq!(git(&scratch.0, &["config", "user.name"]), "Someone");
+    assert_eq!(git(&scratch.0, &["symbolic-ref", "HEAD"]), "refs/heads/dev");
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use common::{git, git_output, ours, repository_with_submodule, Scratch};
+
+#[test]
+fn checked_out_submodules_are_not_deleted() {
+    let scratch = Scratch::new("status-submodule");
+    let work = repository_with_submodule(&scratch);
+
+    assert_eq!(ours(&work, &["status", "--porcelain"]), "");
+
+    std::fs::write(work.join("file.txt"), "changed\n").unwrap();
+    std::fs::write(work.join("new.txt"), "new\n").unwrap();
+    assert_eq!(
+        ours(&work, &["status", "--porcelain"]),
+        git(&work, &["status", "--porcelain"]) + "\n"
+    );
+}
+
+#[test]
+fn missing_submodules_are_deleted() {
+    let scratch = Scratch::new("status-submodule-gone");
+    let work = repository_with_submodule(&scratch);
+    std::fs::remove_dir_all(work.join("module")).unwrap();
+
+    assert_eq!(ours(&work, &["status", "--porcelain"]), " D module\n");
+}
+
+#[test]
+fn unmerged_paths_are_listed_in_path_order() {
+    let scratch = Scratch::new("status-unmerged");
+    git(&scratch.0, &["init", "--quiet", "-b", "main", "work"]);
+    let work = scratch.join("work");
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        std::fs::write(work.join(name), "base\n").unwrap();
+    }
+    git(&work, &["add", "."]);
+    git(&work, &["commit", "--quiet", "-m", "base"]);
+
+    git(&work, &["checkout", "--quiet", "-b", "other"]);
+    std::fs::write(work.join("b.txt"), "other\n").unwrap();
+    git(&work, &["commit", "--quiet", "-am", "other"]);
+    git(&work, &["checkout", "--quiet", "main"]);
+    std::fs::write(work.join("b.txt"), "main\n").unwrap();
+    git(&work, &["commit", "--quiet", "-am", "main"]);
+    assert!(!git_output(&work, &["merge", "--quiet", "other"])
+        .status
+        .success());
+
+    std::fs::write(work.join("a.txt"), "changed\n").unwrap();
+    std::fs::write(work.join("c.txt"), "changed\n").unwrap();
+    std::fs::write(work.join("d.txt"), "new\n").unwrap();
+    let expected = git(&work, &["status", "--porcelain"]);
+    assert_eq!(expected, " M a.txt\nUU b.txt\n M c.txt\n?? d.txt");
+    assert_eq!(ours(&work, &["status", "--porcelain"]), expected + "\n");
+}